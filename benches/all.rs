@@ -23,6 +23,8 @@
  * POSSIBILITY OF SUCH DAMAGE.
  */
 
+#![allow(clippy::unit_arg)]
+
 #[path = "../tests/data.rs"]
 mod data;
 
@@ -75,7 +77,7 @@ fn diff_inputs() -> (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) {
     let mut rng = Xoshiro256Plus::seed_from_u64(0xeba2fa67e5a81121);
     let mut old = vec![0u8; 262_144];
     rng.fill_bytes(&mut old);
-    aehobak::patch(&old, &mut &*patch, &mut new).unwrap();
+    aehobak::patch(&old, &patch, &mut new).unwrap();
     let bspatch = Vec::with_capacity(262_144);
     let patch = Vec::with_capacity(262_144);
     (old, new, bspatch, patch)
@@ -1,19 +1,30 @@
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        println!("Usage: bench <ORIGFILE> <FILE>");
+    if !(3..=4).contains(&args.len()) {
+        println!("Usage: bench <ORIGFILE> <FILE> [SEGMENT]");
         return;
     }
-    bench(&args[1], &args[2]).unwrap();
+    let segment_len = args
+        .get(3)
+        .map(|len| len.parse().expect("invalid segment length"));
+    bench(&args[1], &args[2], segment_len).unwrap();
 }
 
-fn bench(orig_file: &str, file: &str) -> std::io::Result<()> {
+fn bench(orig_file: &str, file: &str, segment_len: Option<usize>) -> std::io::Result<()> {
     let old = std::fs::read(orig_file)?;
     let new = std::fs::read(file)?;
     let mut patch = Vec::new();
     let mut encoded = Vec::new();
 
-    aehobak::diff(&old, &new, &mut encoded)?;
+    match segment_len {
+        #[cfg(feature = "rayon")]
+        Some(len) => aehobak::DiffOptions::new()
+            .parallel(len)
+            .diff(&old, &new, &mut encoded)?,
+        #[cfg(not(feature = "rayon"))]
+        Some(_) => panic!("segments require the rayon feature"),
+        None => aehobak::diff(&old, &new, &mut encoded)?,
+    }
     aehobak::decode(&mut encoded.as_slice(), &mut patch)?;
     println!("bsdiff:      {} bytes", patch.len());
     println!("aehobak:     {} bytes", encoded.len());
@@ -0,0 +1,185 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::control::Bsdiff as BsdiffControl;
+use crate::decode::Decoder;
+use crate::encode::Encoder;
+use bzip2::bufread::BzDecoder;
+use bzip2::write::BzEncoder;
+use bzip2::Compression;
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use std::io::{Read, Write};
+
+const BSDIFF40: &[u8; 8] = b"BSDIFF40";
+const BSDIFF43: &[u8; 16] = b"ENDSLEY/BSDIFF43";
+/// Payloads are transcoded through a buffer of this size.
+const CHUNK: usize = 1 << 16;
+
+/// Container formats of bsdiff patches, with bzip2-compressed streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    /// The format of bsdiff 4.x, with separate control, diff and extra streams.
+    Bsdiff40,
+    /// The format of endsley/bsdiff, with a single interleaved stream.
+    Bsdiff43,
+}
+
+/// The streams of a BSDIFF40 container, which BSDIFF43 interleaves.
+#[derive(Clone, Copy)]
+enum Block {
+    Control,
+    Diff,
+    Extra,
+}
+
+/// Encode a BSDIFF40 or BSDIFF43 container, detected by its magic,
+/// returning a compact representation.
+pub fn encode_container<T: Write>(container: &[u8], writer: &mut T) -> io::Result<()> {
+    let mut encoder = Encoder::new(writer);
+    if let Some(rest) = container.strip_prefix(BSDIFF43) {
+        let new_len = read_len(rest)?;
+        let mut stream = BzDecoder::new(&rest[8..]);
+        transcode(new_len, &mut encoder, |_, buf| stream.read_exact(buf))?;
+        check_end(&mut stream)?;
+    } else if let Some(rest) = container.strip_prefix(BSDIFF40) {
+        let (control_len, diff_len) = (read_len(rest)?, read_len(&rest[8..])?);
+        let new_len = read_len(&rest[16..])?;
+        let blocks = &rest[24..];
+        let (control, blocks) = split_block(blocks, control_len)?;
+        let (diff, extra) = split_block(blocks, diff_len)?;
+        let mut streams = [control, diff, extra].map(BzDecoder::new);
+        transcode(new_len, &mut encoder, |block, buf| {
+            streams[block as usize].read_exact(buf)
+        })?;
+        for stream in &mut streams {
+            check_end(stream)?;
+        }
+    } else {
+        return Err(io::Error::new(InvalidData, "unknown bsdiff container"));
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Decode a compact representation to a bsdiff container.
+pub fn decode_container<R: Read, W: Write>(
+    reader: &mut R,
+    container: Container,
+    writer: &mut W,
+) -> io::Result<()> {
+    let mut decoder = Decoder::new(reader);
+    let new_len = decoder.new_len()?;
+    match container {
+        Container::Bsdiff43 => {
+            writer.write_all(BSDIFF43)?;
+            writer.write_all(&new_len.to_le_bytes())?;
+            let mut stream = BzEncoder::new(writer, Compression::best());
+            io::copy(&mut decoder, &mut stream)?;
+            stream.finish()?;
+        }
+        Container::Bsdiff40 => {
+            // Lengths of the compressed streams precede them, so all are buffered
+            let mut streams = [(); 3].map(|()| BzEncoder::new(Vec::new(), Compression::best()));
+            let mut control = [0; 24];
+            while decoder.read(&mut control[..1])? != 0 {
+                decoder.read_exact(&mut control[1..])?;
+                streams[Block::Control as usize].write_all(&control)?;
+                let control = BsdiffControl::try_from(&control[..]).map_err(|_| InvalidData)?;
+                for (block, len) in [(Block::Diff, control.add), (Block::Extra, control.copy)] {
+                    let copied =
+                        io::copy(&mut (&mut decoder).take(len), &mut streams[block as usize])?;
+                    if copied != len {
+                        return Err(io::Error::from(UnexpectedEof));
+                    }
+                }
+            }
+            let [control, diff, extra] = streams;
+            let (control, diff, extra) = (control.finish()?, diff.finish()?, extra.finish()?);
+            writer.write_all(BSDIFF40)?;
+            writer.write_all(&(control.len() as u64).to_le_bytes())?;
+            writer.write_all(&(diff.len() as u64).to_le_bytes())?;
+            writer.write_all(&new_len.to_le_bytes())?;
+            writer.write_all(&control)?;
+            writer.write_all(&diff)?;
+            writer.write_all(&extra)?;
+        }
+    }
+    Ok(())
+}
+
+/// Feed controls and payloads to `encoder` until they cover `new_len` bytes.
+fn transcode<W, F>(new_len: u64, encoder: &mut Encoder<W>, mut read: F) -> io::Result<()>
+where
+    W: Write,
+    F: FnMut(Block, &mut [u8]) -> io::Result<()>,
+{
+    let mut buf = vec![0; CHUNK];
+    let mut new_cursor = 0u64;
+    while new_cursor < new_len {
+        let mut control = [0; 24];
+        read(Block::Control, &mut control)?;
+        encoder.write_all(&control)?;
+        let control = BsdiffControl::try_from(&control[..]).map_err(|_| InvalidData)?;
+        for (block, mut len) in [(Block::Diff, control.add), (Block::Extra, control.copy)] {
+            new_cursor = (new_cursor.checked_add(len))
+                .filter(|&cursor| cursor <= new_len)
+                .ok_or(io::Error::new(InvalidData, "controls exceed new size"))?;
+            while len != 0 {
+                let chunk = &mut buf[..len.min(CHUNK as u64) as usize];
+                read(block, chunk)?;
+                encoder.write_all(chunk)?;
+                len -= chunk.len() as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a length from a container header, rejecting negative values.
+fn read_len(header: &[u8]) -> io::Result<u64> {
+    let bytes = header.get(..8).ok_or(io::Error::from(UnexpectedEof))?;
+    let len = u64::from_le_bytes(bytes.try_into().unwrap());
+    match len >> 63 {
+        0 => Ok(len),
+        _ => Err(io::Error::new(InvalidData, "negative length")),
+    }
+}
+
+/// Split a stream of `len` bytes from the front of `blocks`.
+fn split_block(blocks: &[u8], len: u64) -> io::Result<(&[u8], &[u8])> {
+    match usize::try_from(len) {
+        Ok(len) if len <= blocks.len() => Ok(blocks.split_at(len)),
+        _ => Err(io::Error::from(UnexpectedEof)),
+    }
+}
+
+/// Reject data beyond the controls that cover new, or beyond its stream.
+fn check_end(stream: &mut BzDecoder<&[u8]>) -> io::Result<()> {
+    match stream.read(&mut [0])? == 0 && stream.get_ref().is_empty() {
+        true => Ok(()),
+        false => Err(io::Error::new(InvalidData, "trailing data")),
+    }
+}
@@ -0,0 +1,169 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::{Op, Source};
+use crate::limits::{self, Limits};
+use crate::parse::parse;
+use std::io;
+use std::io::ErrorKind::InvalidData;
+use std::ops::Range;
+
+// Formats built from copies of old and inserted bytes, such as VCDIFF and git
+// deltas, convert to and from controls through the helpers below.
+
+/// Longer literals between copies cost more as deltas than as a control.
+const MAX_FOLD: usize = 16;
+
+/// An instruction of the target, in absolute positions of old and new.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Inst {
+    Add(Range<usize>),
+    Run(usize, u8),
+    /// A copy of this many bytes from old.
+    Copy(usize, usize),
+    /// A copy of this many bytes from earlier in new.
+    Repeat(usize, usize),
+}
+
+impl Inst {
+    pub fn len(&self) -> usize {
+        match *self {
+            Inst::Add(ref range) => range.len(),
+            Inst::Run(len, _) | Inst::Copy(_, len) | Inst::Repeat(_, len) => len,
+        }
+    }
+
+    /// The first `len` bytes of this instruction.
+    pub fn head(&self, len: usize) -> Self {
+        match *self {
+            Inst::Add(ref range) => Inst::Add(range.start..range.start + len),
+            Inst::Run(_, byte) => Inst::Run(len, byte),
+            Inst::Copy(from, _) => Inst::Copy(from, len),
+            Inst::Repeat(from, _) => Inst::Repeat(from, len),
+        }
+    }
+
+    /// This instruction without its first `len` bytes.
+    pub fn tail(&self, len: usize) -> Self {
+        match *self {
+            Inst::Add(ref range) => Inst::Add(range.start + len..range.end),
+            Inst::Run(rest, byte) => Inst::Run(rest - len, byte),
+            Inst::Copy(from, rest) => Inst::Copy(from + len, rest - len),
+            Inst::Repeat(from, rest) => Inst::Repeat(from + len, rest - len),
+        }
+    }
+}
+
+/// Apply a patch to `old`, returning new and instructions that produce it.
+///
+/// Each add of the patch becomes copies of the bytes it leaves unchanged, if
+/// at least `min_copy` long, and adds of the rest. Fills become runs, and
+/// self-copies become repeats, unless the patch is filtered, as both then
+/// describe filtered bytes and are added as the bytes of new instead.
+/// New is bounded by the default output of `Limits`.
+pub(crate) fn instructions(
+    old: &[u8],
+    patch: &[u8],
+    min_copy: usize,
+) -> io::Result<(Vec<u8>, Vec<Inst>)> {
+    let (header, ops) = parse(patch)?;
+    let new_len = (ops.iter())
+        .try_fold(0usize, |len, op| {
+            len.checked_add(op.add)?.checked_add(op.copy)
+        })
+        .ok_or(InvalidData)?;
+    // Lengths are declared by the patch, so are checked before reserving
+    if new_len > Limits::default().output {
+        return Err(limits::exceeded("output"));
+    }
+    let mut new = Vec::with_capacity(new_len);
+    crate::patch(old, patch, &mut new)?;
+
+    let mut insts: Vec<Inst> = Vec::new();
+    let mut push = |inst: Inst| match (insts.last_mut(), &inst) {
+        (Some(Inst::Add(last)), Inst::Add(range)) if last.end == range.start => {
+            last.end = range.end
+        }
+        _ => insts.push(inst),
+    };
+    let mut new_cursor = 0;
+    for op in &ops {
+        // Unchanged bytes of the add are copied, and changed bytes added
+        let (old_add, new_add) = (&old[op.old..][..op.add], &new[new_cursor..][..op.add]);
+        let mut start = 0;
+        while start < op.add {
+            let same = old_add[start] == new_add[start];
+            let len = (old_add[start..].iter().zip(&new_add[start..]))
+                .take_while(|(o, n)| (o == n) == same)
+                .count();
+            match same && len >= min_copy {
+                true => push(Inst::Copy(op.old + start, len)),
+                false => push(Inst::Add(new_cursor + start..new_cursor + start + len)),
+            }
+            start += len;
+        }
+        new_cursor += op.add;
+        if op.copy != 0 {
+            push(match op.source {
+                _ if header.filter.is_some() => Inst::Add(new_cursor..new_cursor + op.copy),
+                Source::Literal => Inst::Add(new_cursor..new_cursor + op.copy),
+                Source::Fill(byte) => Inst::Run(op.copy, byte),
+                Source::Repeat(distance) => Inst::Repeat(new_cursor - distance, op.copy),
+            });
+        }
+        new_cursor += op.copy;
+    }
+    Ok((new, insts))
+}
+
+/// Append a copy of `len` bytes of old at `old` to `ops`.
+pub(crate) fn push_add(ops: &mut Vec<Op>, old: usize, len: usize) {
+    match ops.last_mut() {
+        // Short literals between copies on one diagonal become deltas
+        Some(last) if last.copy <= MAX_FOLD && last.old + last.add + last.copy == old => {
+            last.add += last.copy + len;
+            last.copy = 0;
+        }
+        _ => ops.push(Op {
+            old,
+            add: len,
+            copy: 0,
+            source: Source::Literal,
+        }),
+    }
+}
+
+/// Append `len` literal bytes to `ops`.
+pub(crate) fn push_literal(ops: &mut Vec<Op>, len: usize) {
+    match ops.last_mut() {
+        Some(last) => last.copy += len,
+        None => ops.push(Op {
+            old: 0,
+            add: 0,
+            copy: len,
+            source: Source::Literal,
+        }),
+    }
+}
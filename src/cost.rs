@@ -0,0 +1,89 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use std::fmt::Debug;
+
+/// The cost of each element of an encoded patch, in arbitrary units.
+///
+/// Literal bytes are assumed to have a constant cost, so that control
+/// boundaries can be placed by comparing running totals.
+pub trait CostModel: Debug + Send + Sync {
+    /// One control with these fields.
+    fn control(&self, add: u32, copy: u32, seek: i32) -> u64;
+    /// One nonzero delta, following `skip` zero deltas.
+    fn delta(&self, skip: u32) -> u64;
+    /// One literal byte.
+    fn literal(&self) -> u64;
+}
+
+/// Minimise the encoded size of a patch, in bits.
+///
+/// Each field costs its streamvbyte tag and data, and each delta costs its
+/// byte and skip. Downstream compression is not modelled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeCost;
+
+impl CostModel for SizeCost {
+    fn control(&self, add: u32, copy: u32, seek: i32) -> u64 {
+        varint(add) + varint(copy) + varint(((seek >> 31) ^ (seek << 1)) as u32)
+    }
+
+    fn delta(&self, skip: u32) -> u64 {
+        8 + varint(skip)
+    }
+
+    fn literal(&self) -> u64 {
+        8
+    }
+}
+
+/// Trade patch size for fewer controls and deltas, which dominate the time
+/// taken by `patch`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpeedCost;
+
+impl CostModel for SpeedCost {
+    fn control(&self, add: u32, copy: u32, seek: i32) -> u64 {
+        SizeCost.control(add, copy, seek) + 256
+    }
+
+    fn delta(&self, skip: u32) -> u64 {
+        SizeCost.delta(skip) + 16
+    }
+
+    fn literal(&self) -> u64 {
+        SizeCost.literal()
+    }
+}
+
+/// Bits taken by one value in a `Coder0124` stream, including its tag.
+fn varint(value: u32) -> u64 {
+    2 + match value {
+        0 => 0,
+        1..=0xFF => 8,
+        0x100..=0xFFFF => 16,
+        _ => 32,
+    }
+}
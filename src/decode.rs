@@ -25,82 +25,314 @@
 
 use crate::control::Aehobak as AehobakControl;
 use crate::control::Bsdiff as BsdiffControl;
+use crate::format::{self, Fill, Header};
+use crate::limits::{self, Limits};
 use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
 use std::io::Read;
 use streamvbyte64::{Coder, Coder0124};
 
 /// Decode a compact representation of bsdiff output.
+/// Untrusted patches should be decoded with `Limits::decode`.
 #[allow(clippy::ptr_arg)]
 pub fn decode<T: Read>(reader: &mut T, patch: &mut Vec<u8>) -> io::Result<()> {
-    let mut prefix = [0u8; 17];
-    reader.read_exact(&mut prefix[..1])?;
-
-    let coder = Coder0124::new();
-
-    let prefix_len = coder.data_len(&prefix[..1]);
-    reader.read_exact(&mut prefix[1..1 + prefix_len])?;
-
-    let (deltas_len, literals_len, controls, data_len) = {
-        let mut v = [0u32; 4];
-        let (tag, data) = prefix.as_mut_slice().split_at_mut(1);
-        coder.decode(tag, data, &mut v);
-        (v[0] as usize, v[1] as usize, v[2] as usize, v[3] as usize)
-    };
-
-    let tags_len = controls.div_ceil(4) * 3 + deltas_len.div_ceil(4);
-
-    let mut delta_diffs = vec![0; deltas_len];
-    let mut literals = vec![0; literals_len];
-    let mut tags = vec![0; tags_len];
-    let mut data = vec![0; data_len];
-
-    reader.read_exact(&mut delta_diffs)?;
-    reader.read_exact(&mut literals)?;
-    reader.read_exact(&mut tags)?;
-    reader.read_exact(&mut data)?;
-
-    let mut u32_seq = vec![0; 4 * tags_len];
-    let _ = coder.decode(&tags, &data, &mut u32_seq);
-    let controls_padded = controls.div_ceil(4) * 4;
-    let deltas_padded = deltas_len.div_ceil(4) * 4;
-    let delta_pos = &mut u32_seq[controls_padded..][..deltas_padded];
-    let mut delta_cursor: u32 = 0;
-    for skip in delta_pos {
-        let pos = delta_cursor.wrapping_add(*skip);
-        delta_cursor = delta_cursor.wrapping_add(*skip).wrapping_add(1);
-        *skip = pos;
+    Decoder::new(reader).read_to_end(patch)?;
+    Ok(())
+}
+
+/// Produce bsdiff output from a compact representation as it is read.
+///
+/// The sections of the patch are read in full and checked against any
+/// `Limits` on the first call to `read`, but bsdiff controls and payloads
+/// are then produced one at a time, so the
+/// bsdiff stream is never held in full. After any error, the decoder should
+/// be discarded.
+///
+/// ```
+/// let old = vec![1, 2, 3, 4, 5];
+/// let new = vec![1, 2, 4, 6];
+/// let mut encoded = Vec::new();
+/// aehobak::diff(&old, &new, &mut encoded).unwrap();
+///
+/// let mut decoder = aehobak::Decoder::new(encoded.as_slice());
+/// let mut patched = Vec::new();
+/// bsdiff::patch(&old, &mut decoder, &mut patched).unwrap();
+/// assert_eq!(patched, new);
+/// ```
+pub struct Decoder<R: Read> {
+    reader: R,
+    limits: Limits,
+    body: Option<Body>,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Create a decoder that reads a compact patch from `reader`.
+    pub fn new(reader: R) -> Self {
+        Self::with_limits(reader, Limits::unlimited())
+    }
+
+    /// Create a decoder that reads a compact patch from `reader`, within `limits`.
+    pub fn with_limits(reader: R, limits: Limits) -> Self {
+        Self {
+            reader,
+            limits,
+            body: None,
+        }
+    }
+}
+
+impl<R: Read> Decoder<R> {
+    fn body(&mut self) -> io::Result<&mut Body> {
+        let body = match self.body.take() {
+            Some(body) => body,
+            None => Body::read_from(&mut self.reader, &self.limits)?,
+        };
+        Ok(self.body.insert(body))
+    }
+
+    /// The length of new, as declared by the controls of the patch.
+    #[cfg_attr(not(feature = "bzip2"), allow(dead_code))]
+    pub(crate) fn new_len(&mut self) -> io::Result<u64> {
+        let body = self.body()?;
+        let adds = body.adds.iter().map(|&add| u64::from(add));
+        Ok(adds
+            .chain(body.copies.iter().map(|&copy| u64::from(copy)))
+            .sum())
     }
-    let copies = &u32_seq[..controls];
-    let mut delta_pos = &u32_seq[controls_padded..][..deltas_len];
-    let seeks = &u32_seq[controls_padded + deltas_padded..][..controls];
-    let adds = &u32_seq[controls_padded * 2 + deltas_padded..][..controls];
-
-    let mut literals = literals.as_slice();
-    let mut delta_diffs = delta_diffs.as_slice();
-
-    let mut delta_buf = Vec::new();
-    let mut add_cursor = 0;
-
-    for (&add, (&copy, &seek)) in adds.iter().zip(copies.iter().zip(seeks)) {
-        let control: BsdiffControl =
-            (&AehobakControl::try_from(&[add, copy, seek][..]).unwrap()).into();
-        let (add, copy) = (control.add as usize, control.copy as usize);
-        control.encode(patch);
-        delta_buf.clear();
-        delta_buf.resize(add, 0);
-        while !delta_pos.is_empty() && !delta_diffs.is_empty() {
-            let delta_cursor = delta_pos[0] as usize;
-            if delta_cursor >= add_cursor + add {
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body()?.read(buf)
+    }
+}
+
+/// The sections of a patch, and the progress of decoding them.
+struct Body {
+    delta_diffs: Vec<u8>,
+    /// Absolute positions of deltas within cumulative add bytes
+    delta_pos: Vec<u32>,
+    literals: Vec<u8>,
+    adds: Vec<u32>,
+    copies: Vec<u32>,
+    seeks: Vec<u32>,
+    fills: Vec<Fill>,
+    /// Index of the next control to begin
+    next: usize,
+    /// Encoded bsdiff control, consumed from `control_pos`
+    control: [u8; 24],
+    control_pos: usize,
+    /// Remaining bytes of the current add, ending at `add_end`
+    add: usize,
+    add_end: usize,
+    /// Remaining bytes of the current copy, and its fill byte if any
+    copy: usize,
+    fill: Option<u8>,
+    deltas_read: usize,
+    literals_read: usize,
+    fills_read: usize,
+}
+
+impl Body {
+    fn read_from<T: Read>(reader: &mut T, limits: &Limits) -> io::Result<Self> {
+        let mut prefix = [0u8; 17];
+        reader.read_exact(&mut prefix[..1])?;
+
+        let coder = Coder0124::new();
+
+        let mut prefix_len = coder.data_len(&prefix[..1]);
+        reader.read_exact(&mut prefix[1..1 + prefix_len])?;
+
+        let mut fills = Vec::new();
+        if format::is_extended(&prefix[..1 + prefix_len]) {
+            format::check_version(&prefix)?;
+            let header = Header::read_from(reader)?;
+            if header.filter.is_some() {
+                let msg = "filtered patches have no bsdiff equivalent";
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+            if header.relocations.is_some() {
+                let msg = "relocated patches have no bsdiff equivalent";
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+            if !header.self_copies().is_empty() {
+                let msg = "self-copying patches have no bsdiff equivalent";
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+            fills = header.fills.unwrap_or_default();
+            reader.read_exact(&mut prefix[..1])?;
+            prefix_len = coder.data_len(&prefix[..1]);
+            reader.read_exact(&mut prefix[1..1 + prefix_len])?;
+        }
+
+        let (deltas_len, literals_len, controls, data_len) = {
+            let mut v = [0u32; 4];
+            let (tag, data) = prefix.as_mut_slice().split_at_mut(1);
+            coder.decode(tag, data, &mut v);
+            (v[0] as usize, v[1] as usize, v[2] as usize, v[3] as usize)
+        };
+
+        let tags_len = (controls.div_ceil(4).checked_mul(3))
+            .and_then(|len| len.checked_add(deltas_len.div_ceil(4)))
+            .ok_or(io::Error::from(InvalidData))?;
+        limits.check_controls(controls)?;
+        // Each tag decodes to four words
+        let alloc = (deltas_len.checked_add(literals_len))
+            .and_then(|len| len.checked_add(data_len))
+            .and_then(|len| len.checked_add(tags_len.checked_mul(17)?));
+        limits.check_alloc(alloc)?;
+
+        // Buffers grow with the input actually read, not the declared lengths
+        let delta_diffs = read_vec(reader, deltas_len)?;
+        let literals = read_vec(reader, literals_len)?;
+        let tags = read_vec(reader, tags_len)?;
+        let data = read_vec(reader, data_len)?;
+        if coder.data_len(&tags) > data.len() {
+            return Err(io::Error::from(UnexpectedEof));
+        }
+
+        let mut u32_seq = vec![0; 4 * tags_len];
+        let _ = coder.decode(&tags, &data, &mut u32_seq);
+        let controls_padded = controls.div_ceil(4) * 4;
+        let deltas_padded = deltas_len.div_ceil(4) * 4;
+        let mut delta_pos = u32_seq[controls_padded..][..deltas_len].to_vec();
+        let mut delta_cursor: u32 = 0;
+        for skip in &mut delta_pos {
+            let pos = delta_cursor.wrapping_add(*skip);
+            delta_cursor = pos.wrapping_add(1);
+            *skip = pos;
+        }
+        if fills.last().is_some_and(|f| f.control >= controls) {
+            return Err(io::Error::new(InvalidData, "fill beyond last control"));
+        }
+        let copies = &u32_seq[..controls];
+        let seeks = &u32_seq[controls_padded + deltas_padded..][..controls];
+        let adds = &u32_seq[controls_padded * 2 + deltas_padded..][..controls];
+
+        // Every control is known before any output is produced
+        let mut output = 0u64;
+        for (&add, (&copy, &seek)) in adds.iter().zip(copies.iter().zip(seeks)) {
+            let control = AehobakControl::try_from(&[add, copy, seek][..])
+                .map_err(|_| io::Error::from(InvalidData))?;
+            limits.check_seek(control.seek.into())?;
+            output = output.saturating_add(24 + u64::from(add) + u64::from(copy));
+        }
+        if output > limits.output as u64 {
+            return Err(limits::exceeded("output"));
+        }
+
+        Ok(Self {
+            delta_diffs,
+            delta_pos,
+            literals,
+            copies: copies.to_vec(),
+            seeks: seeks.to_vec(),
+            adds: adds.to_vec(),
+            fills,
+            next: 0,
+            control: [0; 24],
+            control_pos: 24,
+            add: 0,
+            add_end: 0,
+            copy: 0,
+            fill: None,
+            deltas_read: 0,
+            literals_read: 0,
+            fills_read: 0,
+        })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let out = &mut buf[written..];
+            let len = if self.control_pos < 24 {
+                let control = &self.control[self.control_pos..];
+                let len = control.len().min(out.len());
+                out[..len].copy_from_slice(&control[..len]);
+                self.control_pos += len;
+                len
+            } else if self.add != 0 {
+                let len = self.add.min(out.len());
+                self.add_deltas(&mut out[..len])?;
+                len
+            } else if self.copy != 0 {
+                let len = self.copy.min(out.len());
+                match self.fill {
+                    Some(byte) => out[..len].fill(byte),
+                    None => {
+                        let literals = self.literals.get(self.literals_read..);
+                        let literals = literals.and_then(|l| l.get(..len));
+                        out[..len].copy_from_slice(literals.ok_or(UnexpectedEof)?);
+                        self.literals_read += len;
+                    }
+                }
+                self.copy -= len;
+                len
+            } else if self.next < self.adds.len() {
+                self.begin()?;
+                0
+            } else {
+                if self.deltas_read != self.delta_pos.len() {
+                    return Err(io::Error::new(InvalidData, "delta beyond last add"));
+                }
+                break;
+            };
+            written += len;
+        }
+        Ok(written)
+    }
+
+    /// Encode the next control and prepare its payloads.
+    fn begin(&mut self) -> io::Result<()> {
+        let i = self.next;
+        let (add, copy) = (self.adds[i], self.copies[i]);
+        let control = AehobakControl::try_from(&[add, copy, self.seeks[i]][..])
+            .map_err(|_| io::Error::from(InvalidData))?;
+        let mut encoded = Vec::with_capacity(24);
+        BsdiffControl::from(&control).encode(&mut encoded);
+        self.control.copy_from_slice(&encoded);
+        self.control_pos = 0;
+        self.add = add as usize;
+        self.add_end += add as usize;
+        self.copy = copy as usize;
+        // Fills have no bsdiff equivalent, so are expanded to literals
+        self.fill = match self.fills.get(self.fills_read) {
+            Some(fill) if fill.control == i => {
+                self.fills_read += 1;
+                Some(fill.byte)
+            }
+            _ => None,
+        };
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Write the next bytes of the current add, which are zero but for deltas.
+    fn add_deltas(&mut self, out: &mut [u8]) -> io::Result<()> {
+        out.fill(0);
+        let start = self.add_end - self.add;
+        let end = start + out.len();
+        while let Some(&pos) = self.delta_pos.get(self.deltas_read) {
+            let pos = pos as usize;
+            if pos >= end {
                 break;
             }
-            delta_buf[delta_cursor - add_cursor] = delta_diffs[0];
-            delta_pos = &delta_pos[1..];
-            delta_diffs = &delta_diffs[1..];
+            let offset = pos.checked_sub(start).ok_or(InvalidData)?;
+            out[offset] = self.delta_diffs[self.deltas_read];
+            self.deltas_read += 1;
         }
-        patch.extend(&delta_buf);
-        patch.extend(&literals[..copy]);
-        literals = &literals[copy..];
-        add_cursor += add;
+        self.add -= out.len();
+        Ok(())
     }
-    Ok(())
+}
+
+/// Read exactly `len` bytes, allocating only as they arrive.
+fn read_vec<T: Read>(reader: &mut T, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::Error::from(UnexpectedEof));
+    }
+    Ok(buf)
 }
@@ -24,15 +24,512 @@
  */
 
 use crate::control::Aehobak;
+use crate::cost::{CostModel, SizeCost};
 use crate::encode::EncoderState;
-use anyhow::{ensure, Context, Error, Result};
+use crate::filter::Filter;
+use crate::format::{Fill, Header, Reference, SelfCopy};
+use crate::git;
+use crate::index::{Entry, Index, Suffixes, NARROW_MAX};
+use crate::optimize::optimize;
+use crate::parse::parse;
+use crate::relocate::Relocations;
+use crate::repeat;
+use crate::sort::{default_sorter, SuffixSorter};
+use crate::tree;
+use crate::vcdiff;
+use crate::window;
+use anyhow::{ensure, Context, Result};
 use std::io;
 use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use xxhash_rust::xxh3::xxh3_128;
 
 /// Directly generate a compact representation of bsdiff output.
 /// If numeric limits are reached, the error will be wrapped with `io::Error`.
 pub fn diff<T: Write>(old: &[u8], new: &[u8], writer: &mut T) -> io::Result<()> {
-    match diff_internal(old, new, writer) {
+    DiffOptions::new().diff(old, new, writer)
+}
+
+/// Directly generate a compact representation of bsdiff output,
+/// reusing the suffix array of a previously built `Index`.
+/// If numeric limits are reached, the error will be wrapped with `io::Error`.
+pub fn diff_with_index<T: Write>(index: &Index, new: &[u8], writer: &mut T) -> io::Result<()> {
+    DiffOptions::new().diff_with_index(index, new, writer)
+}
+
+/// Directly generate a compact representation of bsdiff output,
+/// matching against the concatenation of several references in order.
+/// If numeric limits are reached, the error will be wrapped with `io::Error`.
+pub fn diff_multi<T: Write>(olds: &[&[u8]], new: &[u8], writer: &mut T) -> io::Result<()> {
+    DiffOptions::new().diff_multi(olds, new, writer)
+}
+
+/// Rewrite `patch` with cheaper controls, given the `old` and `new` it
+/// connects, without searching for new matches.
+/// If `patch` does not produce `new` from `old`, the error is `InvalidInput`.
+pub fn reoptimize<T: Write>(
+    old: &[u8],
+    new: &[u8],
+    patch: &[u8],
+    writer: &mut T,
+) -> io::Result<()> {
+    DiffOptions::new().reoptimize(old, new, patch, writer)
+}
+
+/// Convert VCDIFF (RFC 3284) to a compact representation, given the old it
+/// applies to.
+pub fn import_vcdiff<T: Write>(old: &[u8], vcdiff: &[u8], writer: &mut T) -> io::Result<()> {
+    DiffOptions::new().import_vcdiff(old, vcdiff, writer)
+}
+
+/// Convert a git delta against `base` to a compact representation.
+pub fn import_git_delta<T: Write>(base: &[u8], delta: &[u8], writer: &mut T) -> io::Result<()> {
+    DiffOptions::new().import_git_delta(base, delta, writer)
+}
+
+/// Write a patch that rebuilds the directory tree at `new` from the tree at `old`.
+/// See `DiffOptions::diff_tree`.
+pub fn diff_tree<T: Write>(old: &Path, new: &Path, writer: &mut T) -> io::Result<()> {
+    DiffOptions::new().diff_tree(old, new, writer)
+}
+
+/// Configuration for patch generation.
+#[derive(Clone, Debug, Default)]
+pub struct DiffOptions {
+    segment_len: Option<usize>,
+    window_len: Option<usize>,
+    #[cfg(feature = "object")]
+    objects: bool,
+    tar: bool,
+    self_copy: bool,
+    fill: bool,
+    filter: Option<Filter>,
+    sorter: Option<Arc<dyn SuffixSorter>>,
+    cost: Option<Arc<dyn CostModel>>,
+    /// The longest old indexed with 32-bit offsets, if not `NARROW_MAX`.
+    narrow_max: Option<usize>,
+}
+
+impl DiffOptions {
+    /// Options for a serial diff of whole files, as `diff` produces.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index old with 64-bit offsets beyond `len` bytes, so that tests of
+    /// modest size take the path of inputs beyond `i32::MAX` bytes.
+    #[cfg(test)]
+    pub(crate) fn narrow_max(mut self, len: usize) -> Self {
+        self.narrow_max = Some(len);
+        self
+    }
+
+    /// Build suffix arrays with `sorter` rather than the default backend.
+    pub fn sorter<S: SuffixSorter + 'static>(mut self, sorter: S) -> Self {
+        self.sorter = Some(Arc::new(sorter));
+        self
+    }
+
+    /// Reparse along the diagonals of the greedy scan to reduce the cost of
+    /// controls under `model`. `SizeCost` favours small patches and
+    /// `SpeedCost` favours fast application.
+    pub fn cost<M: CostModel + 'static>(mut self, model: M) -> Self {
+        self.cost = Some(Arc::new(model));
+        self
+    }
+
+    /// Scan `new` in segments of `segment_len` bytes across threads.
+    ///
+    /// Output depends only on `segment_len`, never on the thread count.
+    /// Each segment restarts the scan heuristics and matches are split at
+    /// segment boundaries, so patches grow slightly as segments shrink.
+    #[cfg(feature = "rayon")]
+    pub fn parallel(mut self, segment_len: usize) -> Self {
+        self.segment_len = Some(segment_len.max(1));
+        self
+    }
+
+    /// Transform old and new with a branch-call-jump `filter` before diffing.
+    /// The filter is recorded in the patch, and `patch` inverts it.
+    /// Filtered patches cannot be decoded to bsdiff.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Copy repeats of earlier output in place of literals, where old has
+    /// no good match, such as duplicated tables or padding.
+    /// Patches with self-copies require version 2 to apply and cannot be
+    /// decoded to bsdiff.
+    pub fn self_copy(mut self, self_copy: bool) -> Self {
+        self.self_copy = self_copy;
+        self
+    }
+
+    /// Emit runs of a single byte that old has no match for, such as the
+    /// zero or erased-flash padding of images, as fill controls.
+    /// Patches with fills require version 3 to apply, and `decode` expands
+    /// fills to literals.
+    pub fn fill(mut self, fill: bool) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Bound suffix array memory by matching against `window_len` bytes of
+    /// old at a time, rather than sorting old whole.
+    ///
+    /// Old windows are placed by coarse block matching against each segment
+    /// of new, so content that moved further than a window is not found.
+    /// Has no effect on `diff_with_index`, where a full index already exists.
+    pub fn window(mut self, window_len: usize) -> Self {
+        self.window_len = Some(window_len.max(2));
+        self
+    }
+
+    /// Match sections and symbols of ELF, PE and Mach-O inputs by name.
+    ///
+    /// Each section of new is matched only against its counterpart in old,
+    /// so code that grew or moved stays aligned with its previous version.
+    /// Where sections of a linked file moved, pointers into them are rebased
+    /// in old before matching, and the shifts are recorded in the patch so
+    /// that `patch` rebases them too, rather than storing each as deltas.
+    /// Inputs that are not both object files are diffed as usual.
+    /// Takes precedence over `window`, and has no effect on `diff_with_index`.
+    #[cfg(feature = "object")]
+    pub fn objects(mut self, objects: bool) -> Self {
+        self.objects = objects;
+        self
+    }
+
+    /// Pair the members of tar archives by content and path, matching the
+    /// headers and contents of each new member only against its old
+    /// counterpart, so members that were reordered or whose headers shifted
+    /// stay aligned with their previous version. The patch reproduces new
+    /// exactly, including headers, checksums and padding.
+    /// Inputs that are not both tar archives are diffed as usual.
+    /// Takes precedence over `window`, and has no effect on `diff_with_index`.
+    pub fn tar(mut self, tar: bool) -> Self {
+        self.tar = tar;
+        self
+    }
+
+    /// Directly generate a compact representation of bsdiff output.
+    /// If numeric limits are reached, the error will be wrapped with `io::Error`.
+    pub fn diff<T: Write>(&self, old: &[u8], new: &[u8], writer: &mut T) -> io::Result<()> {
+        self.diff_header(old, new, self.header(), writer)
+    }
+
+    fn diff_header(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        mut header: Header,
+        writer: &mut dyn Write,
+    ) -> io::Result<()> {
+        let relocated;
+        let old = match self.relocations(old, new) {
+            Some((relocations, buf)) => {
+                header.relocations = Some(relocations);
+                relocated = buf;
+                &relocated[..]
+            }
+            None => old,
+        };
+        let filtered;
+        let (old, new) = match self.filter {
+            Some(filter) => {
+                let (mut old, mut new) = (old.to_vec(), new.to_vec());
+                filter.encode(&mut old);
+                filter.encode(&mut new);
+                filtered = (old, new);
+                (&filtered.0[..], &filtered.1[..])
+            }
+            None => (old, new),
+        };
+        #[cfg(feature = "object")]
+        if self.objects {
+            if let Some(plan) = crate::layout::plan(old, new) {
+                return into_io(self.diff_segments(old, new, plan, &header, writer));
+            }
+        }
+        if self.tar {
+            if let Some(plan) = crate::tar::plan(old, new) {
+                return into_io(self.diff_segments(old, new, plan, &header, writer));
+            }
+        }
+        if let Some(window_len) = self.window_len {
+            let plan = window::plan(old, new, window_len)
+                .into_iter()
+                .map(|(new_range, old_range)| (new_range, Some(old_range)))
+                .collect();
+            return into_io(self.diff_segments(old, new, plan, &header, writer));
+        }
+        let index = self.index(old)?;
+        into_io(self.diff_internal(&index, new, &header, writer))
+    }
+
+    /// Directly generate a compact representation of bsdiff output,
+    /// matching against the concatenation of several references in order.
+    /// The length and hash of each reference are recorded, and the patch
+    /// must be applied with `patch_multi` and the same references.
+    /// If numeric limits are reached, the error will be wrapped with `io::Error`.
+    pub fn diff_multi<T: Write>(
+        &self,
+        olds: &[&[u8]],
+        new: &[u8],
+        writer: &mut T,
+    ) -> io::Result<()> {
+        let references = olds.iter().map(|old| Reference {
+            len: old.len(),
+            hash: xxh3_128(old),
+        });
+        let header = Header {
+            references: Some(references.collect()),
+            ..self.header()
+        };
+        self.diff_header(&olds.concat(), new, header, writer)
+    }
+
+    /// Rewrite `patch` with cheaper controls, given the `old` and `new` it
+    /// connects, without searching for new matches.
+    ///
+    /// Adjacent controls are merged, boundaries are moved under the cost
+    /// model, which defaults to `SizeCost`, and empty controls are dropped.
+    /// The filter and relocations of `patch` are kept, while self-copies and
+    /// fills are kept and may be added if enabled.
+    /// If `patch` does not produce `new` from `old`, the error is `InvalidInput`.
+    pub fn reoptimize<T: Write>(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        patch: &[u8],
+        writer: &mut T,
+    ) -> io::Result<()> {
+        let mut applied = Vec::with_capacity(new.len());
+        if crate::patch(old, patch, &mut applied).is_err() || applied != new {
+            let msg = "patch does not produce new from old";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        let (mut header, ops) = parse(patch)?;
+        let relocated;
+        let old = match &header.relocations {
+            Some(relocations) => {
+                relocated = relocations.relocate(old);
+                &relocated[..]
+            }
+            None => old,
+        };
+        let filtered;
+        let (old, new) = match header.filter {
+            Some(filter) => {
+                let (mut old, mut new) = (old.to_vec(), new.to_vec());
+                filter.encode(&mut old);
+                filter.encode(&mut new);
+                filtered = (old, new);
+                (&filtered.0[..], &filtered.1[..])
+            }
+            None => (old, new),
+        };
+        if self.self_copy {
+            header.self_copies.get_or_insert_with(Vec::new);
+        }
+        if self.fill {
+            header.fills.get_or_insert_with(Vec::new);
+        }
+        let model = self.cost.as_deref().unwrap_or(&SizeCost);
+        let ops = optimize(old, new, &ops, model);
+        into_io(emit(old, new, &ops, &header, writer))
+    }
+
+    /// Convert VCDIFF (RFC 3284) to a compact representation, given the old
+    /// it applies to.
+    ///
+    /// Copies from old become adds, and the other instructions literals,
+    /// which are then folded into adds as byte deltas under the cost model.
+    /// Runs and copies within new are found again where `fill` and
+    /// `self_copy` are enabled.
+    pub fn import_vcdiff<T: Write>(
+        &self,
+        old: &[u8],
+        vcdiff: &[u8],
+        writer: &mut T,
+    ) -> io::Result<()> {
+        let (new, ops) = vcdiff::read(old, vcdiff)?;
+        self.import(old, &new, &ops, writer)
+    }
+
+    /// Write a patch that rebuilds the directory tree at `new` from the tree
+    /// at `old`, to be applied by `apply_tree`.
+    ///
+    /// Files are paired by path, and by content to follow renames and moves:
+    /// a file identical to any old file is recorded as such, and otherwise is
+    /// diffed with these options against the old file sharing most of its
+    /// content, or failing that the old file at the same path. Files that
+    /// diff no smaller than themselves are stored in full. Directories,
+    /// permissions and symlinks are recorded as found, and paths must be UTF-8.
+    pub fn diff_tree<T: Write>(&self, old: &Path, new: &Path, writer: &mut T) -> io::Result<()> {
+        tree::diff(self, old, new, writer)
+    }
+
+    /// Convert a git delta against `base` to a compact representation.
+    ///
+    /// Copies from base become adds, and inserts literals, which are then
+    /// folded into adds as byte deltas under the cost model. Runs and copies
+    /// within the result are found where `fill` and `self_copy` are enabled.
+    pub fn import_git_delta<T: Write>(
+        &self,
+        base: &[u8],
+        delta: &[u8],
+        writer: &mut T,
+    ) -> io::Result<()> {
+        let (new, ops) = git::read(base, delta)?;
+        self.import(base, &new, &ops, writer)
+    }
+
+    /// Directly generate a compact representation of bsdiff output,
+    /// reusing the suffix array of a previously built `Index`.
+    /// If numeric limits are reached, the error will be wrapped with `io::Error`.
+    pub fn diff_with_index<T: Write>(
+        &self,
+        index: &Index,
+        new: &[u8],
+        writer: &mut T,
+    ) -> io::Result<()> {
+        if self.filter.is_some() {
+            let msg = "filters apply only to DiffOptions::diff";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        into_io(self.diff_internal(index, new, &self.header(), writer))
+    }
+
+    /// Encode controls converted from another format, which ignore filters.
+    fn import(&self, old: &[u8], new: &[u8], ops: &[Op], writer: &mut dyn Write) -> io::Result<()> {
+        let header = Header {
+            filter: None,
+            ..self.header()
+        };
+        let model = self.cost.as_deref().unwrap_or(&SizeCost);
+        let ops = optimize(old, new, ops, model);
+        into_io(emit(old, new, &ops, &header, writer))
+    }
+
+    fn header(&self) -> Header {
+        Header {
+            filter: self.filter,
+            self_copies: self.self_copy.then(Vec::new),
+            fills: self.fill.then(Vec::new),
+            relocations: None,
+            references: None,
+        }
+    }
+
+    /// Pointer shifts between object files, with old rebased by them,
+    /// where enabled and any pointer of old moves.
+    fn relocations(&self, old: &[u8], new: &[u8]) -> Option<(Relocations, Vec<u8>)> {
+        #[cfg(feature = "object")]
+        if self.objects {
+            let relocations = crate::layout::relocations(old, new)?;
+            let relocated = relocations.relocate(old);
+            return (relocated != old).then_some((relocations, relocated));
+        }
+        let _ = (old, new);
+        None
+    }
+
+    fn suffix_sorter(&self) -> &dyn SuffixSorter {
+        self.sorter.as_deref().unwrap_or(default_sorter())
+    }
+
+    fn index<'a>(&self, old: &'a [u8]) -> io::Result<Index<'a>> {
+        let narrow_max = self.narrow_max.unwrap_or(NARROW_MAX);
+        Index::with_limit(old, self.suffix_sorter(), narrow_max)
+    }
+
+    fn diff_internal(
+        &self,
+        index: &Index,
+        new: &[u8],
+        header: &Header,
+        writer: &mut dyn Write,
+    ) -> Result<()> {
+        let (old, sa) = (index.old(), index.suffixes());
+        let ops = match self.segment_len {
+            #[cfg(feature = "rayon")]
+            Some(segment_len) => {
+                use rayon::prelude::*;
+                let segments = new
+                    .par_chunks(segment_len)
+                    .map(|segment| scan(old, segment, sa))
+                    .collect::<Result<Vec<_>>>()?;
+                segments.concat()
+            }
+            _ => scan(old, new, sa)?,
+        };
+        self.emit(old, new, ops, header, writer)
+    }
+
+    /// Scan each range of new against its range of old, or all of old.
+    fn diff_segments(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        plan: Vec<Segment>,
+        header: &Header,
+        writer: &mut dyn Write,
+    ) -> Result<()> {
+        // Ranges of old recur in runs, and those left open in object and tar
+        // plans all share an index of the whole of old, so besides that index
+        // only the last is kept, and no more than one window is indexed at once
+        let mut whole: Option<Index> = None;
+        let mut last: Option<(Range<usize>, Index)> = None;
+        let mut ops = Vec::new();
+        for (new_range, old_range) in plan {
+            let (index, old_start) = match old_range {
+                None => {
+                    if whole.is_none() {
+                        whole = Some(self.index(old)?);
+                    }
+                    (whole.as_ref().unwrap(), 0)
+                }
+                Some(old_range) => {
+                    if last.as_ref().is_none_or(|(range, _)| *range != old_range) {
+                        // The previous index is freed before the next is built
+                        drop(last.take());
+                        last = Some((old_range.clone(), self.index(&old[old_range])?));
+                    }
+                    let (range, index) = last.as_ref().unwrap();
+                    (index, range.start)
+                }
+            };
+            let segment = scan(index.old(), &new[new_range], index.suffixes())?;
+            ops.extend(segment.into_iter().map(|op| Op {
+                old: op.old + old_start,
+                ..op
+            }));
+        }
+        self.emit(old, new, ops, header, writer)
+    }
+
+    fn emit(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        ops: Vec<Op>,
+        header: &Header,
+        writer: &mut dyn Write,
+    ) -> Result<()> {
+        let ops = match &self.cost {
+            Some(model) => optimize(old, new, &ops, model.as_ref()),
+            None => ops,
+        };
+        emit(old, new, &ops, header, writer)
+    }
+}
+
+fn into_io(result: Result<()>) -> io::Result<()> {
+    match result {
         Ok(_) => Ok(()),
         Err(e) => match e.downcast::<io::Error>() {
             Ok(e) => Err(e),
@@ -41,56 +538,206 @@ pub fn diff<T: Write>(old: &[u8], new: &[u8], writer: &mut T) -> io::Result<()>
     }
 }
 
-fn diff_internal(old: &[u8], new: &[u8], writer: &mut dyn Write) -> Result<()> {
-    #[cfg(miri)]
-    let sa = suf_sort_naive(old)?;
-    #[cfg(not(miri))]
-    let sa = sais(old)?;
-    let mut scanner = ScanState::new(old, new, &sa);
-    let mut encoder = EncoderState::new(new.len());
+/// A run of new and the part of old it is matched against, if restricted.
+pub(crate) type Segment = (Range<usize>, Option<Range<usize>>);
+
+/// A control with its source expressed as an absolute offset into old.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Op {
+    pub old: usize,
+    pub add: usize,
+    pub copy: usize,
+    pub source: Source,
+}
+
+/// Where the copied bytes of a control come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Source {
+    Literal,
+    /// Earlier output, this many bytes back.
+    Repeat(usize),
+    /// A run of a single byte.
+    Fill(u8),
+}
+
+fn scan(old: &[u8], new: &[u8], sa: Suffixes) -> Result<Vec<Op>> {
+    // Small inputs keep the compact 32-bit suffix array
+    match sa {
+        Suffixes::Narrow(sa) => scan_with(old, new, sa),
+        Suffixes::Wide(sa) => scan_with(old, new, sa),
+    }
+}
+
+fn scan_with<S: Entry>(old: &[u8], new: &[u8], sa: &[S]) -> Result<Vec<Op>> {
+    let mut scanner = ScanState::new(old, new, sa);
+    let mut ops = Vec::new();
 
     while !scanner.done() {
         if !scanner.advance() {
             continue;
         }
         let (add, back) = scanner.optimize_overlap(scanner.calc_add(), scanner.calc_back());
-        let (copy, seek) = scanner.calc_copy_seek(add, back);
-
-        let add_u32: u32 = add.try_into()?;
-        let copy_u32: u32 = copy.try_into()?;
-        let seek_i32: i32 = seek.try_into()?;
-
-        encoder.control(Aehobak {
-            add: add_u32,
-            copy: copy_u32,
-            seek: seek_i32,
+        let copy = scanner.calc_copy(add, back);
+        ops.push(Op {
+            old: scanner.last_pos,
+            add,
+            copy,
+            source: Source::Literal,
         });
-        encoder.add(scanner.old_add_slice(add)?, scanner.new_add_slice(add)?);
-        encoder.copy(scanner.new_copy_slice(add, copy)?);
         scanner.commit(back)?;
     }
+    Ok(ops)
+}
+
+/// Encode a sequence of controls that together produce `new`.
+/// Each seek leads to the next op, so the final control seeks by zero.
+pub(crate) fn emit(
+    old: &[u8],
+    new: &[u8],
+    ops: &[Op],
+    header: &Header,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let fenced;
+    let ops = if ops.iter().map(|op| op.add).sum::<usize>() > DELTA_FENCE {
+        fenced = fence_deltas(old, new, ops, DELTA_FENCE)?;
+        &fenced[..]
+    } else {
+        ops
+    };
+    let mut header = header.clone();
+    let filled;
+    let ops = match header.fills {
+        Some(_) => {
+            filled = repeat::fill(new, ops);
+            &filled[..]
+        }
+        None => ops,
+    };
+    let repeated;
+    let ops = match header.self_copies {
+        Some(_) => {
+            repeated = repeat::split(new, ops);
+            &repeated[..]
+        }
+        None => ops,
+    };
+    let (mut self_copies, mut fills) = (Vec::new(), Vec::new());
+    let mut encoder = EncoderState::new(new.len());
+    let mut new_cursor = 0;
+    // Controls begin reading old from offset zero
+    chain_seek(&mut encoder, ops.first().map_or(0, |op| op.old as i64));
+    for (i, op) in ops.iter().enumerate() {
+        let old_end = op.old.checked_add(op.add).context("")?;
+        let next = ops.get(i + 1).map_or(old_end, |next| next.old);
+        let mut seek = (next as i64).checked_sub(old_end as i64).context("")?;
+
+        // Lengths beyond the range of a single control are split
+        let (mut old_cursor, mut add, mut copy) = (op.old, op.add, op.copy);
+        loop {
+            let add_u32 = add.min(u32::MAX as usize) as u32;
+            let copy_u32 = match add_u32 as usize == add {
+                true => copy.min(u32::MAX as usize) as u32,
+                false => 0,
+            };
+            let last = add_u32 as usize == add && copy_u32 as usize == copy;
+            let seek_i32 = match last {
+                true => seek.clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+                false => 0,
+            };
+            seek -= i64::from(seek_i32);
+
+            let control = encoder.controls();
+            match op.source {
+                _ if copy_u32 == 0 => {}
+                Source::Literal => {}
+                Source::Repeat(distance) => self_copies.push(SelfCopy { control, distance }),
+                Source::Fill(byte) => fills.push(Fill { control, byte }),
+            }
+            encoder.control(Aehobak {
+                add: add_u32,
+                copy: copy_u32,
+                seek: seek_i32,
+            });
+            let (piece_add, piece_copy) = (add_u32 as usize, copy_u32 as usize);
+            let new_add = new.get(new_cursor..).and_then(|s| s.get(..piece_add));
+            let old_add = old.get(old_cursor..).and_then(|s| s.get(..piece_add));
+            encoder.add(old_add.context("")?, new_add.context("")?);
+            old_cursor += piece_add;
+            new_cursor += piece_add;
+            add -= piece_add;
+            let new_copy = new.get(new_cursor..).and_then(|s| s.get(..piece_copy));
+            let new_copy = new_copy.context("")?;
+            if op.source == Source::Literal {
+                encoder.copy(new_copy);
+            }
+            new_cursor += piece_copy;
+            copy -= piece_copy;
+            if last {
+                break;
+            }
+        }
+        chain_seek(&mut encoder, seek);
+    }
+    ensure!(new_cursor == new.len(), "controls do not cover new");
+    if header.self_copies.is_some() {
+        header.self_copies = Some(self_copies);
+    }
+    if header.fills.is_some() {
+        header.fills = Some(fills);
+    }
+    header.write(writer)?;
     encoder.finalize(writer)?;
     Ok(())
 }
 
-#[cfg(miri)]
-fn suf_sort_naive(old: &[u8]) -> Result<Box<[u32]>> {
-    ensure!(old.len() <= i32::MAX as usize, "input too large");
-    let mut sa: Vec<u32> = (0..old.len() as u32).collect();
-    sa.sort_unstable_by_key(|&v| {
-        // SAFETY: Values of `sa` are offsets into `old`
-        unsafe { old.get_unchecked(v as usize..) }
-    });
-    Ok(sa.into_boxed_slice())
+/// Delta positions are accumulated in 32 bits by appliers.
+const DELTA_FENCE: usize = u32::MAX as usize;
+
+/// Replace every nonzero delta at or beyond `fence` bytes of cumulative add
+/// with a single literal, so that delta positions never wrap.
+fn fence_deltas(old: &[u8], new: &[u8], ops: &[Op], fence: usize) -> Result<Vec<Op>> {
+    let mut fenced = Vec::with_capacity(ops.len());
+    let (mut add_cursor, mut new_cursor) = (0, 0);
+    for op in ops {
+        let old_add = old.get(op.old..).and_then(|s| s.get(..op.add));
+        let new_add = new.get(new_cursor..).and_then(|s| s.get(..op.add));
+        let (old_add, new_add) = (old_add.context("")?, new_add.context("")?);
+        let mut start = 0;
+        for i in fence.saturating_sub(add_cursor)..op.add {
+            if old_add[i] != new_add[i] {
+                fenced.push(Op {
+                    old: op.old + start,
+                    add: i - start,
+                    copy: 1,
+                    source: Source::Literal,
+                });
+                start = i + 1;
+            }
+        }
+        fenced.push(Op {
+            old: op.old + start,
+            add: op.add - start,
+            copy: op.copy,
+            source: op.source,
+        });
+        add_cursor += op.add;
+        new_cursor += op.add + op.copy;
+    }
+    Ok(fenced)
 }
 
-#[cfg(not(miri))]
-fn sais(old: &[u8]) -> Result<Box<[u32]>> {
-    ensure!(old.len() <= i32::MAX as usize, "input too large");
-    let (_, sa) = cdivsufsort::sort(old).into_parts();
-    // SAFETY: i32 to u32 transmute is safe; non-negative values
-    let sa: Vec<u32> = unsafe { core::mem::transmute(sa) };
-    Ok(sa.into_boxed_slice())
+/// Seeks beyond the range of a single control are chained through empty controls.
+fn chain_seek(encoder: &mut EncoderState, mut seek: i64) {
+    while seek != 0 {
+        let seek_i32 = seek.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+        seek -= i64::from(seek_i32);
+        encoder.control(Aehobak {
+            add: 0,
+            copy: 0,
+            seek: seek_i32,
+        });
+    }
 }
 
 #[inline(never)]
@@ -112,8 +759,8 @@ fn mismatch(old: &[u8], new: &[u8]) -> usize {
     i
 }
 
-struct ScanState<'a> {
-    sa: &'a [u32],
+struct ScanState<'a, S> {
+    sa: &'a [S],
     old: &'a [u8],
     new: &'a [u8],
     scan: usize,
@@ -124,9 +771,9 @@ struct ScanState<'a> {
     last_offset: isize,
 }
 
-impl<'a> ScanState<'a> {
+impl<'a, S: Entry> ScanState<'a, S> {
     #[inline(always)]
-    fn new(old: &'a [u8], new: &'a [u8], sa: &'a [u32]) -> Self {
+    fn new(old: &'a [u8], new: &'a [u8], sa: &'a [S]) -> Self {
         Self {
             sa,
             old,
@@ -153,7 +800,7 @@ impl<'a> ScanState<'a> {
         while sa.len() > 2 {
             let pos = (sa.len() - 1) / 2;
             // SAFETY: pos indexes sa
-            let old_start = unsafe { *sa.get_unchecked(pos) as usize };
+            let old_start = unsafe { sa.get_unchecked(pos).offset() };
             // SAFETY: content of sa indexes old
             let old_slice = unsafe { self.old.get_unchecked(old_start..) };
 
@@ -171,8 +818,8 @@ impl<'a> ScanState<'a> {
         // SAFETY: sa is not empty
         let (a_start, b_start) = unsafe {
             (
-                *sa.first().unwrap_unchecked() as usize,
-                *sa.last().unwrap_unchecked() as usize,
+                sa.first().unwrap_unchecked().offset(),
+                sa.last().unwrap_unchecked().offset(),
             )
         };
         // SAFETY: content of sa indexes old
@@ -243,7 +890,7 @@ impl<'a> ScanState<'a> {
                 score += 1;
             }
             i += 1;
-            if score * 2 - i as i32 > best * 2 - add as i32 {
+            if score * 2 - i as isize > best * 2 - add as isize {
                 best = score;
                 add = i;
             }
@@ -314,35 +961,8 @@ impl<'a> ScanState<'a> {
         (add, back)
     }
 
-    fn calc_copy_seek(&self, add: usize, back: usize) -> (usize, isize) {
-        let copy = self.scan - back - (self.last_scan + add);
-        let seek = (self.pos as isize) - (self.last_pos as isize) - ((back + add) as isize);
-        (copy, seek)
-    }
-
-    #[inline(always)]
-    fn old_add_slice(&self, add: usize) -> Result<&[u8], Error> {
-        self.old
-            .get(self.last_pos..)
-            .and_then(|s| s.get(..add))
-            .context("")
-    }
-
-    #[inline(always)]
-    fn new_add_slice(&self, add: usize) -> Result<&[u8], Error> {
-        self.new
-            .get(self.last_scan..)
-            .and_then(|s| s.get(..add))
-            .context("")
-    }
-
-    #[inline(always)]
-    fn new_copy_slice(&self, add: usize, copy: usize) -> Result<&[u8], Error> {
-        self.new
-            .get(self.last_scan..)
-            .and_then(|s| s.get(add..))
-            .and_then(|s| s.get(..copy))
-            .context("")
+    fn calc_copy(&self, add: usize, back: usize) -> usize {
+        self.scan - back - (self.last_scan + add)
     }
 
     fn commit(&mut self, back: usize) -> Result<()> {
@@ -354,3 +974,31 @@ impl<'a> ScanState<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    quickcheck! {
+        fn fenced_deltas(old: Vec<u8>, new: Vec<u8>, fence: u8) -> bool {
+            let index = Index::new(&old).unwrap();
+            let ops = scan(&old, &new, index.suffixes()).unwrap();
+            let fenced = fence_deltas(&old, &new, &ops, fence as usize).unwrap();
+            let mut encoded = Vec::new();
+            emit(&old, &new, &fenced, &Header::default(), &mut encoded).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            crate::patch(&old, &encoded, &mut result).unwrap();
+            let (mut add_cursor, mut new_cursor) = (0, 0);
+            let fenced_ok = fenced.iter().all(|op| {
+                let ok = (0..op.add).all(|i| {
+                    add_cursor + i < fence as usize || old[op.old + i] == new[new_cursor + i]
+                });
+                add_cursor += op.add;
+                new_cursor += op.add + op.copy;
+                ok
+            });
+            result == new && fenced_ok
+        }
+    }
+}
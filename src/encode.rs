@@ -26,31 +26,116 @@
 use crate::control::Aehobak as AehobakControl;
 use crate::control::Bsdiff as BsdiffControl;
 use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
 use std::io::Write;
 use streamvbyte64::{Coder, Coder0124};
 
 /// Encode bsdiff output, returning a compact representation.
+///
+/// Truncated controls or payloads are reported as `UnexpectedEof`, and
+/// values beyond the range of the format as `InvalidData`.
 pub fn encode<T: Write>(patch: &[u8], writer: &mut T) -> io::Result<()> {
     encode_internal(patch, writer)
 }
 
-fn encode_internal(mut patch: &[u8], writer: &mut dyn Write) -> io::Result<()> {
-    let mut encoder = EncoderState::new(patch.len());
-
-    while 24 <= patch.len() {
-        let control: AehobakControl = BsdiffControl::try_from(&patch[..24])
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let (add, copy) = (control.add as usize, control.copy as usize);
-        encoder.control(control);
-        patch = &patch[24..];
-        encoder.add_diffed(&patch[..add]);
-        patch = &patch[add..];
-        encoder.copy(&patch[..copy]);
-        patch = &patch[copy..];
+fn encode_internal(patch: &[u8], writer: &mut dyn Write) -> io::Result<()> {
+    let mut encoder = Encoder::with_state(writer, EncoderState::new(patch.len()));
+    encoder.write_all(patch)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Transcode bsdiff output to aehobak as it is written.
+///
+/// Controls and their payloads are consumed as they arrive, so the bsdiff
+/// stream is never held in full. The encoded patch is written by `finish`,
+/// which reports a stream that ends partway through a control. After any
+/// error, the encoder should be discarded.
+///
+/// ```
+/// use std::io::Write;
+///
+/// let old = vec![1, 2, 3, 4, 5];
+/// let new = vec![1, 2, 4, 6];
+/// let mut encoder = aehobak::Encoder::new(Vec::new());
+/// bsdiff::diff(&old, &new, &mut encoder).unwrap();
+/// let encoded = encoder.finish().unwrap();
+///
+/// let mut patched = Vec::with_capacity(new.len());
+/// aehobak::patch(&old, &encoded, &mut patched).unwrap();
+/// assert_eq!(patched, new);
+/// ```
+pub struct Encoder<W: Write> {
+    writer: W,
+    state: EncoderState,
+    control: Vec<u8>,
+    add: usize,
+    copy: usize,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder that writes the encoded patch to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self::with_state(writer, EncoderState::new(0))
+    }
+
+    fn with_state(writer: W, state: EncoderState) -> Self {
+        Self {
+            writer,
+            state,
+            control: Vec::with_capacity(24),
+            add: 0,
+            copy: 0,
+        }
+    }
+
+    /// Write the encoded patch, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.control.is_empty() || self.add != 0 || self.copy != 0 {
+            return Err(io::Error::from(UnexpectedEof));
+        }
+        self.state.finalize(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            if self.add != 0 {
+                let (deltas, rest) = buf.split_at(self.add.min(buf.len()));
+                self.state.add_diffed(deltas)?;
+                self.add -= deltas.len();
+                buf = rest;
+            } else if self.copy != 0 {
+                let (literals, rest) = buf.split_at(self.copy.min(buf.len()));
+                self.state.copy(literals);
+                self.copy -= literals.len();
+                buf = rest;
+            } else {
+                let (head, rest) = buf.split_at((24 - self.control.len()).min(buf.len()));
+                self.control.extend_from_slice(head);
+                buf = rest;
+                if self.control.len() == 24 {
+                    let control: AehobakControl = BsdiffControl::try_from(&self.control[..])
+                        .map_err(|_| UnexpectedEof)?
+                        .try_into()
+                        .map_err(|_| io::Error::new(InvalidData, "control out of range"))?;
+                    (self.add, self.copy) = (control.add as usize, control.copy as usize);
+                    self.state.control(control);
+                    self.control.clear();
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// The encoded patch is only written by `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
-    encoder.finalize(writer)
 }
 
 pub struct EncoderState {
@@ -79,6 +164,10 @@ impl EncoderState {
         }
     }
 
+    pub fn controls(&self) -> usize {
+        self.adds.len()
+    }
+
     pub fn control(&mut self, control: AehobakControl) {
         control.encode((&mut self.adds, &mut self.copies, &mut self.seeks));
     }
@@ -102,16 +191,21 @@ impl EncoderState {
         self.add_cursor += add;
     }
 
-    pub fn add_diffed(&mut self, deltas: &[u8]) {
+    pub fn add_diffed(&mut self, deltas: &[u8]) -> io::Result<()> {
         for (i, &delta) in deltas.iter().enumerate() {
             if delta != 0 {
                 let skip = self.add_cursor + i - self.delta_cursor;
-                self.delta_skips.push(skip.try_into().unwrap());
+                // Appliers accumulate delta positions in 32 bits
+                if self.add_cursor + i > u32::MAX as usize {
+                    return Err(io::Error::new(InvalidData, "delta beyond 4 GiB of add"));
+                }
+                self.delta_skips.push(skip as u32);
                 self.delta_diffs.push(delta);
                 self.delta_cursor += skip + 1;
             }
         }
         self.add_cursor += deltas.len();
+        Ok(())
     }
 
     pub fn copy(&mut self, new: &[u8]) {
@@ -122,6 +216,11 @@ impl EncoderState {
         let coder = Coder0124::new();
 
         let controls = self.adds.len();
+        let len = |len: usize| {
+            u32::try_from(len).map_err(|_| io::Error::new(InvalidData, "patch exceeds 4 GiB"))
+        };
+        let (deltas_len, literals_len) = (len(self.delta_diffs.len())?, len(self.literals.len())?);
+        let controls_len = len(controls)?;
         let padding = controls.wrapping_neg() % 4;
         self.seeks.resize(controls + padding, 0);
         self.adds.resize(controls + padding, 0);
@@ -143,17 +242,13 @@ impl EncoderState {
         let (tags, data) = encoded.split_at_mut(tag_len);
         let data_len = coder.encode(&u32_seq, tags, data);
         let data = &data[..data_len];
+        let data_len = len(data_len)?;
 
         let mut prefix = [0u8; 17];
         let prefix_len = 1 + {
             let (tag, data) = prefix.as_mut_slice().split_at_mut(1);
             coder.encode(
-                &[
-                    self.delta_diffs.len() as u32,
-                    self.literals.len() as u32,
-                    controls as u32,
-                    data_len as u32,
-                ],
+                &[deltas_len, literals_len, controls_len, data_len],
                 tag,
                 data,
             )
@@ -0,0 +1,211 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+/// A branch-call-jump filter, applied to both old and new before diffing.
+///
+/// In the manner of xz's BCJ filters, relative branch targets are rewritten as
+/// absolute addresses, so that code insertions no longer perturb every call
+/// that crosses them. The transform is reversible for arbitrary input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// `E8`/`E9` call and jump with 32-bit displacements within ±16 MiB.
+    X86,
+    /// `BL` and `ADRP` instructions.
+    Arm64,
+    /// `JAL` instructions that link through `ra` or `t0`.
+    RiscV,
+}
+
+impl Filter {
+    /// Convert relative branch targets to absolute addresses.
+    pub fn encode(self, buf: &mut [u8]) {
+        self.apply(buf, true)
+    }
+
+    /// Invert `encode`, restoring relative branch targets.
+    pub fn decode(self, buf: &mut [u8]) {
+        self.apply(buf, false)
+    }
+
+    fn apply(self, buf: &mut [u8], encode: bool) {
+        match self {
+            Filter::X86 => x86(buf, encode),
+            Filter::Arm64 => arm64(buf, encode),
+            Filter::RiscV => riscv(buf, encode),
+        }
+    }
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Filter::X86 => 1,
+            Filter::Arm64 => 2,
+            Filter::RiscV => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Filter::X86),
+            2 => Some(Filter::Arm64),
+            3 => Some(Filter::RiscV),
+            _ => None,
+        }
+    }
+}
+
+fn x86(buf: &mut [u8], encode: bool) {
+    // Only displacements whose top byte is a sign extension are converted,
+    // and results are stored in the same 25-bit form, so the decoder makes
+    // identical choices.
+    let mut i = 0;
+    while i + 5 <= buf.len() {
+        if !matches!(buf[i], 0xE8 | 0xE9) || !matches!(buf[i + 4], 0x00 | 0xFF) {
+            i += 1;
+            continue;
+        }
+        let src = u32::from_le_bytes(buf[i + 1..i + 5].try_into().unwrap());
+        let pc = (i as u32).wrapping_add(5);
+        let dest = match encode {
+            true => src.wrapping_add(pc),
+            false => src.wrapping_sub(pc),
+        };
+        let dest = ((dest << 7) as i32 >> 7) as u32;
+        buf[i + 1..i + 5].copy_from_slice(&dest.to_le_bytes());
+        i += 5;
+    }
+}
+
+fn arm64(buf: &mut [u8], encode: bool) {
+    for (i, word) in buf.chunks_exact_mut(4).enumerate() {
+        let pc = (i as u32).wrapping_mul(4);
+        let mut instr = u32::from_le_bytes((&*word).try_into().unwrap());
+        if instr >> 26 == 0x25 {
+            // BL
+            let pc = pc >> 2;
+            let pc = if encode { pc } else { pc.wrapping_neg() };
+            instr = 0x94000000 | (instr.wrapping_add(pc) & 0x03FFFFFF);
+        } else if instr & 0x9F000000 == 0x90000000 {
+            // ADRP, limited to ±512 MiB so the decoder sees the same range
+            let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001FFFFC);
+            if src.wrapping_add(0x00020000) & 0x001C0000 != 0 {
+                continue;
+            }
+            let pc = pc >> 12;
+            let pc = if encode { pc } else { pc.wrapping_neg() };
+            let dest = src.wrapping_add(pc);
+            instr &= 0x9000001F;
+            instr |= (dest & 3) << 29;
+            instr |= (dest & 0x0003FFFC) << 3;
+            instr |= (dest & 0x00020000).wrapping_neg() & 0x00E00000;
+        } else {
+            continue;
+        }
+        word.copy_from_slice(&instr.to_le_bytes());
+    }
+}
+
+fn riscv(buf: &mut [u8], encode: bool) {
+    // Instructions are 2-byte aligned with compressed extensions.
+    // Opcode and destination register are never rewritten.
+    let mut i = 0;
+    while i + 4 <= buf.len() {
+        let instr = u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let rd = (instr >> 7) & 0x1F;
+        if instr & 0x7F != 0x6F || (rd != 1 && rd != 5) {
+            i += 2;
+            continue;
+        }
+        let imm = ((instr >> 31) & 1) << 20
+            | ((instr >> 21) & 0x3FF) << 1
+            | ((instr >> 20) & 1) << 11
+            | ((instr >> 12) & 0xFF) << 12;
+        let pc = i as u32;
+        let dest = match encode {
+            true => imm.wrapping_add(pc),
+            false => imm.wrapping_sub(pc),
+        } & 0x001FFFFE;
+        let instr = (instr & 0xFFF)
+            | ((dest >> 20) & 1) << 31
+            | ((dest >> 1) & 0x3FF) << 21
+            | ((dest >> 11) & 1) << 20
+            | ((dest >> 12) & 0xFF) << 12;
+        buf[i..i + 4].copy_from_slice(&instr.to_le_bytes());
+        i += 4;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    quickcheck! {
+        fn filter_round_trip(buf: Vec<u8>, seed: u8) -> bool {
+            // Bias the input towards opcodes of interest
+            let mut buf = buf;
+            for (i, b) in buf.iter_mut().enumerate() {
+                if (i as u8).wrapping_mul(seed).is_multiple_of(7) {
+                    *b = [0xE8, 0xE9, 0x00, 0xFF, 0x94, 0x90, 0x6F, 0xEF][i % 8];
+                }
+            }
+            [Filter::X86, Filter::Arm64, Filter::RiscV].into_iter().all(|filter| {
+                let mut filtered = buf.clone();
+                filter.encode(&mut filtered);
+                filter.decode(&mut filtered);
+                filtered == buf
+            })
+        }
+    }
+
+    #[test]
+    fn x86_calls_become_absolute() {
+        // Two calls to the same target from different sites
+        let mut code = vec![0x90; 32];
+        code[0..5].copy_from_slice(&[0xE8, 0x1B, 0x00, 0x00, 0x00]);
+        code[16..21].copy_from_slice(&[0xE8, 0x0B, 0x00, 0x00, 0x00]);
+        Filter::X86.encode(&mut code);
+        assert_eq!(code[1..5], code[17..21]);
+        assert_eq!(code[1..5], [0x20, 0, 0, 0]);
+    }
+
+    #[test]
+    fn arm64_calls_become_absolute() {
+        let mut code = Vec::new();
+        code.extend(0x94000004u32.to_le_bytes()); // BL +16 at 0
+        code.extend(0x94000003u32.to_le_bytes()); // BL +12 at 4
+        Filter::Arm64.encode(&mut code);
+        assert_eq!(code[0..4], code[4..8]);
+    }
+
+    #[test]
+    fn riscv_calls_become_absolute() {
+        let jal = |imm: u32| 0x0EF | ((imm >> 1) & 0x3FF) << 21;
+        let mut code = Vec::new();
+        code.extend(jal(16).to_le_bytes()); // JAL ra, +16 at 0
+        code.extend(jal(12).to_le_bytes()); // JAL ra, +12 at 4
+        Filter::RiscV.encode(&mut code);
+        assert_eq!(code[0..4], code[4..8]);
+    }
+}
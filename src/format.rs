@@ -0,0 +1,364 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::filter::Filter;
+use crate::relocate::{Relocations, Shift};
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use std::io::{Read, Write};
+
+// Extended patches begin with a prefix that declares no controls and nearly
+// 4 GiB of deltas, which no legacy patch can hold, so legacy appliers reject
+// them as truncated. The second byte carries the format version, which is
+// the lowest that supports every feature in use.
+const EXTENDED: [u8; 5] = [0x03, VERSION, 0xFF, 0xFF, 0xFF];
+const VERSION: u8 = 4;
+
+const FILTER: u8 = 1;
+const SELF_COPY: u8 = 2;
+const FILL: u8 = 4;
+const RELOCATE: u8 = 8;
+const REFERENCES: u8 = 16;
+const KNOWN: u8 = FILTER | SELF_COPY | FILL | RELOCATE | REFERENCES;
+
+/// Optional features of a patch, recorded ahead of the legacy layout.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Header {
+    pub filter: Option<Filter>,
+    /// Controls whose copy repeats earlier output rather than reading literals.
+    /// `None` disables self-copies, while `Some` permits `emit` to add them.
+    pub self_copies: Option<Vec<SelfCopy>>,
+    /// Controls whose copy is a run of one byte rather than literals.
+    /// `None` disables fills, while `Some` permits `emit` to add them.
+    pub fills: Option<Vec<Fill>>,
+    /// Pointers in old rebased before applying the controls.
+    pub relocations: Option<Relocations>,
+    /// The references whose concatenation is old, for `patch_multi`.
+    pub references: Option<Vec<Reference>>,
+}
+
+/// A reference of `diff_multi`, identified by its length and hash.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Reference {
+    pub len: usize,
+    pub hash: u128,
+}
+
+/// The copy of control number `control` reads output from `distance` bytes back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SelfCopy {
+    pub control: usize,
+    pub distance: usize,
+}
+
+/// The copy of control number `control` repeats `byte`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fill {
+    pub control: usize,
+    pub byte: u8,
+}
+
+impl Header {
+    fn features(&self) -> u8 {
+        let mut features = 0;
+        if self.filter.is_some() {
+            features |= FILTER;
+        }
+        if self.self_copies.as_ref().is_some_and(|c| !c.is_empty()) {
+            features |= SELF_COPY;
+        }
+        if self.fills.as_ref().is_some_and(|f| !f.is_empty()) {
+            features |= FILL;
+        }
+        if self.relocations.is_some() {
+            features |= RELOCATE;
+        }
+        if self.references.is_some() {
+            features |= REFERENCES;
+        }
+        features
+    }
+
+    fn version(&self) -> u8 {
+        match self.features() {
+            f if f & (RELOCATE | REFERENCES) != 0 => 4,
+            f if f & FILL != 0 => 3,
+            f if f & SELF_COPY != 0 => 2,
+            _ => 1,
+        }
+    }
+
+    /// Self-copies in control order, or none.
+    pub fn self_copies(&self) -> &[SelfCopy] {
+        self.self_copies.as_deref().unwrap_or_default()
+    }
+
+    /// Fills in control order, or none.
+    pub fn fills(&self) -> &[Fill] {
+        self.fills.as_deref().unwrap_or_default()
+    }
+
+    /// Split a patch into its header and legacy body.
+    pub fn parse(patch: &[u8]) -> io::Result<(Self, &[u8])> {
+        if !is_extended(patch) {
+            return Ok((Self::default(), patch));
+        }
+        check_version(patch)?;
+        let mut rest = &patch[EXTENDED.len()..];
+        let header = Self::read_from(&mut rest)?;
+        Ok((header, rest))
+    }
+
+    /// Read the remainder of a header, after the prefix recognised by `is_extended`.
+    pub fn read_from<T: Read>(reader: &mut T) -> io::Result<Self> {
+        let mut features = [0];
+        reader.read_exact(&mut features)?;
+        let features = features[0];
+        if features & !KNOWN != 0 {
+            return Err(io::Error::new(InvalidData, "unknown patch feature"));
+        }
+        let mut header = Self::default();
+        if features & FILTER != 0 {
+            let mut id = [0];
+            reader.read_exact(&mut id)?;
+            let filter =
+                Filter::from_id(id[0]).ok_or(io::Error::new(InvalidData, "unknown filter"))?;
+            header.filter = Some(filter);
+        }
+        if features & SELF_COPY != 0 {
+            let mut copies = Vec::new();
+            for (control, distance) in read_controls(reader)? {
+                if distance == 0 {
+                    return Err(io::Error::new(InvalidData, "zero self-copy distance"));
+                }
+                copies.push(SelfCopy { control, distance });
+            }
+            header.self_copies = Some(copies);
+        }
+        if features & FILL != 0 {
+            let mut fills = Vec::new();
+            for (control, byte) in read_controls(reader)? {
+                let byte = u8::try_from(byte).map_err(|_| io::Error::from(InvalidData))?;
+                fills.push(Fill { control, byte });
+            }
+            header.fills = Some(fills);
+        }
+        if features & RELOCATE != 0 {
+            header.relocations = Some(read_relocations(reader)?);
+        }
+        if features & REFERENCES != 0 {
+            let mut references = Vec::new();
+            for _ in 0..read_varint(reader)? {
+                let len = read_varint(reader)?;
+                let mut hash = [0; 16];
+                reader.read_exact(&mut hash)?;
+                let hash = u128::from_le_bytes(hash);
+                references.push(Reference { len, hash });
+            }
+            header.references = Some(references);
+        }
+        Ok(header)
+    }
+
+    /// Write the header, or nothing for a legacy patch.
+    pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let features = self.features();
+        if features == 0 {
+            return Ok(());
+        }
+        let mut prefix = EXTENDED;
+        prefix[1] = self.version();
+        writer.write_all(&prefix)?;
+        writer.write_all(&[features])?;
+        if let Some(filter) = self.filter {
+            writer.write_all(&[filter.id()])?;
+        }
+        if features & SELF_COPY != 0 {
+            let copies = self.self_copies().iter();
+            write_controls(writer, copies.map(|c| (c.control, c.distance)))?;
+        }
+        if features & FILL != 0 {
+            let fills = self.fills().iter();
+            write_controls(writer, fills.map(|f| (f.control, f.byte.into())))?;
+        }
+        if let Some(relocations) = &self.relocations {
+            write_relocations(writer, relocations)?;
+        }
+        if let Some(references) = &self.references {
+            write_varint(writer, references.len())?;
+            for reference in references {
+                write_varint(writer, reference.len)?;
+                writer.write_all(&reference.hash.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Relocations are stored as a byte holding the pointer width, with the top
+// bit set for big-endian, then the scanned ranges and the shifted ranges.
+// Ranges are in order, so each start is stored as the gap after the end of
+// its predecessor, followed by the length and, for shifts, the zigzag delta.
+fn read_relocations<T: Read>(reader: &mut T) -> io::Result<Relocations> {
+    let invalid = || io::Error::new(InvalidData, "invalid relocations");
+    let mut layout = [0];
+    reader.read_exact(&mut layout)?;
+    let width = layout[0] & 0x7F;
+    if width != 4 && width != 8 {
+        return Err(invalid());
+    }
+    let mut ranges = |params| {
+        let mut ranges = Vec::new();
+        let mut end = 0usize;
+        for _ in 0..read_varint(reader)? {
+            let start = end.checked_add(read_varint(reader)?).ok_or_else(invalid)?;
+            end = start
+                .checked_add(read_varint(reader)?)
+                .ok_or_else(invalid)?;
+            let delta = match params {
+                true => read_varint(reader)? as u64,
+                false => 0,
+            };
+            ranges.push((start..end, (delta >> 1) as i64 ^ -((delta & 1) as i64)));
+        }
+        Ok::<_, io::Error>(ranges)
+    };
+    let scan = ranges(false)?.into_iter().map(|(range, _)| range).collect();
+    let shifts = (ranges(true)?.into_iter())
+        .map(|(range, delta)| Shift {
+            start: range.start,
+            len: range.len(),
+            delta,
+        })
+        .collect();
+    Ok(Relocations {
+        width,
+        big_endian: layout[0] & 0x80 != 0,
+        scan,
+        shifts,
+    })
+}
+
+fn write_relocations(writer: &mut dyn Write, relocations: &Relocations) -> io::Result<()> {
+    let order = if relocations.big_endian { 0x80 } else { 0 };
+    writer.write_all(&[relocations.width | order])?;
+    write_varint(writer, relocations.scan.len())?;
+    let mut end = 0;
+    for range in &relocations.scan {
+        write_varint(writer, range.start - end)?;
+        write_varint(writer, range.len())?;
+        end = range.end;
+    }
+    write_varint(writer, relocations.shifts.len())?;
+    let mut end = 0;
+    for shift in &relocations.shifts {
+        write_varint(writer, shift.start - end)?;
+        write_varint(writer, shift.len)?;
+        write_varint(writer, ((shift.delta << 1) ^ (shift.delta >> 63)) as usize)?;
+        end = shift.start + shift.len;
+    }
+    Ok(())
+}
+
+// Lists of controls are stored as a count, then a pair for each control.
+// Control numbers are strictly increasing, so each is stored as the gap
+// after its predecessor, followed by a parameter.
+fn read_controls<T: Read>(reader: &mut T) -> io::Result<Vec<(usize, usize)>> {
+    let mut controls = Vec::new();
+    let mut next = 0usize;
+    for _ in 0..read_varint(reader)? {
+        let control = next
+            .checked_add(read_varint(reader)?)
+            .ok_or(io::Error::from(InvalidData))?;
+        controls.push((control, read_varint(reader)?));
+        next = control.checked_add(1).ok_or(io::Error::from(InvalidData))?;
+    }
+    Ok(controls)
+}
+
+fn write_controls<I>(writer: &mut dyn Write, controls: I) -> io::Result<()>
+where
+    I: ExactSizeIterator<Item = (usize, usize)>,
+{
+    write_varint(writer, controls.len())?;
+    let mut next = 0;
+    for (control, param) in controls {
+        write_varint(writer, control - next)?;
+        write_varint(writer, param)?;
+        next = control + 1;
+    }
+    Ok(())
+}
+
+/// Write an unsigned LEB128 integer.
+fn write_varint(writer: &mut dyn Write, mut value: usize) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        buf[len] = value as u8 & 0x7F;
+        value >>= 7;
+        if value == 0 {
+            len += 1;
+            break;
+        }
+        buf[len] |= 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+/// Read an unsigned LEB128 integer, rejecting values beyond `usize`.
+fn read_varint<T: Read>(reader: &mut T) -> io::Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        let bits = usize::from(byte[0] & 0x7F);
+        if bits << shift >> shift != bits {
+            return Err(io::Error::new(InvalidData, "varint overflow"));
+        }
+        value |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(InvalidData, "varint overflow"))
+}
+
+/// Whether `prefix` begins an extended patch, of any version.
+pub(crate) fn is_extended(prefix: &[u8]) -> bool {
+    prefix.len() >= EXTENDED.len()
+        && prefix[0] == EXTENDED[0]
+        && prefix[2..EXTENDED.len()] == EXTENDED[2..]
+}
+
+/// Reject versions newer than this implementation with `InvalidData`.
+pub(crate) fn check_version(prefix: &[u8]) -> io::Result<()> {
+    match prefix.get(1) {
+        Some(&v) if (1..=VERSION).contains(&v) => Ok(()),
+        Some(_) => Err(io::Error::new(InvalidData, "unsupported patch version")),
+        None => Err(io::Error::from(UnexpectedEof)),
+    }
+}
@@ -0,0 +1,175 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::convert::{instructions, push_add, push_literal, Inst};
+use crate::diff::Op;
+use crate::limits::{self, Limits};
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use std::io::Write;
+
+/// Shorter matches cost more as a copy than as inserted bytes.
+const MIN_COPY: usize = 8;
+/// Copies are split at the largest size understood by every version of git.
+const MAX_COPY: usize = 0x10000;
+const MAX_INSERT: usize = 0x7F;
+
+/// Convert a patch to a git delta against `base`.
+///
+/// Each add of the patch becomes copies of the bytes it leaves unchanged and
+/// inserts of those it changes. Fills, self-copies and copies beyond the
+/// 32-bit offsets of git deltas are expanded to inserts.
+pub fn export_git_delta<T: Write>(base: &[u8], patch: &[u8], writer: &mut T) -> io::Result<()> {
+    let (new, insts) = instructions(base, patch, MIN_COPY)?;
+    let mut delta = Vec::new();
+    write_size(&mut delta, base.len());
+    write_size(&mut delta, new.len());
+    let mut pos = 0;
+    for inst in insts {
+        match inst {
+            Inst::Copy(mut from, len) if from + len <= 1 << 32 => {
+                for chunk in (0..len).step_by(MAX_COPY) {
+                    let size = (len - chunk).min(MAX_COPY);
+                    write_copy(&mut delta, from, size);
+                    from += size;
+                }
+            }
+            _ => {
+                for chunk in new[pos..pos + inst.len()].chunks(MAX_INSERT) {
+                    delta.push(chunk.len() as u8);
+                    delta.extend_from_slice(chunk);
+                }
+            }
+        }
+        pos += inst.len();
+    }
+    writer.write_all(&delta)
+}
+
+/// Write a copy opcode, with only the nonzero bytes of offset and size.
+fn write_copy(delta: &mut Vec<u8>, offset: usize, size: usize) {
+    let opcode = delta.len();
+    delta.push(0x80);
+    for i in 0..4 {
+        let byte = (offset >> (8 * i)) as u8;
+        if byte != 0 {
+            delta[opcode] |= 1 << i;
+            delta.push(byte);
+        }
+    }
+    // A size of zero stands for the largest copy
+    let size = size % MAX_COPY;
+    for i in 0..3 {
+        let byte = (size >> (8 * i)) as u8;
+        if byte != 0 {
+            delta[opcode] |= 0x10 << i;
+            delta.push(byte);
+        }
+    }
+}
+
+/// Apply a git delta to `base`, returning the result and controls that produce it.
+///
+/// Copies become adds, and inserts literals, but for short inserts between
+/// copies along one diagonal, which become deltas.
+pub(crate) fn read(base: &[u8], mut delta: &[u8]) -> io::Result<(Vec<u8>, Vec<Op>)> {
+    if read_size(&mut delta)? != base.len() {
+        return Err(io::Error::new(InvalidData, "git delta base size mismatch"));
+    }
+    let new_len = read_size(&mut delta)?;
+    if new_len > Limits::default().output {
+        return Err(limits::exceeded("output"));
+    }
+    let (mut new, mut ops) = (Vec::new(), Vec::new());
+    while let Some((&opcode, rest)) = delta.split_first() {
+        delta = rest;
+        match opcode {
+            0 => return Err(io::Error::new(InvalidData, "reserved git delta opcode")),
+            1..=0x7F => {
+                let len = usize::from(opcode);
+                let bytes = delta.get(..len).ok_or(UnexpectedEof)?;
+                new.extend_from_slice(bytes);
+                push_literal(&mut ops, len);
+                delta = &delta[len..];
+            }
+            _ => {
+                let mut field = |bits: u8, bytes: usize| -> io::Result<usize> {
+                    let mut value = 0;
+                    for i in 0..bytes {
+                        if bits & (1 << i) != 0 {
+                            let (&byte, rest) = delta.split_first().ok_or(UnexpectedEof)?;
+                            value |= usize::from(byte) << (8 * i);
+                            delta = rest;
+                        }
+                    }
+                    Ok(value)
+                };
+                let offset = field(opcode, 4)?;
+                let size = match field(opcode >> 4, 3)? {
+                    0 => MAX_COPY,
+                    size => size,
+                };
+                let bytes = base.get(offset..).and_then(|tail| tail.get(..size));
+                let bytes =
+                    bytes.ok_or(io::Error::new(InvalidData, "git delta copy beyond base"))?;
+                new.extend_from_slice(bytes);
+                push_add(&mut ops, offset, size);
+            }
+        }
+        if new.len() > new_len {
+            return Err(io::Error::new(InvalidData, "git delta result overrun"));
+        }
+    }
+    if new.len() != new_len {
+        return Err(io::Error::from(UnexpectedEof));
+    }
+    Ok((new, ops))
+}
+
+/// Read a size from the header of a git delta, seven bits at a time from the least significant.
+fn read_size(delta: &mut &[u8]) -> io::Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = delta.split_first().ok_or(UnexpectedEof)?;
+        *delta = rest;
+        let bits = usize::from(byte & 0x7F);
+        if bits << shift >> shift != bits {
+            break;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(InvalidData, "git delta size overflow"))
+}
+
+fn write_size(delta: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        delta.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    delta.push(value as u8);
+}
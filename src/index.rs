@@ -0,0 +1,296 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::sort::{default_sorter, SuffixSorter};
+use std::io;
+use std::io::ErrorKind::InvalidData;
+use std::io::Write;
+use std::ops::Deref;
+use xxhash_rust::xxh3::xxh3_128;
+
+const MAGIC: &[u8; 8] = b"AEHOBAKX";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 40;
+
+/// The longest `old` indexed with 32-bit suffix offsets.
+pub(crate) const NARROW_MAX: usize = i32::MAX as usize;
+
+/// A suffix array over `old`, reusable across many calls to `diff_with_index`.
+///
+/// An index may be persisted with `write_to` and reloaded with `from_bytes`,
+/// which borrows the suffix array in place when the buffer is suitably aligned.
+/// The serialized form is versioned and carries a hash of `old`, so loading
+/// against different content is rejected with `InvalidData`.
+///
+/// Suffix offsets are stored in 32 bits unless `old` exceeds `i32::MAX` bytes.
+pub struct Index<'a> {
+    old: &'a [u8],
+    sa: Width<'a>,
+}
+
+enum Width<'a> {
+    Narrow(Storage<'a, u32>),
+    Wide(Storage<'a, u64>),
+}
+
+/// A borrowed suffix array of either width.
+#[derive(Clone, Copy)]
+pub(crate) enum Suffixes<'s> {
+    Narrow(&'s [u32]),
+    Wide(&'s [u64]),
+}
+
+enum Storage<'a, T> {
+    Owned(Box<[T]>),
+    Borrowed(&'a [T]),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl<T: Entry> Deref for Storage<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Owned(sa) => sa,
+            Storage::Borrowed(sa) => sa,
+            #[cfg(feature = "mmap")]
+            Storage::Mapped(map) => {
+                // SAFETY: Alignment and length were validated by `Index::open`
+                let (_, sa, _) = unsafe { map[HEADER_LEN..].align_to::<T>() };
+                sa
+            }
+        }
+    }
+}
+
+/// An unsigned integer that may hold a suffix offset.
+pub(crate) trait Entry: Copy + Send + Sync + 'static {
+    const WIDTH: usize;
+    fn offset(self) -> usize;
+    fn from_le(bytes: &[u8]) -> Self;
+    fn to_le(self) -> [u8; 8];
+}
+
+impl Entry for u32 {
+    const WIDTH: usize = 4;
+
+    #[inline(always)]
+    fn offset(self) -> usize {
+        self as usize
+    }
+
+    fn from_le(bytes: &[u8]) -> Self {
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn to_le(self) -> [u8; 8] {
+        u64::from(self).to_le_bytes()
+    }
+}
+
+impl Entry for u64 {
+    const WIDTH: usize = 8;
+
+    #[inline(always)]
+    fn offset(self) -> usize {
+        self as usize
+    }
+
+    fn from_le(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn to_le(self) -> [u8; 8] {
+        self.to_le_bytes()
+    }
+}
+
+impl<'a> Index<'a> {
+    /// Sort the suffixes of `old`.
+    /// If numeric limits are reached, the error will be wrapped with `io::Error`.
+    pub fn new(old: &'a [u8]) -> io::Result<Self> {
+        Self::with_sorter(old, default_sorter())
+    }
+
+    /// Sort the suffixes of `old` with a specific backend.
+    /// Output of the sorter is validated before use.
+    pub fn with_sorter(old: &'a [u8], sorter: &dyn SuffixSorter) -> io::Result<Self> {
+        Self::with_limit(old, sorter, NARROW_MAX)
+    }
+
+    /// As `with_sorter`, with 64-bit offsets where `old` is longer than `narrow_max`.
+    pub(crate) fn with_limit(
+        old: &'a [u8],
+        sorter: &dyn SuffixSorter,
+        narrow_max: usize,
+    ) -> io::Result<Self> {
+        Self::with_width(old, sorter, old.len() > narrow_max)
+    }
+
+    pub(crate) fn with_width(
+        old: &'a [u8],
+        sorter: &dyn SuffixSorter,
+        wide: bool,
+    ) -> io::Result<Self> {
+        fn check<T: Entry>(old: &[u8], sa: Box<[T]>) -> io::Result<Box<[T]>> {
+            // The scanner trusts every entry to index `old`
+            if sa.len() != old.len() || sa.iter().any(|&v| v.offset() >= old.len()) {
+                return Err(io::Error::other("invalid suffix array"));
+            }
+            Ok(sa)
+        }
+        let sa = if wide {
+            Width::Wide(Storage::Owned(check(old, sorter.sort64(old)?)?))
+        } else {
+            Width::Narrow(Storage::Owned(check(old, sorter.sort(old)?)?))
+        };
+        Ok(Self { old, sa })
+    }
+
+    /// The content this index was built over.
+    pub fn old(&self) -> &'a [u8] {
+        self.old
+    }
+
+    pub(crate) fn suffixes(&self) -> Suffixes<'_> {
+        match &self.sa {
+            Width::Narrow(sa) => Suffixes::Narrow(sa),
+            Width::Wide(sa) => Suffixes::Wide(sa),
+        }
+    }
+
+    /// Serialize the index, including a header identifying `old`.
+    pub fn write_to<T: Write>(&self, writer: &mut T) -> io::Result<()> {
+        match &self.sa {
+            Width::Narrow(sa) => write_to(self.old, sa, writer),
+            Width::Wide(sa) => write_to(self.old, sa, writer),
+        }
+    }
+
+    /// Load an index previously written by `write_to`.
+    /// The suffix array is borrowed from `bytes` without copying
+    /// on little-endian targets when it is suitably aligned.
+    pub fn from_bytes(old: &'a [u8], bytes: &'a [u8]) -> io::Result<Self> {
+        let sa = match validate(old, bytes)? {
+            4 => Width::Narrow(load(&bytes[HEADER_LEN..])),
+            _ => Width::Wide(load(&bytes[HEADER_LEN..])),
+        };
+        Ok(Self { old, sa })
+    }
+
+    /// Memory-map an index previously written by `write_to`.
+    ///
+    /// The file must not be modified while the index is alive.
+    #[cfg(feature = "mmap")]
+    pub fn open<P: AsRef<std::path::Path>>(old: &'a [u8], path: P) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: The caller upholds that the file is not concurrently modified
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let width = validate(old, &map)?;
+        #[cfg(target_endian = "little")]
+        let sa = match width {
+            4 => Width::Narrow(Storage::Mapped(map)),
+            _ => Width::Wide(Storage::Mapped(map)),
+        };
+        #[cfg(not(target_endian = "little"))]
+        let sa = match width {
+            4 => Width::Narrow(Storage::Owned(load(&map[HEADER_LEN..]).to_vec().into())),
+            _ => Width::Wide(Storage::Owned(load(&map[HEADER_LEN..]).to_vec().into())),
+        };
+        Ok(Self { old, sa })
+    }
+}
+
+fn write_to<T: Entry>(old: &[u8], sa: &[T], writer: &mut dyn Write) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(T::WIDTH as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&(old.len() as u64).to_le_bytes());
+    header[24..40].copy_from_slice(&xxh3_128(old).to_le_bytes());
+    writer.write_all(&header)?;
+    let mut buf = Vec::with_capacity(8192);
+    for chunk in sa.chunks(1024) {
+        buf.clear();
+        buf.extend(
+            chunk
+                .iter()
+                .flat_map(|v| v.to_le().into_iter().take(T::WIDTH)),
+        );
+        writer.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+fn load<T: Entry>(payload: &[u8]) -> Storage<'_, T> {
+    #[cfg(target_endian = "little")]
+    {
+        // SAFETY: Every bit pattern is a valid unsigned integer
+        let (prefix, sa, _) = unsafe { payload.align_to::<T>() };
+        if prefix.is_empty() {
+            return Storage::Borrowed(sa);
+        }
+    }
+    Storage::Owned(payload.chunks_exact(T::WIDTH).map(T::from_le).collect())
+}
+
+/// Check the header and entries of a serialized index, returning its width.
+fn validate(old: &[u8], bytes: &[u8]) -> io::Result<usize> {
+    let invalid = |msg: &str| io::Error::new(InvalidData, msg);
+    let header = bytes.get(..HEADER_LEN).ok_or(invalid("truncated index"))?;
+    if &header[..8] != MAGIC {
+        return Err(invalid("not an index"));
+    }
+    if u32::from_le_bytes(header[8..12].try_into().unwrap()) != VERSION {
+        return Err(invalid("unsupported index version"));
+    }
+    let width = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    if width != u32::WIDTH && width != u64::WIDTH {
+        return Err(invalid("unsupported index width"));
+    }
+    if u64::from_le_bytes(header[16..24].try_into().unwrap()) != old.len() as u64
+        || u128::from_le_bytes(header[24..40].try_into().unwrap()) != xxh3_128(old)
+    {
+        return Err(invalid("index does not match old"));
+    }
+    let payload = &bytes[HEADER_LEN..];
+    if Some(payload.len()) != old.len().checked_mul(width) {
+        return Err(invalid("index length mismatch"));
+    }
+    // The scanner trusts every entry to index `old`
+    let in_range = match width {
+        4 => payload
+            .chunks_exact(4)
+            .all(|v| (<u32 as Entry>::from_le(v) as u64) < old.len() as u64),
+        _ => payload
+            .chunks_exact(8)
+            .all(|v| <u64 as Entry>::from_le(v) < old.len() as u64),
+    };
+    if !in_range {
+        return Err(invalid("index entry out of range"));
+    }
+    Ok(width)
+}
@@ -0,0 +1,95 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::Source;
+use crate::filter::Filter;
+use crate::format::Header;
+use crate::parse::parse;
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use streamvbyte64::{Coder, Coder0124};
+
+/// Summary of a patch, as recorded in its header and controls.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PatchInfo {
+    /// Length of new produced by the patch.
+    pub new_len: usize,
+    /// Extent of old read by the controls, the least length of old the patch applies to.
+    pub old_len: usize,
+    /// Number of controls.
+    pub controls: usize,
+    /// Bytes of new added to bytes of old.
+    pub added: usize,
+    /// Bytes of `added` that differ from old.
+    pub deltas: usize,
+    /// Bytes of new copied from literals.
+    pub literals: usize,
+    /// Bytes of new filled with runs of one byte.
+    pub filled: usize,
+    /// Bytes of new repeated from earlier output.
+    pub repeated: usize,
+    /// The filter applied to old and new, if any.
+    pub filter: Option<Filter>,
+    /// Ranges of addresses whose pointers in old are rebased before patching.
+    pub shifts: usize,
+    /// Number of references recorded for `patch_multi`, or zero.
+    pub references: usize,
+}
+
+/// Summarize `patch` without applying it, such as to preallocate new.
+///
+/// The controls are checked for consistency with each other, but not with
+/// any old, which is left to `patch`.
+pub fn inspect(patch: &[u8]) -> io::Result<PatchInfo> {
+    let (header, ops) = parse(patch)?;
+    let (_, body) = Header::parse(patch)?;
+    let (prefix_tag, body) = body.split_first_chunk::<1>().ok_or(UnexpectedEof)?;
+    let mut prefix = [0u32; 4];
+    Coder0124::new().decode(prefix_tag, body, &mut prefix);
+    let mut info = PatchInfo {
+        controls: ops.len(),
+        deltas: prefix[0] as usize,
+        filter: header.filter,
+        shifts: header.relocations.as_ref().map_or(0, |r| r.shifts.len()),
+        references: header.references.as_ref().map_or(0, Vec::len),
+        ..PatchInfo::default()
+    };
+    let invalid = || io::Error::new(InvalidData, "patch exceeds address space");
+    for op in &ops {
+        // Every other tally is bounded by this one
+        info.new_len = (info.new_len.checked_add(op.add))
+            .and_then(|len| len.checked_add(op.copy))
+            .ok_or_else(invalid)?;
+        let end = op.old.checked_add(op.add).ok_or_else(invalid)?;
+        info.old_len = info.old_len.max(if op.add == 0 { 0 } else { end });
+        info.added += op.add;
+        match op.source {
+            Source::Literal => info.literals += op.copy,
+            Source::Fill(_) => info.filled += op.copy,
+            Source::Repeat(_) => info.repeated += op.copy,
+        }
+    }
+    Ok(info)
+}
@@ -0,0 +1,239 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::Segment;
+use crate::relocate::{Relocations, Shift};
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Pair the sections and symbols of two object files by name.
+///
+/// Each section of new that also exists in old is matched only against that
+/// section, and within it each defined symbol is matched only against the
+/// symbol of the same name. Everything else, such as headers, symbol tables
+/// and sections without a counterpart, is matched against the whole of old.
+/// Returns `None` unless both inputs parse as object files.
+pub(crate) fn plan(old: &[u8], new: &[u8]) -> Option<Vec<Segment>> {
+    let old_file = object::File::parse(old).ok()?;
+    let new_file = object::File::parse(new).ok()?;
+    let old_sections = sections(&old_file, old.len());
+    let old_symbols = symbols(&old_file, old.len());
+
+    let mut section_pairs: Vec<(Range<usize>, Range<usize>)> = sections(&new_file, new.len())
+        .into_iter()
+        .filter_map(|(name, range)| Some((range, old_sections.get(name)?.clone())))
+        .collect();
+    let mut symbol_pairs: Vec<(Range<usize>, Range<usize>)> = symbols(&new_file, new.len())
+        .into_iter()
+        .filter_map(|(name, range)| Some((range, old_symbols.get(name)?.clone())))
+        .collect();
+    disjoint(&mut section_pairs);
+    disjoint(&mut symbol_pairs);
+
+    let mut plan = Vec::new();
+    let mut symbols = symbol_pairs.into_iter().peekable();
+    let mut pos = 0;
+    for (new_section, old_section) in section_pairs {
+        push(&mut plan, pos..new_section.start, None);
+        pos = new_section.start;
+        while let Some((new_symbol, old_symbol)) = symbols.next_if(|s| s.0.start < new_section.end)
+        {
+            if new_symbol.start < pos || new_symbol.end > new_section.end {
+                continue;
+            }
+            push(&mut plan, pos..new_symbol.start, Some(old_section.clone()));
+            push(&mut plan, new_symbol.clone(), Some(old_symbol));
+            pos = new_symbol.end;
+        }
+        push(&mut plan, pos..new_section.end, Some(old_section));
+        pos = new_section.end;
+    }
+    push(&mut plan, pos..new.len(), None);
+    Some(plan)
+}
+
+/// Address shifts of the sections that moved between two linked object
+/// files, and the sections of old that may hold pointers into them.
+///
+/// Sections are paired by name, and a section whose address changed shifts
+/// every address within it. All sections of old except code are scanned,
+/// as code mostly refers to addresses relatively, which `filter` handles.
+/// Returns `None` unless both inputs parse as object files of the same
+/// pointer width and byte order, and some section moved.
+pub(crate) fn relocations(old: &[u8], new: &[u8]) -> Option<Relocations> {
+    let old_file = object::File::parse(old).ok()?;
+    let new_file = object::File::parse(new).ok()?;
+    if old_file.is_64() != new_file.is_64()
+        || old_file.is_little_endian() != new_file.is_little_endian()
+    {
+        return None;
+    }
+    let new_addresses = addresses(&new_file);
+    let mut shifts: Vec<Shift> = addresses(&old_file)
+        .into_iter()
+        .filter_map(|(name, range)| {
+            let delta = new_addresses.get(name)?.start as i64 - range.start as i64;
+            let start = usize::try_from(range.start).ok()?;
+            let len = usize::try_from(range.end - range.start).ok()?;
+            (delta != 0).then_some(Shift { start, len, delta })
+        })
+        .collect();
+    if shifts.is_empty() {
+        return None;
+    }
+    // Overlapping sections, such as thread-local templates, keep the first
+    shifts.sort_by_key(|s| (s.start, s.len, s.delta));
+    let mut end = 0;
+    shifts.retain(|s| {
+        let keep = s.start >= end;
+        if keep {
+            end = s.start.saturating_add(s.len);
+        }
+        keep
+    });
+
+    let mut scan: Vec<Range<usize>> = old_file
+        .sections()
+        .filter(|section| section.kind() != SectionKind::Text)
+        .filter_map(|section| file_range(section.file_range(), old.len()))
+        .collect();
+    scan.sort_by_key(|range| (range.start, range.end));
+    let mut end = 0;
+    scan.retain(|range| {
+        let keep = range.start >= end;
+        if keep {
+            end = range.end;
+        }
+        keep
+    });
+    Some(Relocations {
+        width: if old_file.is_64() { 8 } else { 4 },
+        big_endian: !old_file.is_little_endian(),
+        scan,
+        shifts,
+    })
+}
+
+fn push(plan: &mut Vec<Segment>, new: Range<usize>, old: Option<Range<usize>>) {
+    if !new.is_empty() {
+        plan.push((new, old));
+    }
+}
+
+/// Sort pairs by their position in new and drop any that overlap.
+fn disjoint(pairs: &mut Vec<(Range<usize>, Range<usize>)>) {
+    pairs.sort_by_key(|(new, _)| (new.start, new.end));
+    let mut end = 0;
+    pairs.retain(|(new, _)| {
+        let keep = new.start >= end;
+        if keep {
+            end = new.end;
+        }
+        keep
+    });
+}
+
+/// File ranges of sections with contents, keyed by name.
+fn sections<'data>(file: &object::File<'data>, len: usize) -> HashMap<&'data str, Range<usize>> {
+    let mut sections = HashMap::new();
+    for section in file.sections() {
+        let (Ok(name), Some(range)) = (section.name(), file_range(section.file_range(), len))
+        else {
+            continue;
+        };
+        // Duplicate names are ambiguous, so neither is paired
+        if sections.insert(name, range.clone()).is_some() {
+            sections.insert(name, 0..0);
+        }
+    }
+    sections.retain(|_, range| range.start < range.end);
+    sections
+}
+
+/// Address ranges of allocated sections, keyed by name.
+fn addresses<'data>(file: &object::File<'data>) -> HashMap<&'data str, Range<u64>> {
+    let mut addresses = HashMap::new();
+    for section in file.sections() {
+        let Ok(name) = section.name() else {
+            continue;
+        };
+        let (start, size) = (section.address(), section.size());
+        if start == 0 || size == 0 {
+            continue;
+        }
+        let range = start..start.saturating_add(size);
+        if addresses.insert(name, range).is_some() {
+            addresses.insert(name, 0..0);
+        }
+    }
+    addresses.retain(|_, range| range.start < range.end);
+    addresses
+}
+
+/// File ranges of defined, sized symbols, keyed by name.
+fn symbols<'data>(file: &object::File<'data>, len: usize) -> HashMap<&'data str, Range<usize>> {
+    let mut symbols = HashMap::new();
+    for symbol in file.symbols() {
+        if !symbol.is_definition() || symbol.size() == 0 {
+            continue;
+        }
+        let Some(section) = symbol
+            .section_index()
+            .and_then(|index| file.section_by_index(index).ok())
+        else {
+            continue;
+        };
+        let (Ok(name), Some((offset, size))) = (symbol.name(), section.file_range()) else {
+            continue;
+        };
+        let Some(start) = symbol
+            .address()
+            .checked_sub(section.address())
+            .filter(|&start| start < size)
+        else {
+            continue;
+        };
+        let size = symbol.size().min(size - start);
+        let Some(range) = file_range(Some((offset + start, size)), len) else {
+            continue;
+        };
+        if name.is_empty() {
+            continue;
+        }
+        if symbols.insert(name, range.clone()).is_some() {
+            symbols.insert(name, 0..0);
+        }
+    }
+    symbols.retain(|_, range| range.start < range.end);
+    symbols
+}
+
+fn file_range(range: Option<(u64, u64)>, len: usize) -> Option<Range<usize>> {
+    let (offset, size) = range?;
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(size).ok()?)?;
+    (end <= len && start < end).then_some(start..end)
+}
@@ -25,21 +25,61 @@
 
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "bzip2")]
+mod container;
 mod control;
+mod convert;
+mod cost;
 mod decode;
 mod diff;
 mod encode;
+mod filter;
+mod format;
+mod git;
+mod index;
+mod inspect;
+#[cfg(feature = "object")]
+mod layout;
+mod limits;
+mod optimize;
+mod parse;
 mod patch;
+mod relocate;
+mod repeat;
+mod report;
+mod sort;
+mod tar;
+mod tree;
+mod update;
+mod vcdiff;
+mod window;
 
-pub use decode::decode;
-pub use diff::diff;
-pub use encode::encode;
-pub use patch::patch;
+#[cfg(feature = "bzip2")]
+pub use container::{decode_container, encode_container, Container};
+pub use cost::{CostModel, SizeCost, SpeedCost};
+pub use decode::{decode, Decoder};
+pub use diff::{
+    diff, diff_multi, diff_tree, diff_with_index, import_git_delta, import_vcdiff, reoptimize,
+    DiffOptions,
+};
+pub use encode::{encode, Encoder};
+pub use filter::Filter;
+pub use git::export_git_delta;
+pub use index::Index;
+pub use inspect::{inspect, PatchInfo};
+pub use limits::Limits;
+pub use patch::{patch, patch_multi};
+pub use report::{report, ReportFormat};
+#[cfg(feature = "libsais")]
+pub use sort::Libsais;
+pub use sort::{DivSufSort, NaiveSort, SuffixSorter};
+pub use tree::apply_tree;
+pub use update::Update;
+pub use vcdiff::export_vcdiff;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bsdiff;
     use quickcheck::{quickcheck, TestResult};
     use rand_xoshiro::rand_core::{RngCore, SeedableRng};
     use rand_xoshiro::Xoshiro256Plus;
@@ -144,6 +184,159 @@ mod tests {
             TestResult::from_bool(diff(&old, &new, &mut patch.as_mut_slice()).is_err())
         }
 
+        fn windowed_diff(old: Vec<u8>, new: Vec<u8>, window: u8) -> bool {
+            let mut encoded = Vec::new();
+            let options = DiffOptions::new().window(window as usize);
+            options.diff(&old, &new, &mut encoded).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, &encoded, &mut result).unwrap();
+            result == new
+        }
+
+        fn filtered_diff(old: Vec<u8>, new: Vec<u8>) -> bool {
+            [Filter::X86, Filter::Arm64, Filter::RiscV].into_iter().all(|filter| {
+                let mut encoded = Vec::new();
+                let options = DiffOptions::new().filter(filter);
+                options.diff(&old, &new, &mut encoded).unwrap();
+                let mut result = Vec::with_capacity(new.len());
+                patch(&old, &encoded, &mut result).unwrap();
+                let mut decoded = Vec::new();
+                let e = decode(&mut encoded.as_slice(), &mut decoded).unwrap_err();
+                result == new && e.kind() == std::io::ErrorKind::Unsupported
+            })
+        }
+
+        fn self_copy_diff(old: Vec<u8>, new: Vec<u8>, repeats: u8) -> bool {
+            // Repeat a suffix of new, which is unlikely to match old
+            let tail = new.len() / 2;
+            let mut new = new;
+            for _ in 0..repeats % 8 {
+                new.extend_from_within(tail..);
+            }
+            let mut encoded = Vec::new();
+            let options = DiffOptions::new().self_copy(true).filter(Filter::X86);
+            options.diff(&old, &new, &mut encoded).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, &encoded, &mut result).unwrap();
+            result == new
+        }
+
+        fn fill_diff(old: Vec<u8>, new: Vec<u8>, runs: Vec<(u8, u8)>) -> bool {
+            let mut new = new;
+            for (byte, len) in runs {
+                new.resize(new.len() + len as usize, byte);
+            }
+            let mut encoded = Vec::new();
+            let options = DiffOptions::new().fill(true).self_copy(true);
+            options.diff(&old, &new, &mut encoded).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, &encoded, &mut result).unwrap();
+            let options = DiffOptions::new().fill(true);
+            let mut encoded = Vec::new();
+            options.diff(&old, &new, &mut encoded).unwrap();
+            let mut decoded = Vec::new();
+            decode(&mut encoded.as_slice(), &mut decoded).unwrap();
+            let mut bspatched = Vec::new();
+            bsdiff::patch(&old, &mut decoded.as_slice(), &mut bspatched).unwrap();
+            result == new && bspatched == new
+        }
+
+        fn cost_diff(old: Vec<u8>, new: Vec<u8>) -> bool {
+            let models: [&dyn Fn(DiffOptions) -> DiffOptions; 2] =
+                [&|o| o.cost(SizeCost), &|o| o.cost(SpeedCost)];
+            models.into_iter().all(|model| {
+                let mut encoded = Vec::new();
+                model(DiffOptions::new()).diff(&old, &new, &mut encoded).unwrap();
+                let mut result = Vec::with_capacity(new.len());
+                patch(&old, &encoded, &mut result).unwrap();
+                result == new
+            })
+        }
+
+        fn reoptimized(old: Vec<u8>, new: Vec<u8>) -> bool {
+            let mut bspatch = Vec::new();
+            let mut encoded = Vec::new();
+            let mut reoptimized = Vec::new();
+            bsdiff::diff(&old, &new, &mut bspatch).unwrap();
+            encode(&bspatch, &mut encoded).unwrap();
+            reoptimize(&old, &new, &encoded, &mut reoptimized).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, &reoptimized, &mut result).unwrap();
+            result == new && reoptimized.len() <= encoded.len()
+        }
+
+        #[cfg_attr(miri, ignore)] // Slow
+        fn vcdiff_round_trip(old: Vec<u8>, new: Vec<u8>, fill: bool) -> bool {
+            let options = DiffOptions::new().fill(fill).self_copy(true);
+            let (mut encoded, mut vcdiff, mut imported) = (Vec::new(), Vec::new(), Vec::new());
+            options.diff(&old, &new, &mut encoded).unwrap();
+            export_vcdiff(&old, &encoded, &mut vcdiff).unwrap();
+            options.import_vcdiff(&old, &vcdiff, &mut imported).unwrap();
+            let mut patched = Vec::with_capacity(new.len());
+            patch(&old, &imported, &mut patched).unwrap();
+            patched == new
+        }
+
+        fn inspect_lengths(old: Vec<u8>, new: Vec<u8>, fill: bool) -> bool {
+            let options = DiffOptions::new().fill(fill).self_copy(true);
+            let mut encoded = Vec::new();
+            options.diff(&old, &new, &mut encoded).unwrap();
+            let info = inspect(&encoded).unwrap();
+            info.new_len == new.len()
+                && info.added + info.literals + info.filled + info.repeated == new.len()
+                && info.old_len <= old.len()
+                && info.deltas <= info.added
+        }
+
+        fn tar_round_trip(old: Vec<(u8, Vec<u8>)>, new: Vec<(u8, Vec<u8>)>) -> bool {
+            let tar = |members: &[(u8, Vec<u8>)]| {
+                let members: Vec<_> = (members.iter())
+                    .map(|(name, data)| (name.to_string(), u64::from(*name), &data[..]))
+                    .collect();
+                gen_tar(&members)
+            };
+            let (old, new) = (tar(&old), tar(&new));
+            let mut encoded = Vec::new();
+            DiffOptions::new().tar(true).diff(&old, &new, &mut encoded).unwrap();
+            let mut patched = Vec::with_capacity(new.len());
+            patch(&old, &encoded, &mut patched).unwrap();
+            patched == new
+        }
+
+        fn multi_round_trip(olds: Vec<Vec<u8>>, edits: Vec<(usize, u8)>) -> bool {
+            let olds: Vec<&[u8]> = olds.iter().map(Vec::as_slice).collect();
+            // Edits of the concatenation keep matches that span references
+            let mut new = olds.concat();
+            for (i, byte) in edits {
+                if !new.is_empty() {
+                    let len = new.len();
+                    new[i % len] = byte;
+                }
+            }
+            let mut encoded = Vec::new();
+            diff_multi(&olds, &new, &mut encoded).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch_multi(&olds, &encoded, &mut result).unwrap();
+            result == new
+        }
+
+        fn wide_sort(old: Vec<u8>, symbols: u8) -> bool {
+            // Few symbols force recursion on repeated LMS substrings
+            let old: Vec<u8> = old.iter().map(|b| b % symbols.max(1)).collect();
+            DivSufSort.sort64(&old).unwrap() == NaiveSort.sort64(&old).unwrap()
+        }
+
+        fn git_delta_round_trip(old: Vec<u8>, new: Vec<u8>, fill: bool) -> bool {
+            let options = DiffOptions::new().fill(fill).self_copy(true);
+            let (mut encoded, mut delta, mut imported) = (Vec::new(), Vec::new(), Vec::new());
+            options.diff(&old, &new, &mut encoded).unwrap();
+            export_git_delta(&old, &encoded, &mut delta).unwrap();
+            options.import_git_delta(&old, &delta, &mut imported).unwrap();
+            let mut patched = Vec::with_capacity(new.len());
+            patch(&old, &imported, &mut patched).unwrap();
+            patched == new
+        }
+
         #[cfg_attr(miri, ignore)] // Slow
         fn arbitrary_patch(skeleton: LinkedList<(u8,u8,i8)>, period: u8, phase: u8) -> bool {
             use std::io::ErrorKind::{InvalidData, UnexpectedEof};
@@ -164,6 +357,116 @@ mod tests {
             }
         }
 
+        #[cfg_attr(miri, ignore)] // Slow
+        fn arbitrary_decode(skeleton: LinkedList<(u8,u8,i8)>, period: u8, phase: u8) -> bool {
+            let (bspatch, _, _) = gen_bspatch(skeleton, period, phase);
+            let mut encoded = Vec::new();
+            let mut decoded = Vec::new();
+            encode(&bspatch, &mut encoded).unwrap();
+            decode(&mut encoded.as_slice(), &mut decoded).unwrap();
+            decoded == bspatch
+        }
+
+        #[cfg_attr(miri, ignore)] // Slow
+        fn streaming_decode(skeleton: LinkedList<(u8,u8,i8)>, chunks: Vec<u8>) -> bool {
+            let (bspatch, _, _) = gen_bspatch(skeleton, 3, 0);
+            let mut encoded = Vec::new();
+            encode(&bspatch, &mut encoded).unwrap();
+            use std::io::Read;
+            let mut decoder = Decoder::new(encoded.as_slice());
+            let mut decoded: Vec<u8> = Vec::new();
+            let mut buf = [0; 256];
+            for &len in chunks.iter().chain(&[u8::MAX]).cycle() {
+                let len = decoder.read(&mut buf[..len.max(1) as usize]).unwrap();
+                if len == 0 {
+                    break;
+                }
+                decoded.extend(&buf[..len]);
+            }
+            decoded == bspatch
+        }
+
+        #[cfg_attr(miri, ignore)] // Slow
+        fn corrupt_decode(skeleton: LinkedList<(u8,u8,i8)>, flips: Vec<(usize, u8)>, truncate: usize, fill: bool) -> TestResult {
+            use std::io::ErrorKind::{InvalidData, OutOfMemory, UnexpectedEof, Unsupported};
+            let mut encoded = Vec::new();
+            let old = if fill {
+                let Some((old, mut new)) = gen_old_new(skeleton, 3, 0) else {
+                    return TestResult::discard();
+                };
+                new.extend([0xFF; 64]);
+                DiffOptions::new().fill(true).diff(&old, &new, &mut encoded).unwrap();
+                old
+            } else {
+                let (bspatch, old_len, _) = gen_bspatch(skeleton, 3, 0);
+                encode(&bspatch, &mut encoded).unwrap();
+                vec![0; old_len]
+            };
+            if encoded.is_empty() {
+                return TestResult::discard();
+            }
+            for (pos, bits) in flips {
+                let len = encoded.len();
+                encoded[pos % len] ^= bits;
+            }
+            encoded.truncate(encoded.len() - truncate % encoded.len());
+            // Valid controls may declare far more output than the tests allow
+            match parse::parse(&encoded) {
+                Ok((_, ops)) if ops.iter().map(|op| op.add + op.copy).sum::<usize>() > 1 << 24 => {
+                    return TestResult::discard()
+                }
+                Err(e) if e.kind() != UnexpectedEof => return TestResult::discard(),
+                _ => {}
+            }
+            let mut decoded = Vec::new();
+            let reported = report(&old, &encoded, ReportFormat::Text, &mut Vec::new());
+            TestResult::from_bool([decode(&mut encoded.as_slice(), &mut decoded), reported].into_iter().all(|result| match result {
+                Ok(()) => true,
+                Err(e) => [InvalidData, OutOfMemory, UnexpectedEof, Unsupported].contains(&e.kind()),
+            }))
+        }
+
+        #[cfg_attr(miri, ignore)] // Slow
+        fn streaming_encode(skeleton: LinkedList<(u8,u8,i8)>, chunks: Vec<u8>) -> bool {
+            use std::io::Write;
+            let (bspatch, _, _) = gen_bspatch(skeleton, 3, 0);
+            let mut encoded = Vec::new();
+            encode(&bspatch, &mut encoded).unwrap();
+            let mut encoder = Encoder::new(Vec::new());
+            let mut rest = bspatch.as_slice();
+            let mut lens = chunks.iter().cycle();
+            while let Some(&len) = lens.next().filter(|_| !rest.is_empty()) {
+                let (chunk, tail) = rest.split_at((len as usize).clamp(1, rest.len()));
+                encoder.write_all(chunk).unwrap();
+                rest = tail;
+            }
+            encoder.write_all(rest).unwrap();
+            encoder.finish().unwrap() == encoded
+        }
+
+        #[cfg_attr(miri, ignore)] // Slow
+        fn corrupt_encode(skeleton: LinkedList<(u8,u8,i8)>, flips: Vec<(usize, u8)>, truncate: usize) -> TestResult {
+            use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+            let (mut bspatch, _, _) = gen_bspatch(skeleton, 3, 0);
+            if bspatch.is_empty() {
+                return TestResult::discard();
+            }
+            for (pos, bits) in flips {
+                let len = bspatch.len();
+                bspatch[pos % len] ^= bits;
+            }
+            bspatch.truncate(bspatch.len() - truncate % bspatch.len());
+            let mut encoded = Vec::new();
+            TestResult::from_bool(match encode(&bspatch, &mut encoded) {
+                Ok(()) => {
+                    let mut decoded = Vec::new();
+                    decode(&mut encoded.as_slice(), &mut decoded).unwrap();
+                    decoded == bspatch
+                }
+                Err(e) => [InvalidData, UnexpectedEof].contains(&e.kind()),
+            })
+        }
+
         #[cfg_attr(miri, ignore)] // Slow
         fn arbitrary_diff(skeleton: LinkedList<(u8,u8,i8)>, period: u8, phase: u8) -> TestResult {
             if let Some((old, new)) = gen_old_new(skeleton, period, phase) {
@@ -182,13 +485,1309 @@ mod tests {
         }
     }
 
+    #[test]
+    fn malformed_encode() {
+        use crate::control::Bsdiff;
+        use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+        let control = |add, copy, seek| {
+            let mut patch = Vec::new();
+            Bsdiff { add, copy, seek }.encode(&mut patch);
+            patch
+        };
+        let mut valid = control(2, 1, 0);
+        valid.extend([0, 1, 2]);
+        for (patch, kind) in [
+            (valid[..10].to_vec(), UnexpectedEof),
+            (valid[..26].to_vec(), UnexpectedEof),
+            ([&valid[..], &[0; 23]].concat(), UnexpectedEof),
+            (control(1 << 32, 0, 0), InvalidData),
+            (control(0, 1 << 32, 0), InvalidData),
+            (control(0, 0, 1 << 31), InvalidData),
+            (control(0, 0, -(1 << 31) - 1), InvalidData),
+        ] {
+            let result = encode(&patch, &mut Vec::new());
+            assert_eq!(result.unwrap_err().kind(), kind);
+        }
+        encode(&valid, &mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let (old, new) = gen_old_new(LinkedList::from([(40, 8, 3), (90, 0, -20)]), 7, 1).unwrap();
+        let index = Index::new(&old).unwrap();
+        let mut serialized = Vec::new();
+        index.write_to(&mut serialized).unwrap();
+        let mut reference = Vec::new();
+        diff(&old, &new, &mut reference).unwrap();
+        let mut aligned = vec![0u32; serialized.len().div_ceil(4)];
+        // SAFETY: Every bit pattern is a valid u8
+        let bytes: &mut [u8] = unsafe { aligned.align_to_mut::<u8>().1 };
+        bytes[..serialized.len()].copy_from_slice(&serialized);
+        for bytes in [&bytes[..serialized.len()], &serialized] {
+            let reloaded = Index::from_bytes(&old, bytes).unwrap();
+            let mut encoded = Vec::new();
+            diff_with_index(&reloaded, &new, &mut encoded).unwrap();
+            assert_eq!(encoded, reference);
+        }
+        let mut other = old.clone();
+        other[0] ^= 1;
+        let e = Index::from_bytes(&other, &serialized).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let e = Index::from_bytes(&old, &serialized[..serialized.len() - 1])
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    #[cfg_attr(miri, ignore)] // FFI
+    fn index_mmap() {
+        let (old, new) = gen_old_new(LinkedList::from([(40, 8, 3), (90, 0, -20)]), 7, 1).unwrap();
+        let path = std::env::temp_dir().join(format!("aehobak-{}.idx", std::process::id()));
+        Index::new(&old)
+            .unwrap()
+            .write_to(&mut std::fs::File::create(&path).unwrap())
+            .unwrap();
+        let index = Index::open(&old, &path).unwrap();
+        let mut reference = Vec::new();
+        let mut encoded = Vec::new();
+        diff(&old, &new, &mut reference).unwrap();
+        diff_with_index(&index, &new, &mut encoded).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(encoded, reference);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn parallel_diff() {
+        let (old, new) = gen_old_new(
+            LinkedList::from_iter((0..64).map(|i| (100 + i, 20, -50))),
+            5,
+            2,
+        )
+        .unwrap();
+        let options = DiffOptions::new().parallel(700);
+        let mut outputs = Vec::new();
+        for threads in [1, 3] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut encoded = Vec::new();
+            pool.install(|| options.diff(&old, &new, &mut encoded))
+                .unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, &encoded, &mut result).unwrap();
+            assert_eq!(result, new);
+            outputs.push(encoded);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn final_seek() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x9b05688c2b3e6c1f);
+        let mut old = vec![0; 4096];
+        rng.fill_bytes(&mut old);
+        // Swapped halves make bsdiff end with a seek back into old
+        let new = [&old[2048..], &old[..2048]].concat();
+        let mut encoded = Vec::new();
+        diff(&old, &new, &mut encoded).unwrap();
+        let mut decoded = Vec::new();
+        decode(&mut encoded.as_slice(), &mut decoded).unwrap();
+        let seeks = |mut rest: &[u8]| {
+            let mut seeks = Vec::new();
+            while !rest.is_empty() {
+                let field = |i: usize| {
+                    let x = u64::from_le_bytes(rest[i * 8..][..8].try_into().unwrap());
+                    match x >> 63 {
+                        0 => x as i64,
+                        _ => -((x & !(1 << 63)) as i64),
+                    }
+                };
+                let (add, copy) = (field(0) as usize, field(1) as usize);
+                seeks.push(field(2));
+                rest = &rest[24 + add + copy..];
+            }
+            seeks
+        };
+        let aehobak = seeks(&decoded);
+        assert!(aehobak.len() > 1);
+        assert_eq!(aehobak.last(), Some(&0));
+
+        // A trailing seek from bsdiff still applies
+        let mut bspatch = Vec::new();
+        bsdiff::diff(&old, &new, &mut bspatch).unwrap();
+        assert_ne!(seeks(&bspatch).last(), Some(&0));
+        encoded.clear();
+        encode(&bspatch, &mut encoded).unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+    }
+
+    #[test]
+    fn filtered_calls() {
+        // Calls to a common target, shifted by an insertion
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x510e527fade682d1);
+        let mut old = vec![0; 4096];
+        rng.fill_bytes(&mut old);
+        let calls = |code: &mut [u8], start: usize| {
+            for site in (start..start + 1600).step_by(40) {
+                let rel = 100 - (site as i32 + 5);
+                code[site] = 0xE8;
+                code[site + 1..site + 5].copy_from_slice(&rel.to_le_bytes());
+            }
+        };
+        calls(&mut old, 2400);
+        let mut new = old[..2048].to_vec();
+        new.extend([0x90; 16]);
+        new.extend(&old[2048..]);
+        calls(&mut new, 2416);
+        let (mut plain, mut filtered) = (Vec::new(), Vec::new());
+        diff(&old, &new, &mut plain).unwrap();
+        let options = DiffOptions::new().filter(Filter::X86);
+        options.diff(&old, &new, &mut filtered).unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &filtered, &mut result).unwrap();
+        assert_eq!(result, new);
+        assert!(filtered.len() < plain.len() / 2);
+
+        filtered[1] = u8::MAX;
+        let e = patch(&old, &filtered, &mut Vec::with_capacity(new.len())).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let index = Index::new(&old).unwrap();
+        assert!(options.diff_with_index(&index, &new, &mut plain).is_err());
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)] // Slow
+    fn windowed_diff_moved() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x3c6ef372fe94f82b);
+        let mut old = vec![0; 1 << 16];
+        rng.fill_bytes(&mut old);
+        let mut new = Vec::with_capacity(old.len());
+        for pair in old.chunks(1000).collect::<Vec<_>>().chunks(2) {
+            new.extend(pair.iter().rev().copied().flatten());
+            new.push(rng.next_u32() as u8);
+        }
+        let mut encoded = Vec::new();
+        DiffOptions::new()
+            .window(8192)
+            .diff(&old, &new, &mut encoded)
+            .unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        assert!(encoded.len() < new.len() / 64);
+    }
+
+    #[cfg(feature = "bzip2")]
+    #[test]
+    fn bsdiff_containers() {
+        use bzip2::{write::BzEncoder, Compression};
+        use std::io::Write;
+        let compress = |data: &[u8]| {
+            let mut stream = BzEncoder::new(Vec::new(), Compression::best());
+            stream.write_all(data).unwrap();
+            stream.finish().unwrap()
+        };
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x2c8e41b7d90f6a35);
+        let mut old = vec![0; 1 << 14];
+        rng.fill_bytes(&mut old);
+        let mut new = old.clone();
+        new.splice(4096..4096, [7; 300]);
+        new[9000] ^= 0x40;
+        let mut raw = Vec::new();
+        bsdiff::diff(&old, &new, &mut raw).unwrap();
+
+        // Containers assembled as bsdiff 4.3 and endsley/bsdiff write them
+        let (mut control, mut diff, mut extra) = (Vec::new(), Vec::new(), Vec::new());
+        let mut rest = raw.as_slice();
+        while !rest.is_empty() {
+            let parsed = crate::control::Bsdiff::try_from(&rest[..24]).unwrap();
+            let (add, copy) = (parsed.add as usize, parsed.copy as usize);
+            control.extend(&rest[..24]);
+            diff.extend(&rest[24..][..add]);
+            extra.extend(&rest[24 + add..][..copy]);
+            rest = &rest[24 + add + copy..];
+        }
+        let (control, diff, extra) = (compress(&control), compress(&diff), compress(&extra));
+        let mut bsdiff40 = b"BSDIFF40".to_vec();
+        bsdiff40.extend((control.len() as u64).to_le_bytes());
+        bsdiff40.extend((diff.len() as u64).to_le_bytes());
+        bsdiff40.extend((new.len() as u64).to_le_bytes());
+        bsdiff40.extend([control, diff, extra].concat());
+        let mut bsdiff43 = b"ENDSLEY/BSDIFF43".to_vec();
+        bsdiff43.extend((new.len() as u64).to_le_bytes());
+        bsdiff43.extend(compress(&raw));
+
+        let mut encoded = Vec::new();
+        encode(&raw, &mut encoded).unwrap();
+        for (bytes, container) in [
+            (bsdiff40, Container::Bsdiff40),
+            (bsdiff43, Container::Bsdiff43),
+        ] {
+            let mut transcoded = Vec::new();
+            encode_container(&bytes, &mut transcoded).unwrap();
+            assert_eq!(transcoded, encoded);
+            let mut decoded = Vec::new();
+            decode_container(&mut encoded.as_slice(), container, &mut decoded).unwrap();
+            assert_eq!(decoded, bytes);
+
+            let mut trailing = bytes.clone();
+            trailing.push(0);
+            assert!(encode_container(&trailing, &mut Vec::new()).is_err());
+            let truncated = &bytes[..bytes.len() - 1];
+            assert!(encode_container(truncated, &mut Vec::new()).is_err());
+        }
+        assert!(encode_container(b"BSDIFF41", &mut Vec::new()).is_err());
+    }
+
+    #[cfg(feature = "object")]
+    #[test]
+    fn object_diff() {
+        use object::write::{Object, Symbol, SymbolSection};
+        use object::{Architecture, BinaryFormat, Endianness, SectionKind};
+        use object::{SymbolFlags, SymbolKind, SymbolScope};
+
+        fn build(functions: &[(String, Vec<u8>)], data: &[u8]) -> Vec<u8> {
+            let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+            let text = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+            for (name, code) in functions {
+                let symbol = obj.add_symbol(Symbol {
+                    name: name.as_bytes().to_vec(),
+                    value: 0,
+                    size: 0,
+                    kind: SymbolKind::Text,
+                    scope: SymbolScope::Linkage,
+                    weak: false,
+                    section: SymbolSection::Undefined,
+                    flags: SymbolFlags::None,
+                });
+                obj.add_symbol_data(symbol, text, code, 16);
+            }
+            let section = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+            obj.append_section_data(section, data, 16);
+            obj.write().unwrap()
+        }
+
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x9b05688c2b3e6c1f);
+        let mut random = |len| {
+            let mut bytes = vec![0; len];
+            rng.fill_bytes(&mut bytes);
+            bytes
+        };
+        let functions: Vec<_> = (0..16).map(|i| (format!("f{i}"), random(512))).collect();
+        let data = random(4096);
+        let old = build(&functions, &data);
+        // Reorder, grow and patch functions, then grow data
+        let mut changed: Vec<_> = functions.into_iter().rev().collect();
+        for (i, (_, code)) in changed.iter_mut().enumerate() {
+            code[i * 7] ^= 0x55;
+        }
+        changed.insert(3, ("g".to_string(), random(300)));
+        let new = build(&changed, &[&data[..], &random(100)].concat());
+
+        let plan = layout::plan(&old, &new).unwrap();
+        assert!(plan.iter().filter(|(_, old)| old.is_some()).count() > 16);
+        assert!(layout::plan(&old[1..], &new).is_none());
+
+        let mut encoded = Vec::new();
+        DiffOptions::new()
+            .objects(true)
+            .diff(&old, &new, &mut encoded)
+            .unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        assert!(encoded.len() < new.len() / 8);
+    }
+
+    #[cfg(feature = "object")]
+    #[test]
+    fn relocated_pointers() {
+        // A linked ELF64 image with sections at the given addresses
+        fn build(sections: &[(&str, u64, u64, &[u8])]) -> Vec<u8> {
+            let mut names = b"\0.shstrtab\0".to_vec();
+            let mut image = vec![0; 64];
+            let mut headers = vec![0; 64];
+            for &(name, addr, flags, data) in sections {
+                headers.extend((names.len() as u32).to_le_bytes());
+                headers.extend(1u32.to_le_bytes()); // PROGBITS
+                headers.extend(flags.to_le_bytes());
+                headers.extend(addr.to_le_bytes());
+                headers.extend((image.len() as u64).to_le_bytes());
+                headers.extend((data.len() as u64).to_le_bytes());
+                headers.extend([0; 8]);
+                headers.extend([8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                names.extend(name.as_bytes());
+                names.push(0);
+                image.extend(data);
+            }
+            headers.extend(1u32.to_le_bytes());
+            headers.extend(3u32.to_le_bytes()); // STRTAB
+            headers.extend([0; 16]);
+            headers.extend((image.len() as u64).to_le_bytes());
+            headers.extend((names.len() as u64).to_le_bytes());
+            headers.extend([0; 24]);
+            image.extend(&names);
+            image.resize(image.len().next_multiple_of(8), 0);
+            let shoff = image.len() as u64;
+            image.extend(headers);
+            let shnum = sections.len() as u16 + 2;
+            image[..16].copy_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+            image[16..24].copy_from_slice(&[2, 0, 62, 0, 1, 0, 0, 0]);
+            image[40..48].copy_from_slice(&shoff.to_le_bytes());
+            image[52..54].copy_from_slice(&64u16.to_le_bytes());
+            image[58..60].copy_from_slice(&64u16.to_le_bytes());
+            image[60..62].copy_from_slice(&shnum.to_le_bytes());
+            image[62..64].copy_from_slice(&(shnum - 1).to_le_bytes());
+            image
+        }
+        fn pointers(base: u64, targets: &[u64]) -> Vec<u8> {
+            targets
+                .iter()
+                .flat_map(|t| (base + t).to_le_bytes())
+                .collect()
+        }
+
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x5be0cd19137e2179);
+        let mut random = |len| {
+            let mut bytes = vec![0; len];
+            rng.fill_bytes(&mut bytes);
+            bytes
+        };
+        let (text, rodata) = (random(1024), random(2048));
+        let targets: Vec<u64> = random(1024).chunks(2).map(|c| c[0] as u64 * 8).collect();
+        let old = build(&[
+            (".text", 0x1000, 6, &text),
+            (".rodata", 0x2000, 2, &rodata),
+            (".data", 0x3000, 3, &pointers(0x2000, &targets)),
+        ]);
+        // Code grows, moving every later section and the targets of pointers
+        let grown = [&text[..512], &random(256), &text[512..]].concat();
+        let new = build(&[
+            (".text", 0x1000, 6, &grown),
+            (".rodata", 0x2100, 2, &rodata),
+            (".data", 0x3100, 3, &pointers(0x2100, &targets)),
+        ]);
+
+        let mut plain = Vec::new();
+        diff(&old, &new, &mut plain).unwrap();
+        let mut encoded = Vec::new();
+        DiffOptions::new()
+            .objects(true)
+            .diff(&old, &new, &mut encoded)
+            .unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        let (plain, relocated) = (inspect(&plain).unwrap(), inspect(&encoded).unwrap());
+        assert_eq!(relocated.shifts, 2);
+        assert!(plain.deltas >= targets.len());
+        assert!(relocated.deltas < 16);
+        assert!(decode(&mut encoded.as_slice(), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn multi_reference() {
+        use std::io::ErrorKind::InvalidData;
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x1f83d9abfb41bd6b);
+        let mut olds = [vec![0; 3000], vec![0; 5000], vec![0; 2000]];
+        for old in &mut olds {
+            rng.fill_bytes(old);
+        }
+        let olds: Vec<&[u8]> = olds.iter().map(Vec::as_slice).collect();
+        let new = [
+            &olds[2][500..],
+            &olds[0][..1000],
+            b"glue",
+            &olds[1][100..4000],
+            // Spanning the boundary between references
+            &olds[0][2500..],
+            &olds[1][..500],
+        ]
+        .concat();
+        let mut encoded = Vec::new();
+        diff_multi(&olds, &new, &mut encoded).unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch_multi(&olds, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        // Mostly the length and hash of each reference
+        assert!(encoded.len() < 128);
+
+        let kind = |e: std::io::Error| e.kind();
+        let mut altered = olds[1].to_vec();
+        altered[4999] ^= 1;
+        for wrong in [
+            vec![olds[1], olds[0], olds[2]],
+            vec![olds[0], &altered, olds[2]],
+            vec![olds[0], olds[1]],
+        ] {
+            let result = patch_multi(&wrong, &encoded, &mut Vec::with_capacity(new.len()));
+            assert_eq!(result.map_err(kind), Err(InvalidData));
+        }
+        let result = patch(&olds.concat(), &encoded, &mut Vec::with_capacity(new.len()));
+        assert_eq!(result.map_err(kind), Err(InvalidData));
+        let mut plain = Vec::new();
+        diff(&olds.concat(), &new, &mut plain).unwrap();
+        let result = patch_multi(&olds, &plain, &mut Vec::with_capacity(new.len()));
+        assert_eq!(result.map_err(kind), Err(InvalidData));
+
+        // References are ordinary old content to bsdiff
+        let mut decoded = Vec::new();
+        decode(&mut encoded.as_slice(), &mut decoded).unwrap();
+        let mut bspatched = Vec::new();
+        bsdiff::patch(&olds.concat(), &mut decoded.as_slice(), &mut bspatched).unwrap();
+        assert_eq!(bspatched, new);
+    }
+
+    #[test]
+    fn self_copies() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0xbb67ae8584caa73b);
+        let mut old = vec![0; 8192];
+        rng.fill_bytes(&mut old);
+        let mut table = vec![0; 700];
+        rng.fill_bytes(&mut table);
+        let mut new = old[..4000].to_vec();
+        for _ in 0..6 {
+            new.extend(&table);
+            new.extend(&old[4000..4100]);
+        }
+        new.extend([0x90; 3000]);
+        new.extend(&old[4100..]);
+
+        let mut plain = Vec::new();
+        diff(&old, &new, &mut plain).unwrap();
+        let mut encoded = Vec::new();
+        DiffOptions::new()
+            .self_copy(true)
+            .diff(&old, &new, &mut encoded)
+            .unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        assert!(encoded.len() < table.len() + 200);
+        assert!(plain.len() > 6 * table.len());
+        let mut decoded = Vec::new();
+        let e = decode(&mut encoded.as_slice(), &mut decoded).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+
+        // Without repeats, the patch stays legacy
+        let mut unrepeated = Vec::new();
+        DiffOptions::new()
+            .self_copy(true)
+            .diff(&old, &old, &mut unrepeated)
+            .unwrap();
+        assert!(decode(&mut unrepeated.as_slice(), &mut decoded).is_ok());
+    }
+
+    #[test]
+    fn fills() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x3c6ef372a54ff53a);
+        let mut old = vec![0; 8192];
+        rng.fill_bytes(&mut old);
+        let mut new = old[..4000].to_vec();
+        new.extend([0xFF; 20000]);
+        new.extend(&old[4000..6000]);
+        new.extend([0x00; 10000]);
+        new.extend(&old[6000..]);
+
+        let mut plain = Vec::new();
+        diff(&old, &new, &mut plain).unwrap();
+        let mut encoded = Vec::new();
+        DiffOptions::new()
+            .fill(true)
+            .diff(&old, &new, &mut encoded)
+            .unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        assert!(encoded.len() < 100);
+        assert!(plain.len() > 30000);
+
+        // Converting to bsdiff expands fills back to literals
+        let (mut decoded, mut bspatched) = (Vec::new(), Vec::new());
+        decode(&mut encoded.as_slice(), &mut decoded).unwrap();
+        bsdiff::patch(&old, &mut decoded.as_slice(), &mut bspatched).unwrap();
+        assert_eq!(bspatched, new);
+    }
+
+    #[test]
+    fn cost_models() {
+        // Edits of varying density, as from recompiled code
+        let mut rng = Xoshiro256Plus::seed_from_u64(0xa54ff53a5f1d36f1);
+        let mut old = vec![0; 1 << 16];
+        rng.fill_bytes(&mut old);
+        let mut new: Vec<u8> = Vec::with_capacity(old.len() + 4096);
+        for chunk in old.chunks(512) {
+            new.extend(chunk);
+            let len = new.len();
+            let stride = [2, 3, 5, 16][rng.next_u32() as usize % 4];
+            for i in (len - chunk.len()..len).step_by(stride) {
+                new[i] = new[i].wrapping_add(rng.next_u32() as u8 % 3);
+            }
+            new.extend((0..rng.next_u32() % 24).map(|_| rng.next_u32() as u8));
+        }
+        let controls = |encoded: &[u8]| {
+            let mut decoded = Vec::new();
+            decode(&mut &encoded[..], &mut decoded).unwrap();
+            (decoded.len() - new.len()) / 24
+        };
+        let mut greedy = Vec::new();
+        diff(&old, &new, &mut greedy).unwrap();
+        let (mut size, mut speed) = (Vec::new(), Vec::new());
+        let options = DiffOptions::new();
+        options
+            .clone()
+            .cost(SizeCost)
+            .diff(&old, &new, &mut size)
+            .unwrap();
+        options
+            .cost(SpeedCost)
+            .diff(&old, &new, &mut speed)
+            .unwrap();
+        for encoded in [&size, &speed] {
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, encoded, &mut result).unwrap();
+            assert_eq!(result, new);
+        }
+        assert!(size.len() <= greedy.len());
+        assert!(controls(&speed) < controls(&greedy));
+    }
+
+    #[test]
+    fn reoptimize_archive() {
+        // Empty and adjacent controls, as a transcoder may leave them
+        let skeleton = LinkedList::from_iter((0..64).map(|i| match i % 4 {
+            0 => (0, 0, 0),
+            _ => (40 + i, (i % 3) * 2, 0),
+        }));
+        let (old, new) = gen_old_new(skeleton.clone(), 3, 0).unwrap();
+        let (bspatch, _, _) = gen_bspatch(skeleton, 3, 0);
+        let mut encoded = Vec::new();
+        encode(&bspatch, &mut encoded).unwrap();
+        let mut filtered = Vec::new();
+        let options = DiffOptions::new().filter(Filter::Arm64);
+        options.diff(&old, &new, &mut filtered).unwrap();
+
+        for (encoded, transcoded) in [(encoded, true), (filtered, false)] {
+            let mut reoptimized = Vec::new();
+            reoptimize(&old, &new, &encoded, &mut reoptimized).unwrap();
+            let mut result = Vec::with_capacity(new.len());
+            patch(&old, &reoptimized, &mut result).unwrap();
+            assert_eq!(result, new);
+            assert!(reoptimized.len() <= encoded.len());
+            assert!(reoptimized.len() < encoded.len() || !transcoded);
+
+            let e = reoptimize(&old, &old, &encoded, &mut Vec::new()).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+        use streamvbyte64::{Coder, Coder0124};
+        // A prefix declaring 4 Gi controls and 4 GiB of data, and nothing else
+        let mut hostile = [0u8; 17];
+        let (tag, data) = hostile.split_at_mut(1);
+        let len = 1 + Coder0124::new().encode(&[0, 0, u32::MAX, u32::MAX], tag, data);
+        let hostile = &hostile[..len];
+        let kind = |e: std::io::Error| e.kind();
+        let mut out = Vec::new();
+        let result = Limits::new().decode(&mut &hostile[..], &mut out);
+        assert_eq!(result.map_err(kind), Err(InvalidData));
+        let result = Limits::new().patch(&[], hostile, &mut out);
+        assert_eq!(result.map_err(kind), Err(InvalidData));
+        // Without limits, only the missing sections are reported
+        let result = decode(&mut &hostile[..], &mut out);
+        assert_eq!(result.map_err(kind), Err(UnexpectedEof));
+        assert_eq!(
+            patch(&[], hostile, &mut out).map_err(kind),
+            Err(UnexpectedEof)
+        );
+        let limits = Limits::new().controls(usize::MAX);
+        let result = limits.decode(&mut &hostile[..], &mut out);
+        assert_eq!(result.map_err(kind), Err(InvalidData));
+
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x5d1c6a9f3e2b7408);
+        let mut old = vec![0; 1 << 16];
+        rng.fill_bytes(&mut old);
+        let new = [&old[1 << 15..], &old[..1 << 15]].concat();
+        let mut encoded = Vec::new();
+        diff(&old, &new, &mut encoded).unwrap();
+        let apply = |limits: Limits| {
+            let mut patched = Vec::with_capacity(new.len());
+            limits.patch(&old, &encoded, &mut patched).map(|()| patched)
+        };
+        assert_eq!(apply(Limits::new()).unwrap(), new);
+        assert_eq!(apply(Limits::new().output(new.len())).unwrap(), new);
+        assert!(apply(Limits::new().output(new.len() - 1)).is_err());
+        assert!(apply(Limits::new().seek(1 << 14)).is_err());
+        assert!(apply(Limits::new().controls(1)).is_err());
+        // Plain patch applies no seek limit
+        let mut patched = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        let decode_with = |limits: Limits| limits.decode(&mut encoded.as_slice(), &mut Vec::new());
+        decode_with(Limits::new()).unwrap();
+        assert!(decode_with(Limits::new().output(new.len())).is_err());
+        assert!(decode_with(Limits::new().seek(1 << 14)).is_err());
+        assert!(decode_with(Limits::new().alloc(16)).is_err());
+        decode_with(Limits::unlimited()).unwrap();
+
+        let mut filtered = Vec::new();
+        let options = DiffOptions::new().filter(Filter::X86);
+        options.diff(&old, &new, &mut filtered).unwrap();
+        let mut patched = Vec::with_capacity(new.len());
+        let limits = Limits::new().alloc(old.len() - 1);
+        assert!(limits.patch(&old, &filtered, &mut patched).is_err());
+    }
+
+    /// Random old, with scattered changes, an insertion and an appended repeat in new.
+    fn edited(seed: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut rng = Xoshiro256Plus::seed_from_u64(seed);
+        let mut old = vec![0; 1 << 16];
+        rng.fill_bytes(&mut old);
+        let mut new = old.clone();
+        for i in (0..new.len()).step_by(1000) {
+            new[i] = new[i].wrapping_add(3);
+        }
+        new.splice(20000..20000, [0; 500]);
+        new.extend_from_within(100..1100);
+        (old, new)
+    }
+
+    #[test]
+    fn vcdiff_edited() {
+        let (old, new) = edited(0x91d3a6c04f7e2b58);
+        let options = DiffOptions::new().fill(true).self_copy(true);
+        let (mut encoded, mut vcdiff, mut imported) = (Vec::new(), Vec::new(), Vec::new());
+        options.diff(&old, &new, &mut encoded).unwrap();
+        export_vcdiff(&old, &encoded, &mut vcdiff).unwrap();
+        assert!(vcdiff.len() < 1000);
+        options.import_vcdiff(&old, &vcdiff, &mut imported).unwrap();
+        // Changed bytes between copies are rebuilt as deltas
+        assert!(imported.len() <= encoded.len());
+        let mut patched = Vec::with_capacity(new.len());
+        patch(&old, &imported, &mut patched).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn export_filtered() {
+        // Two copies of a block of calls to one target, against unrelated old
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x1f83d9abfb41bd6b);
+        let mut old = vec![0; 4096];
+        rng.fill_bytes(&mut old);
+        let mut new = vec![0; 2048];
+        rng.fill_bytes(&mut new);
+        new.extend_from_within(..);
+        for start in [0, 2048] {
+            for site in (start..start + 2048).step_by(32) {
+                let rel = 8192 - (site as i32 + 5);
+                new[site] = 0xE8;
+                new[site + 1..site + 5].copy_from_slice(&rel.to_le_bytes());
+            }
+        }
+        let options = DiffOptions::new()
+            .filter(Filter::X86)
+            .fill(true)
+            .self_copy(true);
+        let mut encoded = Vec::new();
+        options.diff(&old, &new, &mut encoded).unwrap();
+        let (mut vcdiff, mut git) = (Vec::new(), Vec::new());
+        export_vcdiff(&old, &encoded, &mut vcdiff).unwrap();
+        export_git_delta(&old, &encoded, &mut git).unwrap();
+        let (mut imported, mut patched) = (Vec::new(), Vec::with_capacity(new.len()));
+        import_vcdiff(&old, &vcdiff, &mut imported).unwrap();
+        patch(&old, &imported, &mut patched).unwrap();
+        assert_eq!(patched, new);
+        let (mut imported, mut patched) = (Vec::new(), Vec::with_capacity(new.len()));
+        import_git_delta(&old, &git, &mut imported).unwrap();
+        patch(&old, &imported, &mut patched).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn export_hostile() {
+        use streamvbyte64::{Coder, Coder0124};
+        // Four copies of 4 GiB, declared in a few bytes
+        let coder = Coder0124::new();
+        let mut sections = vec![u32::MAX; 4];
+        sections.extend([0; 8]);
+        let (mut tags, mut data) = ([0u8; 3], [0u8; 48]);
+        let data_len = coder.encode(&sections, &mut tags, &mut data);
+        let (mut tag, mut prefix) = ([0u8; 1], [0u8; 16]);
+        let prefix_len = coder.encode(&[0, 0, 4, data_len as u32], &mut tag, &mut prefix);
+        let hostile = [&tag[..], &prefix[..prefix_len], &tags, &data[..data_len]].concat();
+        for result in [
+            export_vcdiff(&[], &hostile, &mut Vec::new()),
+            export_git_delta(&[], &hostile, &mut Vec::new()),
+        ] {
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Runs git
+    fn git_delta_interop() {
+        use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+        use std::io::{Read, Write};
+        use std::process::Command;
+        const BASE85: &[u8] =
+            b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+        let dir = std::env::temp_dir().join(format!("aehobak-git-{}", std::process::id()));
+        let git = |args: &[&str]| {
+            let output = Command::new("git").arg("-C").arg(&dir).args(args).output();
+            output.ok().filter(|o| o.status.success()).map(|o| o.stdout)
+        };
+        std::fs::create_dir_all(&dir).unwrap();
+        git(&["init", "-q"]).expect("git is required");
+
+        let (old, new) = edited(0x3c6ef372fe94f82b);
+
+        // Import the delta of a binary diff from git
+        std::fs::write(dir.join("old"), &old).unwrap();
+        std::fs::write(dir.join("new"), &new).unwrap();
+        git(&["add", "old"]).unwrap();
+        std::fs::write(dir.join("old"), &new).unwrap();
+        let diff = git(&["diff", "--binary", "--full-index", "old"]).unwrap();
+        let diff = String::from_utf8(diff).unwrap();
+        let hunk = diff.split("GIT binary patch\ndelta ").nth(1).unwrap();
+        let mut compressed = Vec::new();
+        for line in hunk.lines().skip(1).take_while(|line| !line.is_empty()) {
+            let (len, chars) = line.as_bytes().split_first().unwrap();
+            let len = match len {
+                b'A'..=b'Z' => len - b'A' + 1,
+                _ => len - b'a' + 27,
+            };
+            for group in chars.chunks(5) {
+                let value = group.iter().fold(0u32, |value, c| {
+                    let digit = BASE85.iter().position(|b| b == c).unwrap();
+                    value * 85 + digit as u32
+                });
+                compressed.extend(value.to_be_bytes());
+            }
+            compressed.truncate(compressed.len() - (chars.len() / 5 * 4 - len as usize));
+        }
+        let mut delta = Vec::new();
+        let mut decoder = ZlibDecoder::new(compressed.as_slice());
+        decoder.read_to_end(&mut delta).unwrap();
+        let (mut imported, mut patched) = (Vec::new(), Vec::with_capacity(new.len()));
+        import_git_delta(&old, &delta, &mut imported).unwrap();
+        patch(&old, &imported, &mut patched).unwrap();
+        assert_eq!(patched, new);
+
+        // Export a delta that git applies
+        let options = DiffOptions::new().fill(true).self_copy(true);
+        let (mut encoded, mut delta) = (Vec::new(), Vec::new());
+        options.diff(&old, &new, &mut encoded).unwrap();
+        export_git_delta(&old, &encoded, &mut delta).unwrap();
+        assert!(delta.len() < 2000);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&delta).unwrap();
+        let compressed = encoder.finish().unwrap();
+        std::fs::write(dir.join("old"), &old).unwrap();
+        let hash = |name| String::from_utf8(git(&["hash-object", name]).unwrap()).unwrap();
+        let mut diff = format!(
+            "diff --git a/old b/old\nindex {}..{} 100644\nGIT binary patch\ndelta {}\n",
+            hash("old").trim(),
+            hash("new").trim(),
+            delta.len()
+        );
+        for line in compressed.chunks(52) {
+            diff.push(match line.len() {
+                len @ 1..=26 => (b'A' + len as u8 - 1) as char,
+                len => (b'a' + len as u8 - 27) as char,
+            });
+            for group in line.chunks(4) {
+                let mut bytes = [0; 4];
+                bytes[..group.len()].copy_from_slice(group);
+                let mut value = u32::from_be_bytes(bytes);
+                let mut chars = [0; 5];
+                for c in chars.iter_mut().rev() {
+                    *c = BASE85[(value % 85) as usize];
+                    value /= 85;
+                }
+                diff.push_str(std::str::from_utf8(&chars).unwrap());
+            }
+            diff.push('\n');
+        }
+        diff.push('\n');
+        std::fs::write(dir.join("patch"), diff).unwrap();
+        git(&["apply", "patch"]).unwrap();
+        assert_eq!(std::fs::read(dir.join("old")).unwrap(), new);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Uses the file system
+    fn tree_round_trip() {
+        use std::fs;
+        use std::path::Path;
+        let dir = std::env::temp_dir().join(format!("aehobak-tree-{}", std::process::id()));
+        let (old, new, out) = (dir.join("old"), dir.join("new"), dir.join("out"));
+        let mut rng = Xoshiro256Plus::seed_from_u64(0xa54ff53a5f1d36f1);
+        let mut blob = vec![0; 1 << 16];
+        rng.fill_bytes(&mut blob);
+        let mut edited = blob.clone();
+        for i in (0..edited.len()).step_by(1000) {
+            edited[i] ^= 0x55;
+        }
+        edited.splice(30000..30000, [7; 100]);
+
+        for path in ["lib/sub", "empty", "docs"] {
+            fs::create_dir_all(old.join(path)).unwrap();
+        }
+        fs::write(old.join("lib/app.bin"), &blob).unwrap();
+        fs::write(old.join("docs/readme"), b"old readme").unwrap();
+        fs::write(old.join("lib/sub/data"), &blob[..20000]).unwrap();
+        fs::write(old.join("gone"), b"removed").unwrap();
+
+        for path in ["bin", "lib/sub", "empty", "docs"] {
+            fs::create_dir_all(new.join(path)).unwrap();
+        }
+        // Moved and edited, moved unchanged, changed in place, and added
+        fs::write(new.join("bin/app.bin"), &edited).unwrap();
+        fs::write(new.join("bin/data"), &blob[..20000]).unwrap();
+        fs::write(new.join("docs/readme"), b"new readme").unwrap();
+        fs::write(new.join("added"), b"added").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let exec = fs::Permissions::from_mode(0o755);
+            fs::set_permissions(new.join("bin/app.bin"), exec).unwrap();
+            std::os::unix::fs::symlink("../bin/app.bin", new.join("lib/app")).unwrap();
+        }
+
+        let mut encoded = Vec::new();
+        diff_tree(&old, &new, &mut encoded).unwrap();
+        // The moved files are diffed against their old paths, not stored
+        assert!(encoded.len() < 2000);
+        apply_tree(&old, &encoded, &out).unwrap();
+
+        fn listing(root: &Path, dir: &Path, entries: &mut Vec<(String, String, Vec<u8>)>) {
+            let mut children: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+            children.sort_by_key(|e| e.file_name());
+            for child in children {
+                let path = child.path();
+                let name = path.strip_prefix(root).unwrap().display().to_string();
+                let meta = fs::symlink_metadata(&path).unwrap();
+                let perms = format!("{:?}", meta.permissions());
+                if meta.is_symlink() {
+                    let target = fs::read_link(&path).unwrap();
+                    entries.push((name, perms, target.display().to_string().into_bytes()));
+                } else if meta.is_dir() {
+                    entries.push((name, perms, Vec::new()));
+                    listing(root, &path, entries);
+                } else {
+                    entries.push((name, perms, fs::read(&path).unwrap()));
+                }
+            }
+        }
+        let (mut expected, mut actual) = (Vec::new(), Vec::new());
+        listing(&new, &new, &mut expected);
+        listing(&out, &out, &mut actual);
+        assert_eq!(actual, expected);
+
+        // Paths that leave the tree are refused before anything is written
+        let mut escape = b"AEHOTREE\x01\x00\x00\x00\x00\x05\x00\x00\x00../up".to_vec();
+        escape.extend(0o755u32.to_le_bytes());
+        let err = apply_tree(&old, &escape, &dir.join("escape")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!dir.join("escape").exists());
+        // Files rebuilt from a different old are refused
+        fs::write(old.join("lib/sub/data"), b"changed").unwrap();
+        assert!(apply_tree(&old, &encoded, &dir.join("stale")).is_err());
+        assert!(!dir.join("stale").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Uses the file system
+    fn atomic_update() {
+        use std::fs;
+        use xxhash_rust::xxh3::xxh3_128;
+        let root = std::env::temp_dir().join(format!("aehobak-update-{}", std::process::id()));
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x9b05688c2b3e6c1f);
+        let names = ["a", "dir/b", "dir/c"];
+        let (mut olds, mut news, mut patches) = (Vec::new(), Vec::new(), Vec::new());
+        for _ in names {
+            let mut old = vec![0; 10000];
+            rng.fill_bytes(&mut old);
+            let mut new = old.clone();
+            new[5000..5100].fill(9);
+            let mut encoded = Vec::new();
+            diff(&old, &new, &mut encoded).unwrap();
+            olds.push(old);
+            news.push(new);
+            patches.push(encoded);
+        }
+        let reset = || {
+            fs::create_dir_all(root.join("dir")).unwrap();
+            for (name, old) in names.iter().zip(&olds) {
+                fs::write(root.join(name), old).unwrap();
+            }
+        };
+        let contents = || {
+            names
+                .map(|name| fs::read(root.join(name)).unwrap())
+                .to_vec()
+        };
+        let update = |hashes: &[u128]| {
+            let mut update = Update::new(&root);
+            for ((name, patch), &hash) in names.iter().zip(&patches).zip(hashes) {
+                update = update.file(name, patch, hash);
+            }
+            update
+        };
+        let hashes: Vec<_> = news.iter().map(|new| xxh3_128(new)).collect();
+        let staging = root.join(".aehobak-update");
+
+        reset();
+        update(&hashes).apply().unwrap();
+        assert_eq!(contents(), news);
+        assert!(!staging.exists());
+
+        // A mismatched hash leaves every file unchanged
+        reset();
+        let mut wrong = hashes.clone();
+        wrong[2] ^= 1;
+        assert!(update(&wrong).apply().is_err());
+        assert_eq!(contents(), olds);
+        assert!(!staging.exists());
+
+        // An update interrupted midway is rolled back or completed on the next start
+        for complete in [false, true] {
+            reset();
+            let entries = update(&hashes).prepare().unwrap();
+            update::swap(&root, &entries[..1]).unwrap();
+            assert_eq!(contents()[0], news[0]);
+            let err = update(&hashes).apply().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+            match complete {
+                false => assert!(Update::roll_back(&root).unwrap()),
+                true => assert!(Update::complete(&root).unwrap()),
+            }
+            assert_eq!(&contents(), if complete { &news } else { &olds });
+            assert!(!staging.exists());
+            assert!(!Update::complete(&root).unwrap());
+        }
+
+        // A roll back interrupted midway is finished by complete
+        reset();
+        let entries = update(&hashes).prepare().unwrap();
+        update::swap(&root, &entries).unwrap();
+        update::mark_roll_back(&root).unwrap();
+        fs::rename(staging.join("2.old"), root.join(names[2])).unwrap();
+        assert!(Update::complete(&root).unwrap());
+        assert_eq!(contents(), olds);
+        assert!(!staging.exists());
+
+        // A corrupt staged file is refused, and may still be rolled back
+        reset();
+        let entries = update(&hashes).prepare().unwrap();
+        update::swap(&root, &entries[..1]).unwrap();
+        fs::write(staging.join("1.new"), &olds[1]).unwrap();
+        let err = Update::complete(&root).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(Update::roll_back(&root).unwrap());
+        assert_eq!(contents(), olds);
+
+        // Each path may be updated once
+        let twice = update(&hashes).file(names[0], &patches[0], hashes[0]);
+        let err = twice.apply().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(contents(), olds);
+        assert!(!staging.exists());
+
+        // An update interrupted before commit is discarded
+        reset();
+        fs::create_dir(&staging).unwrap();
+        fs::write(staging.join("0.new"), &news[0]).unwrap();
+        assert!(!Update::complete(&root).unwrap());
+        assert_eq!(contents(), olds);
+        assert!(!staging.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn tar_pairing() {
+        let blob = vec![7; 1000];
+        let old = gen_tar(&[
+            ("a.bin".into(), 1, &blob),
+            ("b.bin".into(), 1, &blob),
+            ("empty1".into(), 1, &[]),
+            ("empty2".into(), 1, &[]),
+        ]);
+        let new = gen_tar(&[("b.bin".into(), 1, &blob), ("empty2".into(), 1, &[])]);
+        // Duplicates pair by path, and empty members never by content
+        let plan = tar::plan(&old, &new).unwrap();
+        assert_eq!(
+            plan,
+            [(0..1536, Some(1536..3072)), (1536..3072, Some(3584..5120))]
+        );
+    }
+
+    #[test]
+    fn tar_members() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x1f83d9abfb41bd6b);
+        let mut blobs = vec![vec![0; 40000], vec![0; 30000], vec![0; 20000]];
+        for blob in &mut blobs {
+            rng.fill_bytes(blob);
+        }
+        let long = format!("{}/payload.bin", "nested".repeat(20));
+        let old = gen_tar(&[
+            ("a.bin".into(), 1, &blobs[0]),
+            ("b.bin".into(), 2, &blobs[1]),
+            (long.clone(), 3, &blobs[2]),
+        ]);
+        // Reordered, edited with new sizes and times, renamed, and added
+        let mut edited = blobs[0].clone();
+        edited.splice(1000..1000, [1; 333]);
+        for i in (0..edited.len()).step_by(997) {
+            edited[i] ^= 0x40;
+        }
+        let new = gen_tar(&[
+            ("added".into(), 9, b"new member"),
+            (long, 8, &blobs[2]),
+            ("renamed.bin".into(), 7, &blobs[1]),
+            ("a.bin".into(), 6, &edited),
+        ]);
+
+        let (mut plain, mut aware) = (Vec::new(), Vec::new());
+        let options = DiffOptions::new().fill(true);
+        options.diff(&old, &new, &mut plain).unwrap();
+        options
+            .clone()
+            .tar(true)
+            .diff(&old, &new, &mut aware)
+            .unwrap();
+        for encoded in [&plain, &aware] {
+            let mut patched = Vec::with_capacity(new.len());
+            patch(&old, encoded, &mut patched).unwrap();
+            assert_eq!(patched, new);
+        }
+        assert!(aware.len() <= plain.len());
+
+        // Anything else is diffed as usual
+        let mut corrupt = new.clone();
+        corrupt[148] ^= 1;
+        let (mut plain, mut aware) = (Vec::new(), Vec::new());
+        options.diff(&old, &corrupt, &mut plain).unwrap();
+        options
+            .clone()
+            .tar(true)
+            .diff(&old, &corrupt, &mut aware)
+            .unwrap();
+        assert_eq!(aware, plain);
+    }
+
+    #[test]
+    fn tar_reordered() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x5be0cd19137e2179);
+        let mut files: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| {
+                let mut text = Vec::new();
+                for line in 0..(20 + rng.next_u32() % 200) {
+                    let value = rng.next_u32() % 16;
+                    let line = format!("// module {i} line {line}: shared value {value}\n");
+                    text.extend(line.as_bytes());
+                }
+                (format!("src/file{i:03}.rs"), text)
+            })
+            .collect();
+        let tar = |files: &[(String, Vec<u8>)], mtime| {
+            let members: Vec<_> = (files.iter())
+                .map(|(name, data)| (name.clone(), mtime, &data[..]))
+                .collect();
+            gen_tar(&members)
+        };
+        let old = tar(&files, 1);
+        for (_, data) in files.iter_mut().step_by(7) {
+            let at = data.len() / 2;
+            data.splice(at..at, *b"// inserted line\n");
+        }
+        files.insert(50, ("src/new.rs".into(), b"fn new() {}\n".to_vec()));
+        files.reverse();
+        let new = tar(&files, 2);
+
+        let (mut plain, mut aware) = (Vec::new(), Vec::new());
+        diff(&old, &new, &mut plain).unwrap();
+        DiffOptions::new()
+            .tar(true)
+            .diff(&old, &new, &mut aware)
+            .unwrap();
+        let mut patched = Vec::with_capacity(new.len());
+        patch(&old, &aware, &mut patched).unwrap();
+        assert_eq!(patched, new);
+        // Members stay aligned with their old counterparts
+        assert!(aware.len() < plain.len());
+    }
+
+    #[test]
+    fn hex_report() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x5be0cd19137e2179);
+        let mut old = vec![0; 8192];
+        rng.fill_bytes(&mut old);
+        let mut new = old[4096..].to_vec();
+        new[100] ^= 0x40;
+        new[103] ^= 0x01;
+        new.extend(b"<inserted & escaped>");
+        new.extend(&old[..4096]);
+        let mut encoded = Vec::new();
+        diff(&old, &new, &mut encoded).unwrap();
+
+        let mut text = Vec::new();
+        report(&old, &encoded, ReportFormat::Text, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("changed  new 0x00000064  old 0x00001064  4 bytes"));
+        assert!(text.contains("inserted new 0x00001000  20 bytes"));
+        assert!(text.contains("moved    new 0x00001014  old 0x00000000  4096 bytes"));
+        let (o, n) = (old[4196], new[100]);
+        assert!(text.contains(&format!("{o:02X}")) && text.contains(&format!("{n:02X}")));
+
+        let mut html = Vec::new();
+        report(&old, &encoded, ReportFormat::Html, &mut html).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains(&format!("<mark>{n:02x}</mark>")));
+        assert!(html.starts_with("<!DOCTYPE html>") && html.ends_with("</html>\n"));
+
+        // Regions come from the controls, so old is checked only where read
+        let result = report(&old[..4096], &encoded, ReportFormat::Text, &mut Vec::new());
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        let mut filtered = Vec::new();
+        let options = DiffOptions::new().filter(Filter::X86);
+        options.diff(&old, &new, &mut filtered).unwrap();
+        let mut text = Vec::new();
+        report(&old, &filtered, ReportFormat::Text, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("inserted new 0x00001000  20 bytes"));
+    }
+
+    #[test]
+    fn wide_index() {
+        let (old, new) = gen_old_new(
+            LinkedList::from_iter((0..16).map(|i| (60 + i, 5, -30))),
+            4,
+            1,
+        )
+        .unwrap();
+        let narrow = Index::with_sorter(&old, &NaiveSort).unwrap();
+        let wide = index::Index::with_width(&old, &NaiveSort, true).unwrap();
+        let mut serialized = Vec::new();
+        wide.write_to(&mut serialized).unwrap();
+        let reloaded = Index::from_bytes(&old, &serialized).unwrap();
+        let mut outputs = Vec::new();
+        for index in [narrow, wide, reloaded] {
+            let mut encoded = Vec::new();
+            diff_with_index(&index, &new, &mut encoded).unwrap();
+            outputs.push(encoded);
+        }
+        assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+        assert_eq!(
+            DivSufSort.sort64(&old).unwrap(),
+            NaiveSort.sort64(&old).unwrap()
+        );
+        #[cfg(feature = "libsais")]
+        assert_eq!(
+            Libsais::new().sort64(&old).unwrap(),
+            NaiveSort.sort64(&old).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // FFI
+    fn sorter_agreement() {
+        let (old, new) = gen_old_new(
+            LinkedList::from_iter((0..32).map(|i| (90 + i, 7, -40))),
+            3,
+            0,
+        )
+        .unwrap();
+        let mut old = old;
+        old.extend_from_within(..old.len() / 2);
+        let sorters: Vec<Box<dyn Fn(DiffOptions) -> DiffOptions>> = vec![
+            Box::new(|o| o.sorter(DivSufSort)),
+            Box::new(|o| o.sorter(NaiveSort)),
+            #[cfg(feature = "libsais")]
+            Box::new(|o| o.sorter(Libsais::new())),
+            #[cfg(feature = "openmp")]
+            Box::new(|o| o.sorter(Libsais::new().threads(2))),
+        ];
+        let mut outputs = Vec::new();
+        for with_sorter in sorters {
+            let mut encoded = Vec::new();
+            with_sorter(DiffOptions::new())
+                .diff(&old, &new, &mut encoded)
+                .unwrap();
+            outputs.push(encoded);
+        }
+        assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // FFI
+    fn wide_default() {
+        // A cutoff low enough to reach 64-bit offsets, on the release path
+        const NARROW: usize = 1 << 16;
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x1f83d9abfb41bd6b);
+        let mut old = vec![0; NARROW + 4096];
+        rng.fill_bytes(&mut old[..4096]);
+        // Repeats of a random block, with a few changes
+        for i in 4096..old.len() {
+            old[i] = old[i % 4096] ^ u8::from(i % 999 == 0);
+        }
+        let mut new = old[1000..].to_vec();
+        new[5000..5100].fill(7);
+        let wide = index::Index::with_limit(&old, &DivSufSort, NARROW).unwrap();
+        assert!(matches!(wide.suffixes(), index::Suffixes::Wide(_)));
+        let narrow = index::Index::with_limit(&old, &DivSufSort, old.len()).unwrap();
+        assert!(matches!(narrow.suffixes(), index::Suffixes::Narrow(_)));
+        let mut outputs = Vec::new();
+        for index in [wide, narrow] {
+            let mut encoded = Vec::new();
+            diff_with_index(&index, &new, &mut encoded).unwrap();
+            outputs.push(encoded);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        let mut encoded = Vec::new();
+        let options = DiffOptions::new().narrow_max(NARROW);
+        options.diff(&old, &new, &mut encoded).unwrap();
+        assert_eq!(encoded, outputs[0]);
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+
+        // Only inputs beyond i32::MAX bytes are wide by default
+        assert_eq!(index::NARROW_MAX, i32::MAX as usize);
+        let index = Index::new(&old).unwrap();
+        assert!(matches!(index.suffixes(), index::Suffixes::Narrow(_)));
+    }
+
+    #[test]
+    #[ignore] // Needs over 20 GiB of memory to sort 2 GiB of old
     fn direct_diff_huge() {
-        let mut old = Vec::with_capacity(i32::MAX as usize + 1);
-        old.resize(old.capacity(), 0);
-        let mut patch = Vec::new();
-        assert!(diff(&old, &old, &mut patch.as_mut_slice()).is_err());
+        let mut rng = Xoshiro256Plus::seed_from_u64(0x6a09e667f3bcc908);
+        let mut old = vec![0; i32::MAX as usize + 4096];
+        rng.fill_bytes(&mut old);
+        let mut new = old[old.len() - (1 << 20)..].to_vec();
+        new[1000] ^= 1;
+        let mut encoded = Vec::new();
+        diff(&old, &new, &mut encoded).unwrap();
+        let mut result = Vec::with_capacity(new.len());
+        patch(&old, &encoded, &mut result).unwrap();
+        assert_eq!(result, new);
+        // New is found at the end of old, beyond 32-bit offsets
+        assert!(inspect(&encoded).unwrap().old_len > i32::MAX as usize);
     }
 
     #[test]
@@ -210,6 +1809,38 @@ mod tests {
         assert!(result == new)
     }
 
+    /// A ustar archive of regular files, with GNU long names where needed.
+    fn gen_tar(members: &[(String, u64, &[u8])]) -> Vec<u8> {
+        fn header(name: &[u8], size: usize, mtime: u64, typeflag: u8) -> [u8; 512] {
+            let mut header = [0; 512];
+            header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+            header[100..108].copy_from_slice(b"0000644\0");
+            header[108..124].copy_from_slice(b"0001750\x000001750\x00");
+            header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+            header[136..148].copy_from_slice(format!("{mtime:011o}\0").as_bytes());
+            header[148..156].fill(b' ');
+            header[156] = typeflag;
+            header[257..265].copy_from_slice(b"ustar\x0000");
+            let sum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+            header[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+            header
+        }
+        let mut tar = Vec::new();
+        for (name, mtime, data) in members {
+            if name.len() >= 100 {
+                let long = [name.as_bytes(), b"\0"].concat();
+                tar.extend(header(b"././@LongLink", long.len(), 0, b'L'));
+                tar.extend(&long);
+                tar.resize(tar.len().next_multiple_of(512), 0);
+            }
+            tar.extend(header(name.as_bytes(), data.len(), *mtime, b'0'));
+            tar.extend(*data);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        }
+        tar.resize(tar.len() + 1024, 0);
+        tar
+    }
+
     fn gen_old_new(
         skeleton: LinkedList<(u8, u8, i8)>,
         period: u8,
@@ -245,7 +1876,7 @@ mod tests {
                 (&Aehobak::try_from([add, copy, seek].as_slice()).unwrap()).into();
             control.encode(&mut bspatch);
             for _ in 0..add {
-                bspatch.push((diffs % (1 + period as usize) == 0) as u8);
+                bspatch.push(diffs.is_multiple_of(1 + period as usize) as u8);
                 diffs += 1;
             }
             cursor += add as usize;
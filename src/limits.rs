@@ -0,0 +1,152 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::decode::Decoder;
+use std::io;
+use std::io::ErrorKind::InvalidData;
+use std::io::Read;
+
+/// Bounds on the resources a patch may demand when decoded or applied.
+///
+/// Every length in a patch comes from the patch itself, so a hostile patch
+/// of a few bytes can declare gigabytes of output. Limits apply only through
+/// `Limits::patch`, `Limits::decode` and `Decoder::with_limits`, while plain
+/// `patch` is bounded by the capacity of new alone. The defaults are meant
+/// for untrusted input, and each bound may be raised for larger files.
+///
+/// ```
+/// let old = vec![1, 2, 3, 4, 5];
+/// let new = vec![1, 2, 4, 6];
+/// let mut encoded = Vec::new();
+/// aehobak::diff(&old, &new, &mut encoded).unwrap();
+///
+/// let limits = aehobak::Limits::new().output(4);
+/// let mut patched = Vec::with_capacity(new.len());
+/// limits.patch(&old, &encoded, &mut patched).unwrap();
+/// assert_eq!(patched, new);
+///
+/// let limits = aehobak::Limits::new().output(3);
+/// assert!(limits.patch(&old, &encoded, &mut Vec::with_capacity(4)).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub(crate) output: usize,
+    pub(crate) alloc: usize,
+    pub(crate) controls: usize,
+    pub(crate) seek: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            output: 1 << 30,
+            alloc: 1 << 28,
+            controls: 1 << 24,
+            seek: 1 << 30,
+        }
+    }
+}
+
+impl Limits {
+    /// Create limits with defaults for untrusted input.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create limits that bound nothing beyond the format itself.
+    pub fn unlimited() -> Self {
+        Self {
+            output: usize::MAX,
+            alloc: usize::MAX,
+            controls: usize::MAX,
+            seek: u64::MAX,
+        }
+    }
+
+    /// Limit the bytes produced, being the new file for `patch` or the bsdiff
+    /// patch for `decode`. Defaults to 1 GiB.
+    pub fn output(mut self, bytes: usize) -> Self {
+        self.output = bytes;
+        self
+    }
+
+    /// Limit the bytes allocated to hold the sections of a patch, or a
+    /// filtered copy of old. Defaults to 256 MiB.
+    pub fn alloc(mut self, bytes: usize) -> Self {
+        self.alloc = bytes;
+        self
+    }
+
+    /// Limit the number of controls declared by a patch. Defaults to 16 Mi.
+    pub fn controls(mut self, controls: usize) -> Self {
+        self.controls = controls;
+        self
+    }
+
+    /// Limit the distance of any seek in old. Defaults to 1 GiB.
+    pub fn seek(mut self, bytes: u64) -> Self {
+        self.seek = bytes;
+        self
+    }
+
+    /// Decode a compact representation of bsdiff output within these limits.
+    #[allow(clippy::ptr_arg)]
+    pub fn decode<T: Read>(&self, reader: &mut T, patch: &mut Vec<u8>) -> io::Result<()> {
+        Decoder::with_limits(reader, *self).read_to_end(patch)?;
+        Ok(())
+    }
+
+    /// Directly apply a compact representation of bsdiff output within these limits.
+    /// Attempts to fill `new` beyond its capacity will result in `Err`.
+    pub fn patch(&self, old: &[u8], patch: &[u8], new: &mut Vec<u8>) -> io::Result<()> {
+        crate::patch::patch_internal(old, patch, new, self)
+    }
+
+    pub(crate) fn check_controls(&self, controls: usize) -> io::Result<()> {
+        match controls <= self.controls {
+            true => Ok(()),
+            false => Err(exceeded("controls")),
+        }
+    }
+
+    pub(crate) fn check_alloc(&self, bytes: Option<usize>) -> io::Result<()> {
+        match bytes.is_some_and(|bytes| bytes <= self.alloc) {
+            true => Ok(()),
+            false => Err(exceeded("allocation")),
+        }
+    }
+
+    pub(crate) fn check_seek(&self, seek: i64) -> io::Result<()> {
+        match seek.unsigned_abs() <= self.seek {
+            true => Ok(()),
+            false => Err(exceeded("seek")),
+        }
+    }
+}
+
+/// Exceeding a limit is reported as `InvalidData`, naming the limit.
+pub(crate) fn exceeded(limit: &str) -> io::Error {
+    io::Error::new(InvalidData, format!("patch exceeds {limit} limit"))
+}
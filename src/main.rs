@@ -0,0 +1,298 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use aehobak::{Decoder, DiffOptions, Encoder, Filter, Limits, SizeCost, SpeedCost};
+use anyhow::{ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Compact binary patches in the manner of bsdiff.
+///
+/// A path of `-` reads standard input or writes standard output.
+#[derive(Parser)]
+#[command(name = "aehobak", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a patch from OLD to NEW.
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        #[arg(default_value = "-")]
+        patch: PathBuf,
+        /// Encode runs of one byte as fills.
+        #[arg(long)]
+        fill: bool,
+        /// Encode repeats of earlier output as self-copies.
+        #[arg(long)]
+        self_copy: bool,
+        /// Pair the members of tar archives.
+        #[arg(long)]
+        tar: bool,
+        /// Filter branch targets of executable code.
+        #[arg(long, value_enum)]
+        filter: Option<FilterArg>,
+        /// Move control boundaries to reduce their cost.
+        #[arg(long, value_enum)]
+        cost: Option<CostArg>,
+    },
+    /// Apply PATCH to OLD, writing NEW.
+    Patch {
+        old: PathBuf,
+        patch: PathBuf,
+        #[arg(default_value = "-")]
+        new: PathBuf,
+        /// Refuse patches that produce more than this many bytes.
+        #[arg(long, default_value_t = MAX_OUTPUT)]
+        max_output: usize,
+    },
+    /// Transcode a bsdiff patch to an aehobak patch.
+    Encode {
+        #[arg(default_value = "-")]
+        bsdiff: PathBuf,
+        #[arg(default_value = "-")]
+        patch: PathBuf,
+    },
+    /// Transcode an aehobak patch to a bsdiff patch.
+    Decode {
+        #[arg(default_value = "-")]
+        patch: PathBuf,
+        #[arg(default_value = "-")]
+        bsdiff: PathBuf,
+        /// Refuse patches that decode to more than this many bytes.
+        #[arg(long, default_value_t = MAX_OUTPUT)]
+        max_output: usize,
+    },
+    /// Summarize a patch, and validate it against OLD if given.
+    Inspect {
+        #[arg(default_value = "-")]
+        patch: PathBuf,
+        #[arg(long)]
+        old: Option<PathBuf>,
+        /// Refuse to validate patches that produce more than this many bytes.
+        #[arg(long, default_value_t = MAX_OUTPUT)]
+        max_output: usize,
+    },
+    /// Compare the size of a patch from OLD to NEW with bsdiff and LZ4.
+    Compare { old: PathBuf, new: PathBuf },
+}
+
+/// The default bound on the output of a patch, as for `Limits`.
+const MAX_OUTPUT: usize = 1 << 30;
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FilterArg {
+    X86,
+    Arm64,
+    Riscv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CostArg {
+    Size,
+    Speed,
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse().command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("aehobak: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Diff {
+            old,
+            new,
+            patch,
+            fill,
+            self_copy,
+            tar,
+            filter,
+            cost,
+        } => {
+            let mut options = DiffOptions::new().fill(fill).self_copy(self_copy).tar(tar);
+            options = match filter {
+                Some(FilterArg::X86) => options.filter(Filter::X86),
+                Some(FilterArg::Arm64) => options.filter(Filter::Arm64),
+                Some(FilterArg::Riscv) => options.filter(Filter::RiscV),
+                None => options,
+            };
+            options = match cost {
+                Some(CostArg::Size) => options.cost(SizeCost),
+                Some(CostArg::Speed) => options.cost(SpeedCost),
+                None => options,
+            };
+            let (old, new) = read_both(&old, &new)?;
+            let mut encoded = Vec::new();
+            options.diff(&old, &new, &mut encoded)?;
+            write(&patch, &encoded)
+        }
+        Command::Patch {
+            old,
+            patch,
+            new,
+            max_output,
+        } => {
+            let (old, patch) = read_both(&old, &patch)?;
+            let patched = apply(&old, &patch, max_output)?;
+            write(&new, &patched)
+        }
+        Command::Encode { bsdiff, patch } => {
+            let mut encoder = Encoder::new(create(&patch)?);
+            io::copy(&mut open(&bsdiff)?, &mut encoder)?;
+            encoder
+                .finish()
+                .context("truncated bsdiff patch")?
+                .flush()?;
+            Ok(())
+        }
+        Command::Decode {
+            patch,
+            bsdiff,
+            max_output,
+        } => {
+            // Every control is checked against the limit before any output
+            let limits = Limits::unlimited().output(max_output);
+            let mut decoder = Decoder::with_limits(open(&patch)?, limits);
+            let mut writer = create(&bsdiff)?;
+            io::copy(&mut decoder, &mut writer)?;
+            Ok(writer.flush()?)
+        }
+        Command::Inspect {
+            patch: patch_path,
+            old,
+            max_output,
+        } => {
+            if let Some(old) = &old {
+                ensure!(!(is_std(old) && is_std(&patch_path)), STDIN_TWICE);
+            }
+            let patch = read(&patch_path)?;
+            let info = aehobak::inspect(&patch).context("invalid patch")?;
+            println!("new length:  {}", info.new_len);
+            println!("old length:  {} or more", info.old_len);
+            println!("controls:    {}", info.controls);
+            println!("added:       {} ({} deltas)", info.added, info.deltas);
+            println!("literals:    {}", info.literals);
+            println!("filled:      {}", info.filled);
+            println!("repeated:    {}", info.repeated);
+            match info.filter {
+                Some(filter) => println!("filter:      {filter:?}"),
+                None => println!("filter:      none"),
+            }
+            println!("shifts:      {}", info.shifts);
+            println!("references:  {}", info.references);
+            if let Some(path) = old {
+                let old = read(&path)?;
+                ensure!(old.len() >= info.old_len, "patch reads beyond old");
+                apply(&old, &patch, max_output)?;
+                println!("valid against {}", path.display());
+            }
+            Ok(())
+        }
+        Command::Compare { old, new } => {
+            let (old, new) = read_both(&old, &new)?;
+            let (mut encoded, mut bsdiff) = (Vec::new(), Vec::new());
+            aehobak::diff(&old, &new, &mut encoded)?;
+            aehobak::decode(&mut encoded.as_slice(), &mut bsdiff)?;
+            let bsdiff_lz4 = lz4_flex::block::compress(&bsdiff).len();
+            let aehobak_lz4 = lz4_flex::block::compress(&encoded).len();
+            println!("bsdiff:      {} bytes", bsdiff.len());
+            println!("aehobak:     {} bytes", encoded.len());
+            println!("bsdiff+lz4:  {} bytes", bsdiff_lz4);
+            println!("aehobak+lz4: {} bytes", aehobak_lz4);
+            Ok(())
+        }
+    }
+}
+
+/// Apply `patch` to `old`, refusing output beyond `max_output` before
+/// reserving the length of new that the patch declares.
+fn apply(old: &[u8], patch: &[u8], max_output: usize) -> Result<Vec<u8>> {
+    let info = aehobak::inspect(patch).context("invalid patch")?;
+    ensure!(
+        info.new_len <= max_output,
+        "patch produces {} bytes, more than --max-output",
+        info.new_len
+    );
+    let mut patched = Vec::new();
+    patched.try_reserve_exact(info.new_len)?;
+    // Seeks and controls are bounded by old and the patch as read
+    let limits = Limits::unlimited().output(max_output);
+    limits
+        .patch(old, patch, &mut patched)
+        .context("failed to apply patch")?;
+    Ok(patched)
+}
+
+const STDIN_TWICE: &str = "standard input can be read only once";
+
+fn is_std(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn read_both(first: &Path, second: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    ensure!(!(is_std(first) && is_std(second)), STDIN_TWICE);
+    Ok((read(first)?, read(second)?))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    open(path)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn write(path: &Path, buf: &[u8]) -> Result<()> {
+    let mut writer = create(path)?;
+    writer.write_all(buf)?;
+    Ok(writer.flush()?)
+}
+
+fn open(path: &Path) -> Result<Box<dyn Read>> {
+    if is_std(path) {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    let file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+fn create(path: &Path) -> Result<Box<dyn Write>> {
+    if is_std(path) {
+        return Ok(Box::new(BufWriter::new(io::stdout().lock())));
+    }
+    let file = File::create(path).with_context(|| format!("cannot create {}", path.display()))?;
+    Ok(Box::new(BufWriter::new(file)))
+}
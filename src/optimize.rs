@@ -0,0 +1,483 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::cost::CostModel;
+use crate::diff::{Op, Source};
+
+/// Passes are repeated while they improve the parse, up to this many times.
+const MAX_PASSES: usize = 4;
+
+/// Diagonals of this many controls either side of a position are candidates.
+const NEIGHBOURS: usize = 2;
+
+/// Rewrite the controls of a parse of `new` to reduce their cost under `model`.
+///
+/// The parse is first replaced by the shortest path through the diagonals
+/// of its controls, where that is cheaper. Then empty controls are dropped,
+/// and those whose add costs more than the same bytes as literals are folded
+/// into the literals of their predecessor. The boundaries between each add,
+/// the following literals and the next add are then moved to the cheapest
+/// positions along both diagonals. Finally, controls continuing the diagonal
+/// of their predecessor are merged.
+pub(crate) fn optimize(old: &[u8], new: &[u8], ops: &[Op], model: &dyn CostModel) -> Vec<Op> {
+    let mut ops = ops.to_vec();
+    let mut cost = total(old, new, &ops, model);
+    if let Some(path) = shortest_path(old, new, &ops, model) {
+        let path_cost = total(old, new, &path, model);
+        if path_cost < cost {
+            (ops, cost) = (path, path_cost);
+        }
+    }
+    for _ in 0..MAX_PASSES {
+        // Diagonals are kept apart until boundaries have moved
+        let mut next = drop_costly(old, new, &merge(ops.clone(), false), model);
+        resplit(old, new, &mut next, model);
+        let next = merge(next, true);
+        let next_cost = total(old, new, &next, model);
+        if next_cost >= cost {
+            break;
+        }
+        (ops, cost) = (next, next_cost);
+    }
+    ops
+}
+
+/// The modelled cost of a whole parse.
+pub(crate) fn total(old: &[u8], new: &[u8], ops: &[Op], model: &dyn CostModel) -> u64 {
+    let mut cost = 0;
+    let mut new_cursor = 0;
+    for (i, op) in ops.iter().enumerate() {
+        cost += control_cost(op, ops.get(i + 1), model);
+        cost += add_cost(&old[op.old..], &new[new_cursor..][..op.add], model);
+        cost += op.copy as u64 * model.literal();
+        new_cursor += op.add + op.copy;
+    }
+    cost
+}
+
+fn clamp(value: u64) -> u32 {
+    value.min(u32::MAX.into()) as u32
+}
+
+/// The cost of the control for `op`, seeking to `next`.
+fn control_cost(op: &Op, next: Option<&Op>, model: &dyn CostModel) -> u64 {
+    let old_end = op.old + op.add;
+    let seek = next.map_or(old_end, |next| next.old) as i64 - old_end as i64;
+    let seek = seek.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+    model.control(clamp(op.add as u64), clamp(op.copy as u64), seek)
+}
+
+/// Delta costs of a whole add region.
+fn add_cost(old: &[u8], new: &[u8], model: &dyn CostModel) -> u64 {
+    let mut cost = 0;
+    let mut skip = 0u64;
+    for (n, o) in new.iter().zip(old) {
+        if n == o {
+            skip += 1;
+        } else {
+            cost += model.delta(clamp(skip));
+            skip = 0;
+        }
+    }
+    cost
+}
+
+/// A parse under way, ending in a control that is still open.
+#[derive(Clone, Copy)]
+struct Path {
+    /// Cost of every byte so far and of every control but the open one.
+    cost: u64,
+    /// Offset of old from new along the add of the open control.
+    diagonal: isize,
+    /// The add of the open control within new, followed by its literals.
+    add: (usize, usize),
+    /// Equal bytes since the add began or last differed.
+    skip: u64,
+    /// Index in the arena of the last closed control.
+    closed: usize,
+}
+
+impl Path {
+    /// The open control, if its literals ended at `end`.
+    fn control(&self, end: usize) -> Op {
+        Op {
+            old: self.add.0.wrapping_add_signed(self.diagonal),
+            add: self.add.1 - self.add.0,
+            copy: end - self.add.1,
+            source: Source::Literal,
+        }
+    }
+
+    /// The cost so far, with the open control ending at `end` without a seek.
+    fn estimate(&self, end: usize, model: &dyn CostModel) -> u64 {
+        self.cost + control_cost(&self.control(end), None, model)
+    }
+}
+
+/// Find a cheap parse of `new` by dynamic programming over positions.
+///
+/// Each byte is either a literal or added along the diagonal of one of the
+/// controls near it in `ops`, and a path through these choices is kept for
+/// literals and for each diagonal. Controls are costed exactly once closed,
+/// while open ones are compared as if they ended at the current position,
+/// so the parse is the shortest path up to that approximation. New controls
+/// begin only where a run of equal bytes begins, and adds end only before a
+/// byte that differs. Returns `None` if `ops` uses sources other than literals.
+fn shortest_path(old: &[u8], new: &[u8], ops: &[Op], model: &dyn CostModel) -> Option<Vec<Op>> {
+    if ops.is_empty() || ops.iter().any(|op| op.source != Source::Literal) {
+        return None;
+    }
+    let mut starts = Vec::with_capacity(ops.len());
+    let mut diagonals = Vec::with_capacity(ops.len());
+    let mut pos = 0;
+    for op in ops {
+        starts.push(pos);
+        diagonals.push(op.old as isize - pos as isize);
+        pos += op.add + op.copy;
+    }
+    // The old byte paired with `new[p]` along `d`, if any
+    let paired = |p: usize, d: isize| p.checked_add_signed(d).and_then(|o| old.get(o));
+    let equal = |p: usize, d: isize| paired(p, d) == Some(&new[p]);
+
+    // Closed controls, each with the index of its predecessor
+    let mut arena: Vec<(Op, usize)> = Vec::new();
+    let mut literals = Path {
+        cost: 0,
+        diagonal: 0,
+        add: (0, 0),
+        skip: 0,
+        closed: usize::MAX,
+    };
+    let mut adds: Vec<Path> = Vec::new();
+    let mut next: Vec<Path> = Vec::new();
+    let mut candidates: Vec<isize> = Vec::new();
+    let mut k = 0;
+    for p in 0..new.len() {
+        while k + 1 < ops.len() && starts[k + 1] <= p {
+            k += 1;
+        }
+        candidates.clear();
+        for &d in &diagonals[k.saturating_sub(NEIGHBOURS)..(k + NEIGHBOURS + 1).min(ops.len())] {
+            if paired(p, d).is_some() && !candidates.contains(&d) {
+                candidates.push(d);
+            }
+        }
+
+        // Adds continue along their diagonal, or open where a run begins
+        next.clear();
+        for &d in &candidates {
+            let step = |mut path: Path| {
+                match equal(p, d) {
+                    true => path.skip += 1,
+                    false => {
+                        path.cost += model.delta(clamp(path.skip));
+                        path.skip = 0;
+                    }
+                }
+                path.add.1 = p + 1;
+                path
+            };
+            let mut best = adds
+                .iter()
+                .find(|path| path.diagonal == d)
+                .copied()
+                .map(step);
+            let mut pushed = false;
+            let begins = equal(p, d) && (p == 0 || !equal(p - 1, d));
+            if begins || starts[k] == p {
+                let opened = Op {
+                    old: p.wrapping_add_signed(d),
+                    add: 0,
+                    copy: 0,
+                    source: Source::Literal,
+                };
+                for path in adds
+                    .iter()
+                    .filter(|path| path.diagonal != d)
+                    .chain([&literals])
+                {
+                    // Only the leading literals may form an empty control
+                    let closed = path.control(p);
+                    let empty = closed.add == 0 && closed.copy == 0;
+                    let cost = match empty {
+                        true => path.cost,
+                        false => path.cost + control_cost(&closed, Some(&opened), model),
+                    };
+                    let candidate = step(Path {
+                        cost,
+                        diagonal: d,
+                        add: (p, p),
+                        skip: 0,
+                        closed: path.closed,
+                    });
+                    let estimate = candidate.estimate(p + 1, model);
+                    if best.is_some_and(|best| best.estimate(p + 1, model) <= estimate) {
+                        continue;
+                    }
+                    if pushed {
+                        arena.pop();
+                    }
+                    pushed = !empty;
+                    best = Some(match empty {
+                        true => candidate,
+                        false => {
+                            arena.push((closed, path.closed));
+                            Path {
+                                closed: arena.len() - 1,
+                                ..candidate
+                            }
+                        }
+                    });
+                }
+            }
+            next.extend(best);
+        }
+
+        // Literals continue, or follow an add that ends before a differing byte
+        let mut best = literals;
+        best.cost += model.literal();
+        for path in &adds {
+            if equal(p, path.diagonal) && candidates.contains(&path.diagonal) {
+                continue;
+            }
+            let mut candidate = *path;
+            candidate.cost += model.literal();
+            if candidate.estimate(p + 1, model) < best.estimate(p + 1, model) {
+                best = candidate;
+            }
+        }
+        literals = best;
+        std::mem::swap(&mut adds, &mut next);
+    }
+
+    let end = new.len();
+    let last = adds
+        .iter()
+        .chain([&literals])
+        .min_by_key(|path| path.estimate(end, model))?;
+    let mut parse = vec![last.control(end)];
+    let mut closed = last.closed;
+    while let Some(&(op, prev)) = arena.get(closed) {
+        parse.push(op);
+        closed = prev;
+    }
+    parse.reverse();
+    Some(parse)
+}
+
+/// Fold each control whose add would be cheaper as literals into its predecessor.
+fn drop_costly(old: &[u8], new: &[u8], ops: &[Op], model: &dyn CostModel) -> Vec<Op> {
+    let mut kept: Vec<Op> = Vec::with_capacity(ops.len());
+    let mut new_cursor = 0;
+    for (i, op) in ops.iter().enumerate() {
+        let add_new = &new[new_cursor..][..op.add];
+        new_cursor += op.add + op.copy;
+        let Some(prev) = kept
+            .last_mut()
+            .filter(|prev| prev.source == Source::Literal)
+        else {
+            kept.push(*op);
+            continue;
+        };
+        let keep =
+            control_cost(op, ops.get(i + 1), model) + add_cost(&old[op.old..], add_new, model);
+        let fold = op.add as u64 * model.literal();
+        if fold < keep {
+            prev.copy += op.add + op.copy;
+        } else {
+            kept.push(*op);
+        }
+    }
+    kept
+}
+
+/// Move each boundary between an add, its literals and the next add to
+/// where the sum of delta and literal costs is least.
+fn resplit(old: &[u8], new: &[u8], ops: &mut [Op], model: &dyn CostModel) {
+    let literal = model.literal() as i64;
+    // Over a span from the start of one add to the end of the next, the
+    // first add covers `..p`, literals `p..q` and the second add `q..`.
+    // Costs are offset by the literals each position implies, so that
+    // `head[p] + tail[q]` is the cost of the span for any `p <= q`.
+    let (mut head, mut tail, mut least) = (Vec::new(), Vec::new(), Vec::new());
+    let mut start = 0;
+    for i in 0..ops.len().saturating_sub(1) {
+        let (a, b) = (ops[i], ops[i + 1]);
+        let b_start = a.add + a.copy;
+        let span = b_start + b.add;
+        // Other sources cannot be moved or resized
+        if a.source != Source::Literal {
+            start += b_start;
+            continue;
+        }
+
+        let (mut cost, mut skip) = (0, 0);
+        head.clear();
+        head.push(0);
+        for k in 0..span.min(old.len() - a.old) {
+            if new[start + k] == old[a.old + k] {
+                skip += 1;
+            } else {
+                cost += model.delta(clamp(skip)) as i64;
+                skip = 0;
+            }
+            head.push(cost - literal * (k as i64 + 1));
+        }
+
+        // Walk backward, tracking the first nonzero delta and the cost after it
+        let first = b_start - b_start.min(b.old);
+        let (mut rest, mut nonzero) = (0, None);
+        tail.clear();
+        tail.resize(span + 1, i64::MAX);
+        tail[span] = literal * span as i64;
+        for q in (first..span).rev() {
+            if new[start + q] != old[b.old + q - b_start] {
+                if let Some(r) = nonzero {
+                    rest += model.delta(clamp((r - q - 1) as u64)) as i64;
+                }
+                nonzero = Some(q);
+            }
+            let cost = nonzero.map_or(0, |r| rest + model.delta(clamp((r - q) as u64)) as i64);
+            tail[q] = cost + literal * q as i64;
+        }
+        // Least tail at or after each position
+        least.clear();
+        least.resize(span + 1, i64::MAX);
+        for q in (0..=span).rev() {
+            least[q] = tail[q].min(least.get(q + 1).copied().unwrap_or(i64::MAX));
+        }
+
+        let (p, best) = head
+            .iter()
+            .enumerate()
+            .map(|(p, &h)| (p, h.saturating_add(least[p])))
+            .min_by_key(|&(_, c)| c)
+            .unwrap();
+        let q = (p..=span).find(|&q| tail[q] == least[p]).unwrap();
+        // Controls are costed only once the best boundaries are known
+        let controls = |p: usize, q: usize| {
+            let a = Op {
+                add: p,
+                copy: q - p,
+                ..a
+            };
+            let b = Op {
+                old: b.old + q - b_start,
+                add: span - q,
+                ..b
+            };
+            let c = ops.get(i + 2);
+            (control_cost(&a, Some(&b), model) + control_cost(&b, c, model)) as i64
+        };
+        let current = head[a.add] + tail[b_start] + controls(a.add, b_start);
+        let (p, q) = match best + controls(p, q) < current {
+            true => (p, q),
+            false => (a.add, b_start),
+        };
+        ops[i].add = p;
+        ops[i].copy = q - p;
+        ops[i + 1].old = b.old + q - b_start;
+        ops[i + 1].add = span - q;
+        start += q;
+    }
+}
+
+/// Merge controls into their predecessor where no seek or add separates them,
+/// and optionally where the add continues the diagonal of the predecessor.
+fn merge(ops: Vec<Op>, diagonal: bool) -> Vec<Op> {
+    let mut merged: Vec<Op> = Vec::with_capacity(ops.len());
+    for op in ops {
+        if op.add == 0 && op.copy == 0 {
+            continue;
+        }
+        match merged.last_mut() {
+            // Literals continue the literals of the predecessor
+            Some(prev)
+                if op.add == 0 && op.source == prev.source && op.source == Source::Literal =>
+            {
+                prev.copy += op.copy
+            }
+            // The add continues the diagonal of the predecessor
+            Some(prev) if diagonal && prev.copy == 0 && prev.old + prev.add == op.old => {
+                prev.add += op.add;
+                prev.copy = op.copy;
+                prev.source = op.source;
+            }
+            _ => merged.push(op),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost::SizeCost;
+
+    #[test]
+    fn resplit_literals() {
+        let old: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        for b in &mut new[500..600] {
+            *b = b.wrapping_add(0x5A);
+        }
+        let op = |old, add, copy| Op {
+            old,
+            add,
+            copy,
+            source: Source::Literal,
+        };
+        let ops = [op(0, 600, 0), op(600, 400, 0)];
+        let optimized = optimize(&old, &new, &ops, &SizeCost);
+        assert_eq!(optimized, [op(0, 500, 100), op(600, 400, 0)]);
+    }
+
+    #[test]
+    fn shortest_path_returns() {
+        use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+        let mut old = vec![0; 16384];
+        rand_xoshiro::Xoshiro256Plus::seed_from_u64(0x6a09e667f3bcc908).fill_bytes(&mut old);
+        // A block moved in, after which new returns to the first diagonal
+        let new = [&old[..2048], &old[6000..8000], &old[4048..6048]].concat();
+        let op = |old, add| Op {
+            old,
+            add,
+            copy: 0,
+            source: Source::Literal,
+        };
+        // The moved block's add runs on over the return, so nearly every
+        // byte after it differs, and moving one boundary cannot help
+        let greedy = [op(0, 2048), op(6000, 4000)];
+        let returns = [op(0, 2048), op(6000, 2000), op(4048, 2000)];
+        let cost = |ops: &[Op]| total(&old, &new, ops, &SizeCost);
+        assert!(cost(&returns) * 10 < cost(&greedy));
+        let optimized = optimize(&old, &new, &greedy, &SizeCost);
+        assert_eq!(
+            optimized.iter().map(|op| op.add + op.copy).sum::<usize>(),
+            new.len()
+        );
+        assert!(cost(&optimized) <= cost(&returns));
+    }
+}
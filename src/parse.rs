@@ -0,0 +1,138 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::{Op, Source};
+use crate::format::Header;
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use streamvbyte64::{Coder, Coder0124};
+
+/// The decoded sections of a patch.
+pub(crate) struct Parsed<'a> {
+    pub header: Header,
+    /// Controls, with absolute old offsets.
+    pub ops: Vec<Op>,
+    /// Position of each delta among the bytes added, with its value.
+    pub deltas: Vec<(usize, u8)>,
+    pub literals: &'a [u8],
+}
+
+/// Recover the header and controls of a patch, with absolute old offsets.
+///
+/// Controls are checked only for consistency with each other, not with any
+/// old or new, so callers that index with them must check bounds.
+pub(crate) fn parse(patch: &[u8]) -> io::Result<(Header, Vec<Op>)> {
+    let parsed = parse_sections(patch)?;
+    Ok((parsed.header, parsed.ops))
+}
+
+/// Recover the controls of a patch, as `parse`, along with its deltas and literals.
+pub(crate) fn parse_sections(patch: &[u8]) -> io::Result<Parsed<'_>> {
+    let eof = || io::Error::from(UnexpectedEof);
+    let invalid = || io::Error::from(InvalidData);
+    let (header, body) = Header::parse(patch)?;
+    let (prefix_tag, body) = body.split_first_chunk::<1>().ok_or_else(eof)?;
+    let coder = Coder0124::new();
+    let prefix_len = coder.data_len(prefix_tag);
+    let mut prefix = [0u32; 4];
+    coder.decode(
+        prefix_tag,
+        body.get(..prefix_len).ok_or_else(eof)?,
+        &mut prefix,
+    );
+    let [deltas_len, literals_len, controls, data_len] = prefix.map(|v| v as usize);
+
+    let tags_len = (controls.div_ceil(4).checked_mul(3))
+        .and_then(|len| len.checked_add(deltas_len.div_ceil(4)))
+        .ok_or_else(invalid)?;
+    let tags_start = (prefix_len.checked_add(deltas_len))
+        .and_then(|len| len.checked_add(literals_len))
+        .ok_or_else(invalid)?;
+    let sections = body.get(prefix_len..tags_start).ok_or_else(eof)?;
+    let (delta_diffs, literals) = sections.split_at(deltas_len);
+    let tags = body.get(tags_start..).ok_or_else(eof)?;
+    let tags = tags.get(..tags_len).ok_or_else(eof)?;
+    let data = body[tags_start + tags_len..]
+        .get(..data_len)
+        .ok_or_else(eof)?;
+    if coder.data_len(tags) > data.len() {
+        return Err(eof());
+    }
+    let mut seq = vec![0u32; tags_len * 4];
+    coder.decode(tags, data, &mut seq);
+    let controls_padded = controls.div_ceil(4) * 4;
+    let deltas_padded = deltas_len.div_ceil(4) * 4;
+    let copies = &seq[..controls];
+    // Each delta is counted from one past the previous
+    let mut next = 0usize;
+    let deltas = (seq[controls_padded..][..deltas_len].iter())
+        .zip(delta_diffs)
+        .map(|(&skip, &diff)| {
+            let pos = next.saturating_add(skip as usize);
+            next = pos.saturating_add(1);
+            (pos, diff)
+        })
+        .collect();
+    let seeks = &seq[controls_padded + deltas_padded..][..controls];
+    let adds = &seq[controls_padded * 2 + deltas_padded..][..controls];
+
+    let (mut self_copies, mut fills) = (header.self_copies(), header.fills());
+    let mut ops = Vec::with_capacity(controls);
+    let mut old_cursor: usize = 0;
+    for (i, (&add, (&copy, &seek))) in adds.iter().zip(copies.iter().zip(seeks)).enumerate() {
+        let source = match (self_copies.first(), fills.first()) {
+            (Some(c), _) if c.control == i => {
+                self_copies = &self_copies[1..];
+                Source::Repeat(c.distance)
+            }
+            (_, Some(f)) if f.control == i => {
+                fills = &fills[1..];
+                Source::Fill(f.byte)
+            }
+            _ => Source::Literal,
+        };
+        let (add, copy) = (add as usize, copy as usize);
+        ops.push(Op {
+            old: old_cursor,
+            add,
+            copy,
+            source,
+        });
+        let seek = ((seek >> 1) ^ (seek & 1).wrapping_neg()) as i32;
+        old_cursor = old_cursor
+            .checked_add(add)
+            .and_then(|end| end.checked_add_signed(seek as isize))
+            .ok_or_else(invalid)?;
+    }
+    if !self_copies.is_empty() || !fills.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Parsed {
+        header,
+        ops,
+        deltas,
+        literals,
+    })
+}
@@ -23,15 +23,176 @@
  * POSSIBILITY OF SUCH DAMAGE.
  */
 
+use crate::format::{Header, Reference};
+use crate::limits::{self, Limits};
 use std::hint::assert_unchecked;
 use std::io;
 use std::io::ErrorKind::{InvalidData, UnexpectedEof};
 use streamvbyte64::{Coder, Coder0124};
+use xxhash_rust::xxh3::xxh3_128;
 
 /// Directly apply a compact representation of bsdiff output.
 /// Attempts to fill `new` beyond its capacity will result in `Err`.
+/// Untrusted patches should be applied with `Limits::patch`.
+pub fn patch(old: &[u8], patch: &[u8], new: &mut Vec<u8>) -> io::Result<()> {
+    patch_internal(old, patch, new, &Limits::unlimited())
+}
+
+pub(crate) fn patch_internal<O: Old + ?Sized>(
+    old: &O,
+    patch: &[u8],
+    new: &mut Vec<u8>,
+    limits: &Limits,
+) -> io::Result<()> {
+    let (header, body) = Header::parse(patch)?;
+    if let Some(references) = &header.references {
+        check_references(references, &old.references())?;
+    }
+    let Some(prepared) = prepare(old, &header, limits)? else {
+        return apply(old, body, &header, new, limits);
+    };
+    let start = new.len();
+    apply(&prepared[..], body, &header, new, limits)?;
+    if let Some(filter) = header.filter {
+        filter.decode(&mut new[start..]);
+    }
+    Ok(())
+}
+
+/// The content the adds of a patch read in place of old, where the patch
+/// relocates or filters it, or `None` where old is read as it is.
+pub(crate) fn prepare<O: Old + ?Sized>(
+    old: &O,
+    header: &Header,
+    limits: &Limits,
+) -> io::Result<Option<Vec<u8>>> {
+    if header.filter.is_none() && header.relocations.is_none() {
+        return Ok(None);
+    }
+    limits.check_alloc(Some(old.len()))?;
+    let mut prepared = old.references().concat();
+    if let Some(relocations) = &header.relocations {
+        relocations.apply(&mut prepared);
+    }
+    if let Some(filter) = header.filter {
+        filter.encode(&mut prepared);
+    }
+    Ok(Some(prepared))
+}
+
+/// Directly apply a compact representation of bsdiff output produced by
+/// `diff_multi`, given the same references in the same order.
+/// References that differ in number, length or content from those recorded
+/// in the patch are rejected with `InvalidData`.
+/// Attempts to fill `new` beyond its capacity will result in `Err`.
+pub fn patch_multi(olds: &[&[u8]], patch: &[u8], new: &mut Vec<u8>) -> io::Result<()> {
+    let (header, _) = Header::parse(patch)?;
+    if header.references.is_none() {
+        return Err(io::Error::new(InvalidData, "patch records no references"));
+    }
+    patch_internal(&Concat::new(olds), patch, new, &Limits::unlimited())
+}
+
+pub(crate) fn check_references(references: &[Reference], olds: &[&[u8]]) -> io::Result<()> {
+    let matches = references.len() == olds.len()
+        && (references.iter().zip(olds))
+            .all(|(r, old)| r.len == old.len() && r.hash == xxh3_128(old));
+    match matches {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            InvalidData,
+            "references do not match the patch",
+        )),
+    }
+}
+
+/// Content read by the adds of a patch, held in one or more slices.
+pub(crate) trait Old {
+    fn len(&self) -> usize;
+
+    /// Append `len` bytes from `pos` to `new`.
+    ///
+    /// # Safety
+    /// `pos + len` must not exceed `len()`.
+    unsafe fn extend(&self, pos: usize, len: usize, new: &mut Vec<u8>);
+
+    /// The slices that make up old, in order.
+    fn references(&self) -> Vec<&[u8]>;
+}
+
+impl Old for [u8] {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    #[inline(always)]
+    unsafe fn extend(&self, pos: usize, len: usize, new: &mut Vec<u8>) {
+        // SAFETY: The caller upholds that the range lies within old
+        new.extend_from_slice(unsafe { self.get_unchecked(pos..pos + len) });
+    }
+
+    fn references(&self) -> Vec<&[u8]> {
+        vec![self]
+    }
+}
+
+/// Several references read as if concatenated, without copying them.
+struct Concat<'a> {
+    olds: &'a [&'a [u8]],
+    /// Offset of the end of each reference.
+    ends: Vec<usize>,
+}
+
+impl<'a> Concat<'a> {
+    fn new(olds: &'a [&'a [u8]]) -> Self {
+        let ends = (olds.iter())
+            .scan(0usize, |end, old| {
+                *end = end.saturating_add(old.len());
+                Some(*end)
+            })
+            .collect();
+        Self { olds, ends }
+    }
+}
+
+impl Old for Concat<'_> {
+    fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    unsafe fn extend(&self, mut pos: usize, len: usize, new: &mut Vec<u8>) {
+        let end = pos + len;
+        let mut i = self.ends.partition_point(|&e| e <= pos);
+        while pos < end {
+            let start = self.ends[i] - self.olds[i].len();
+            let take = (end - pos).min(self.ends[i] - pos);
+            new.extend_from_slice(&self.olds[i][pos - start..][..take]);
+            pos += take;
+            i += 1;
+        }
+    }
+
+    fn references(&self) -> Vec<&[u8]> {
+        self.olds.to_vec()
+    }
+}
+
 #[allow(clippy::ptr_arg)]
-pub fn patch(old: &[u8], mut patch: &[u8], new: &mut Vec<u8>) -> io::Result<()> {
+fn apply<O: Old + ?Sized>(
+    old: &O,
+    mut patch: &[u8],
+    header: &Header,
+    new: &mut Vec<u8>,
+    limits: &Limits,
+) -> io::Result<()> {
+    let start = new.len();
+    // Output beyond the limit is refused as if beyond capacity
+    let end = new.capacity().min(start.saturating_add(limits.output));
+    let room = |new: &Vec<u8>, len: usize| match end.wrapping_sub(new.len()) < len {
+        true if end < new.capacity() => Err(limits::exceeded("output")),
+        true => Err(io::Error::from(UnexpectedEof)),
+        false => Ok(()),
+    };
     let prefix_tag = patch.get(..1).ok_or(io::Error::from(UnexpectedEof))?;
     patch = &patch[1..];
 
@@ -46,6 +207,7 @@ pub fn patch(old: &[u8], mut patch: &[u8], new: &mut Vec<u8>) -> io::Result<()>
         (v[0] as usize, v[1] as usize, v[2] as usize, v[3] as usize)
     };
     patch = &patch[prefix_len..];
+    limits.check_controls(controls)?;
 
     let mut delta_diffs = patch
         .get(..deltas_len)
@@ -101,8 +263,20 @@ pub fn patch(old: &[u8], mut patch: &[u8], new: &mut Vec<u8>) -> io::Result<()>
     (delta_data, data) = data.split_at(delta_data_len);
     (seek_data, add_data) = data.split_at(seek_data_len);
 
+    let mut self_copies = header.self_copies();
+    let mut fills = header.fills();
+    if self_copies.last().is_some_and(|c| c.control >= controls)
+        || fills.last().is_some_and(|f| f.control >= controls)
+    {
+        return Err(io::Error::new(
+            InvalidData,
+            "copy source beyond last control",
+        ));
+    }
+
     let mut old_cursor: usize = 0;
     let mut copy_cursor: usize = 0;
+    let mut control: usize = 0;
 
     let mut window = [0; 8];
     let mut delta_pos_buf = [0; 32];
@@ -142,15 +316,16 @@ pub fn patch(old: &[u8], mut patch: &[u8], new: &mut Vec<u8>) -> io::Result<()>
         }
         for (&add, (&copy, &seek)) in adds.iter().zip(copies.iter().zip(&seeks)) {
             let (add, copy, seek) = (add as usize, copy as usize, seek as i32 as i64);
-            let old_slice = old
-                .get(old_cursor..)
-                .ok_or(io::Error::from(UnexpectedEof))?
-                .get(..add)
-                .ok_or(io::Error::from(UnexpectedEof))?;
-            if new.capacity().wrapping_sub(new.len()) < old_slice.len() {
-                Err(io::Error::from(UnexpectedEof))?;
+            if old_cursor
+                .checked_add(add)
+                .is_none_or(|end| end > old.len())
+            {
+                return Err(io::Error::from(UnexpectedEof));
             }
-            new.extend_from_slice(old_slice);
+            limits.check_seek(seek)?;
+            room(new, add)?;
+            // SAFETY: The range was checked against the length of old above
+            unsafe { old.extend(old_cursor, add, new) };
             'outer: while !delta_diffs.is_empty() {
                 if delta_pos.is_empty() {
                     tags = if delta_tags.len() >= 8 {
@@ -187,12 +362,37 @@ pub fn patch(old: &[u8], mut patch: &[u8], new: &mut Vec<u8>) -> io::Result<()>
                 delta_pos = &mut delta_pos[nonzero..];
                 delta_diffs = &delta_diffs[nonzero..];
             }
-            let lit_slice = literals.get(..copy).ok_or(io::Error::from(UnexpectedEof))?;
-            if new.capacity().wrapping_sub(new.len()) < lit_slice.len() {
-                Err(io::Error::from(UnexpectedEof))?;
+            match self_copies.first() {
+                Some(self_copy) if self_copy.control == control => {
+                    self_copies = &self_copies[1..];
+                    let mut from = new
+                        .len()
+                        .checked_sub(self_copy.distance)
+                        .filter(|&from| from >= start)
+                        .ok_or(io::Error::from(InvalidData))?;
+                    room(new, copy)?;
+                    // Overlapping repeats are extended a distance at a time
+                    let end = new.len() + copy;
+                    while new.len() < end {
+                        let len = (end - new.len()).min(self_copy.distance);
+                        new.extend_from_within(from..from + len);
+                        from += len;
+                    }
+                }
+                _ if fills.first().is_some_and(|f| f.control == control) => {
+                    let byte = fills[0].byte;
+                    fills = &fills[1..];
+                    room(new, copy)?;
+                    new.resize(new.len() + copy, byte);
+                }
+                _ => {
+                    let lit_slice = literals.get(..copy).ok_or(io::Error::from(UnexpectedEof))?;
+                    room(new, lit_slice.len())?;
+                    new.extend_from_slice(lit_slice);
+                    literals = &literals[copy..];
+                }
             }
-            new.extend_from_slice(lit_slice);
-            literals = &literals[copy..];
+            control += 1;
             copy_cursor = copy_cursor.wrapping_add(copy);
             old_cursor = usize::try_from(
                 i64::try_from(
@@ -207,6 +407,10 @@ pub fn patch(old: &[u8], mut patch: &[u8], new: &mut Vec<u8>) -> io::Result<()>
             .map_err(|_| io::Error::from(InvalidData))?;
         }
     }
+    // A control named in both lists leaves the later one unconsumed
+    if !self_copies.is_empty() || !fills.is_empty() {
+        return Err(io::Error::new(InvalidData, "conflicting copy sources"));
+    }
     Ok(())
 }
 
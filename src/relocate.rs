@@ -0,0 +1,107 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use std::ops::Range;
+
+/// Absolute pointers in old to rebase before patching, recorded in the header.
+///
+/// Each aligned word of old within a scanned range whose value falls in a
+/// shifted range of addresses has that shift added, so pointers into sections
+/// that moved between old and new match their counterparts in new rather
+/// than appearing as deltas. Only old is rewritten, so no inverse is needed.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Relocations {
+    /// Bytes in a pointer, 4 or 8.
+    pub width: u8,
+    pub big_endian: bool,
+    /// Ranges of old holding pointers, in order and disjoint.
+    pub scan: Vec<Range<usize>>,
+    /// Ranges of addresses that moved, in order and disjoint.
+    pub shifts: Vec<Shift>,
+}
+
+/// Addresses within `start..start + len` moved by `delta`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Shift {
+    pub start: usize,
+    pub len: usize,
+    pub delta: i64,
+}
+
+impl Relocations {
+    /// A copy of `old` with its pointers rebased.
+    pub fn relocate(&self, old: &[u8]) -> Vec<u8> {
+        let mut old = old.to_vec();
+        self.apply(&mut old);
+        old
+    }
+
+    /// Rebase pointers in place.
+    /// Scanned ranges beyond the end of `old` are cut short.
+    pub fn apply(&self, old: &mut [u8]) {
+        let width = usize::from(self.width);
+        for range in &self.scan {
+            let mut pos = range.start.min(old.len()).next_multiple_of(width);
+            let end = range.end.min(old.len());
+            while pos + width <= end {
+                let word = &mut old[pos..pos + width];
+                if let Some(value) = self.rebase(self.read(word)) {
+                    self.write(word, value);
+                }
+                pos += width;
+            }
+        }
+    }
+
+    /// The shifted value of a pointer, if it points into a shifted range.
+    fn rebase(&self, value: u64) -> Option<u64> {
+        let value = usize::try_from(value).ok()?;
+        let i = self.shifts.partition_point(|s| s.start <= value);
+        let shift = self.shifts.get(i.checked_sub(1)?)?;
+        (value - shift.start < shift.len).then(|| (value as u64).wrapping_add_signed(shift.delta))
+    }
+
+    fn read(&self, word: &[u8]) -> u64 {
+        let mut bytes = [0; 8];
+        match self.big_endian {
+            true => {
+                bytes[8 - word.len()..].copy_from_slice(word);
+                u64::from_be_bytes(bytes)
+            }
+            false => {
+                bytes[..word.len()].copy_from_slice(word);
+                u64::from_le_bytes(bytes)
+            }
+        }
+    }
+
+    fn write(&self, word: &mut [u8], value: u64) {
+        let len = word.len();
+        match self.big_endian {
+            true => word.copy_from_slice(&value.to_be_bytes()[8 - len..]),
+            false => word.copy_from_slice(&value.to_le_bytes()[..len]),
+        }
+    }
+}
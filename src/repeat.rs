@@ -0,0 +1,165 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::{Op, Source};
+use std::ops::Range;
+
+/// Shorter repeats cost more as a control than as literals.
+const MIN_REPEAT: usize = 32;
+/// Shorter runs cost more as a control than as literals.
+const MIN_FILL: usize = 16;
+/// Positions of new are sampled at this stride, and so is the hashed run.
+const STRIDE: usize = 8;
+const MAX_TABLE: usize = 1 << 20;
+
+/// Split the literal copies of `ops` around runs of a single byte.
+pub(crate) fn fill(new: &[u8], ops: &[Op]) -> Vec<Op> {
+    let mut split = Vec::with_capacity(ops.len());
+    let mut new_cursor = 0;
+    for op in ops {
+        let start = new_cursor + op.add;
+        let end = start + op.copy;
+        let mut pieces = Vec::new();
+        if op.source == Source::Literal {
+            let mut pos = start;
+            while pos < end {
+                let byte = new[pos];
+                let len = new[pos..end].iter().take_while(|&&b| b == byte).count();
+                if len >= MIN_FILL {
+                    pieces.push((pos..pos + len, Source::Fill(byte)));
+                }
+                pos += len;
+            }
+        }
+        carve(op, start, pieces, &mut split);
+        new_cursor = end;
+    }
+    split
+}
+
+/// Split the literal copies of `ops` around repeats of earlier parts of new.
+///
+/// Every aligned run of new is remembered in a direct-mapped table, and each
+/// position of a literal copy is looked up in it. Any repeat of at least
+/// `MIN_REPEAT` bytes spans an aligned run, so a repeat is only missed when
+/// its table entry has since been replaced.
+pub(crate) fn split(new: &[u8], ops: &[Op]) -> Vec<Op> {
+    let bits = (new.len() / STRIDE)
+        .next_power_of_two()
+        .clamp(1024, MAX_TABLE)
+        .trailing_zeros();
+    let mut table = vec![usize::MAX; 1 << bits];
+    let slot = |pos: usize| {
+        let run = u64::from_le_bytes(new[pos..pos + STRIDE].try_into().unwrap());
+        (run.wrapping_mul(0x9E3779B97F4A7C15) >> (64 - bits)) as usize
+    };
+
+    let mut split = Vec::with_capacity(ops.len());
+    // Aligned positions below `indexed` are in the table
+    let mut indexed = 0;
+    let mut new_cursor = 0;
+    for op in ops {
+        let start = new_cursor + op.add;
+        let end = start + op.copy;
+        let mut pieces = Vec::new();
+        let mut literal = start;
+        let mut pos = start;
+        while op.source == Source::Literal && pos + MIN_REPEAT <= end {
+            while indexed + STRIDE <= pos {
+                table[slot(indexed)] = indexed;
+                indexed += STRIDE;
+            }
+            let candidate = table[slot(pos)];
+            if candidate == usize::MAX
+                || new[candidate..candidate + STRIDE] != new[pos..pos + STRIDE]
+            {
+                pos += 1;
+                continue;
+            }
+            let ahead = new[candidate..end]
+                .iter()
+                .zip(&new[pos..end])
+                .take_while(|(a, b)| a == b)
+                .count();
+            let behind = new[..candidate]
+                .iter()
+                .rev()
+                .zip(new[literal..pos].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            if ahead + behind < MIN_REPEAT {
+                pos += 1;
+                continue;
+            }
+            let repeat = pos - behind..pos + ahead;
+            pieces.push((repeat.clone(), Source::Repeat(pos - candidate)));
+            pos = repeat.end;
+            literal = pos;
+        }
+        carve(op, start, pieces, &mut split);
+        new_cursor = end;
+    }
+    split
+}
+
+/// Replace the copy of `op`, which begins at `start` in new, with literals
+/// between the given disjoint pieces in order.
+fn carve(op: &Op, start: usize, pieces: Vec<(Range<usize>, Source)>, split: &mut Vec<Op>) {
+    if pieces.is_empty() {
+        split.push(*op);
+        return;
+    }
+    let old_end = op.old + op.add;
+    // The first control keeps the add, and the rest only copy
+    let mut head = Op {
+        copy: 0,
+        source: Source::Literal,
+        ..*op
+    };
+    let mut literal = start;
+    for (range, source) in pieces {
+        head.copy = range.start - literal;
+        if head.add != 0 || head.copy != 0 {
+            split.push(head);
+        }
+        split.push(Op {
+            old: old_end,
+            add: 0,
+            copy: range.len(),
+            source,
+        });
+        literal = range.end;
+        head = Op {
+            old: old_end,
+            add: 0,
+            copy: 0,
+            source: Source::Literal,
+        };
+    }
+    head.copy = start + op.copy - literal;
+    if head.copy != 0 {
+        split.push(head);
+    }
+}
@@ -0,0 +1,346 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::Source;
+use crate::limits::Limits;
+use crate::parse::{parse_sections, Parsed};
+use crate::patch::{check_references, prepare, Old};
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof};
+use std::io::Write;
+
+/// Adjacent changes at most this far apart are shown as one region.
+const MAX_GAP: usize = 8;
+/// Seeks at least this long are reported as moved blocks.
+const MIN_MOVE: i64 = 256;
+/// Inserted bytes shown per region, beyond which the dump is elided.
+const MAX_DUMP: usize = 256;
+const ROW: usize = 16;
+
+/// Output format of `report`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Plain text, with changed bytes in upper case.
+    Text,
+    /// A standalone HTML document, with changed bytes highlighted.
+    Html,
+}
+
+/// A region of new, as described by the controls of a patch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Region {
+    /// Bytes of new that differ from old near `old`, within the add spanning `add`.
+    Changed {
+        new: usize,
+        old: usize,
+        len: usize,
+        add: (usize, usize),
+    },
+    /// Literal bytes with no counterpart in old, from offset `literal` of the literals.
+    Inserted {
+        new: usize,
+        len: usize,
+        literal: usize,
+    },
+    /// A run of one byte.
+    Filled { new: usize, len: usize, byte: u8 },
+    /// A repeat of earlier bytes of new.
+    Repeated { new: usize, len: usize, from: usize },
+    /// Bytes of new taken from a distant part of old.
+    Moved { new: usize, old: usize, len: usize },
+}
+
+/// Describe the changes `patch` makes to `old`, in the style of `cmp -l`
+/// combined with a side-by-side hexdump.
+///
+/// Regions are taken from the controls of the patch without applying it:
+/// nonzero deltas are grouped into changed regions, literals are shown as
+/// insertions, and adds reached by a long seek are shown as moved blocks.
+/// Where the patch relocates or filters old, changed bytes are shown as
+/// prepared for its deltas. A copy of old for this is bounded by the
+/// default `Limits`.
+pub fn report<T: Write>(
+    old: &[u8],
+    patch: &[u8],
+    format: ReportFormat,
+    writer: &mut T,
+) -> io::Result<()> {
+    let Parsed {
+        header,
+        ops,
+        deltas,
+        literals,
+    } = parse_sections(patch)?;
+    if let Some(references) = &header.references {
+        check_references(references, &old.references())?;
+    }
+    let prepared = prepare(old, &header, &Limits::default())?;
+    let old = prepared.as_deref().unwrap_or(old);
+
+    let mut regions = Vec::new();
+    // Deltas by position in new, as applied
+    let mut changes = Vec::new();
+    let mut deltas = deltas.iter().peekable();
+    let (mut new_cursor, mut added, mut literal) = (0usize, 0usize, 0usize);
+    let mut old_end = 0;
+    for op in &ops {
+        if op.old.checked_add(op.add).is_none_or(|end| end > old.len()) {
+            return Err(io::Error::from(UnexpectedEof));
+        }
+        if op.add != 0 && (op.old as i64 - old_end as i64).abs() >= MIN_MOVE {
+            regions.push(Region::Moved {
+                new: new_cursor,
+                old: op.old,
+                len: op.add,
+            });
+        }
+        let add_end = new_cursor.checked_add(op.add);
+        let add = (new_cursor, add_end.ok_or(io::Error::from(InvalidData))?);
+        let mut changed: Option<(usize, usize)> = None;
+        while let Some(&(pos, diff)) = deltas.next_if(|&&(pos, _)| pos < added + op.add) {
+            let i = pos - added;
+            if diff == 0 {
+                continue;
+            }
+            changes.push((new_cursor + i, diff));
+            changed = match changed {
+                Some((start, end)) if i - end <= MAX_GAP => Some((start, i + 1)),
+                Some((start, end)) => {
+                    regions.push(changed_region(op.old, add, start, end));
+                    Some((i, i + 1))
+                }
+                None => Some((i, i + 1)),
+            };
+        }
+        if let Some((start, end)) = changed {
+            regions.push(changed_region(op.old, add, start, end));
+        }
+        new_cursor = add.1;
+        added += op.add;
+        let (new, len) = (new_cursor, op.copy);
+        match op.source {
+            Source::Literal => {
+                if literals.len() - literal < len {
+                    return Err(io::Error::from(UnexpectedEof));
+                }
+                if len != 0 {
+                    regions.push(Region::Inserted { new, len, literal });
+                }
+                literal += len;
+            }
+            Source::Fill(byte) if len != 0 => regions.push(Region::Filled { new, len, byte }),
+            Source::Repeat(distance) if len != 0 => {
+                let from = new
+                    .checked_sub(distance)
+                    .ok_or(io::Error::from(InvalidData))?;
+                regions.push(Region::Repeated { new, len, from });
+            }
+            _ => {}
+        }
+        new_cursor = (new_cursor.checked_add(op.copy)).ok_or(io::Error::from(InvalidData))?;
+        old_end = op.old + op.add;
+    }
+
+    let mut out = Renderer {
+        format,
+        writer,
+        old,
+        changes: &changes,
+        literals,
+    };
+    out.begin(new_cursor)?;
+    for region in regions {
+        out.region(region)?;
+    }
+    out.end()
+}
+
+fn changed_region(old: usize, add: (usize, usize), start: usize, end: usize) -> Region {
+    Region::Changed {
+        new: add.0 + start,
+        old: old + start,
+        len: end - start,
+        add,
+    }
+}
+
+struct Renderer<'w, T: Write> {
+    format: ReportFormat,
+    writer: &'w mut T,
+    old: &'w [u8],
+    /// Position in new and value of each nonzero delta.
+    changes: &'w [(usize, u8)],
+    literals: &'w [u8],
+}
+
+impl<T: Write> Renderer<'_, T> {
+    fn begin(&mut self, new_len: usize) -> io::Result<()> {
+        if self.format == ReportFormat::Html {
+            writeln!(self.writer, "<!DOCTYPE html>")?;
+            writeln!(self.writer, "<html><head><meta charset=\"utf-8\">")?;
+            writeln!(
+                self.writer,
+                "<style>pre{{margin:0 0 1em 2em}}mark{{background:#fd8}}</style>"
+            )?;
+            writeln!(self.writer, "</head><body>")?;
+        }
+        let old_len = self.old.len();
+        self.heading(&format!("old {old_len} bytes, new {new_len} bytes"))
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        if self.format == ReportFormat::Html {
+            writeln!(self.writer, "</body></html>")?;
+        }
+        Ok(())
+    }
+
+    fn heading(&mut self, text: &str) -> io::Result<()> {
+        match self.format {
+            ReportFormat::Text => writeln!(self.writer, "{text}"),
+            ReportFormat::Html => writeln!(self.writer, "<h3>{text}</h3>"),
+        }
+    }
+
+    fn region(&mut self, region: Region) -> io::Result<()> {
+        match region {
+            Region::Changed {
+                new: at,
+                old: from,
+                len,
+                add,
+            } => {
+                self.heading(&format!(
+                    "changed  new {at:#010x}  old {from:#010x}  {len} bytes"
+                ))?;
+                // Rows of the add from an aligned offset, beside the span of old it reads
+                let lead = (at % ROW).min(at - add.0);
+                let (start, old_start) = (at - lead, from - lead);
+                let end = (start + (lead + len).next_multiple_of(ROW)).min(add.1);
+                let old = &self.old[old_start..][..end - start];
+                let mut new = old.to_vec();
+                let first = self.changes.partition_point(|&(pos, _)| pos < start);
+                for &(pos, diff) in &self.changes[first..] {
+                    if pos >= end {
+                        break;
+                    }
+                    new[pos - start] = new[pos - start].wrapping_add(diff);
+                }
+                self.rows(old, old_start, &new, start, lead..lead + len)
+            }
+            Region::Inserted {
+                new: at,
+                len,
+                literal,
+            } => {
+                self.heading(&format!("inserted new {at:#010x}  {len} bytes"))?;
+                let shown = len.min(MAX_DUMP);
+                let literals = self.literals;
+                self.dump(&literals[literal..][..shown], at, len > shown)
+            }
+            Region::Filled { new: at, len, byte } => self.heading(&format!(
+                "filled   new {at:#010x}  {len} bytes of {byte:#04x}"
+            )),
+            Region::Repeated { new: at, len, from } => self.heading(&format!(
+                "repeated new {at:#010x}  {len} bytes from new {from:#010x}"
+            )),
+            Region::Moved {
+                new: at,
+                old: from,
+                len,
+            } => self.heading(&format!(
+                "moved    new {at:#010x}  old {from:#010x}  {len} bytes ({:+})",
+                from as i64 - at as i64
+            )),
+        }
+    }
+
+    /// Rows of old beside rows of new, with bytes in `changed` marked.
+    fn rows(
+        &mut self,
+        old: &[u8],
+        old_at: usize,
+        new: &[u8],
+        new_at: usize,
+        changed: std::ops::Range<usize>,
+    ) -> io::Result<()> {
+        self.open()?;
+        for (row, new_row) in new.chunks(ROW).enumerate() {
+            let offset = row * ROW;
+            let old_row = old.get(offset..).unwrap_or_default();
+            let old_row = &old_row[..old_row.len().min(ROW)];
+            write!(self.writer, "{:08x} ", old_at + offset)?;
+            for (i, &b) in old_row.iter().enumerate() {
+                let mark = changed.contains(&(offset + i)) && new_row.get(i) != Some(&b);
+                self.byte(b, mark)?;
+            }
+            write!(self.writer, "{}", "   ".repeat(ROW - old_row.len()))?;
+            write!(self.writer, "  | {:08x} ", new_at + offset)?;
+            for (i, &b) in new_row.iter().enumerate() {
+                let mark = changed.contains(&(offset + i)) && old_row.get(i) != Some(&b);
+                self.byte(b, mark)?;
+            }
+            writeln!(self.writer)?;
+        }
+        self.close()
+    }
+
+    fn dump(&mut self, bytes: &[u8], at: usize, elided: bool) -> io::Result<()> {
+        self.open()?;
+        for (row, chunk) in bytes.chunks(ROW).enumerate() {
+            write!(self.writer, "{:08x} ", at + row * ROW)?;
+            for &b in chunk {
+                self.byte(b, false)?;
+            }
+            writeln!(self.writer)?;
+        }
+        if elided {
+            writeln!(self.writer, "...")?;
+        }
+        self.close()
+    }
+
+    fn byte(&mut self, b: u8, mark: bool) -> io::Result<()> {
+        match (self.format, mark) {
+            (_, false) => write!(self.writer, " {b:02x}"),
+            (ReportFormat::Text, true) => write!(self.writer, " {b:02X}"),
+            (ReportFormat::Html, true) => write!(self.writer, " <mark>{b:02x}</mark>"),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        match self.format {
+            ReportFormat::Text => Ok(()),
+            ReportFormat::Html => write!(self.writer, "<pre>"),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.format {
+            ReportFormat::Text => Ok(()),
+            ReportFormat::Html => writeln!(self.writer, "</pre>"),
+        }
+    }
+}
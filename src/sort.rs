@@ -0,0 +1,323 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use std::fmt::Debug;
+use std::io;
+
+/// A suffix array construction algorithm.
+///
+/// Implementations trade construction speed against peak memory.
+/// Every backend must produce the same ordering for the same input,
+/// so the choice of sorter never changes the resulting patch.
+pub trait SuffixSorter: Debug + Send + Sync {
+    /// Return the offsets of all suffixes of `old` in lexicographic order.
+    fn sort(&self, old: &[u8]) -> io::Result<Box<[u32]>>;
+
+    /// As `sort`, with 64-bit offsets for inputs beyond `i32::MAX` bytes.
+    /// Backends without 64-bit support report the input as too large.
+    fn sort64(&self, old: &[u8]) -> io::Result<Box<[u64]>> {
+        let _ = old;
+        Err(io::Error::other("input too large"))
+    }
+}
+
+/// Sort with `cdivsufsort`, a port of libdivsufsort.
+///
+/// libdivsufsort is limited to 32-bit offsets, so larger inputs are sorted
+/// by induced sorting (SA-IS) instead, in linear time. This needs at most
+/// 16.25 bytes per byte of input, of which 8 are the suffix array, and
+/// typically about 10.5; `Libsais` needs less.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DivSufSort;
+
+impl SuffixSorter for DivSufSort {
+    fn sort(&self, old: &[u8]) -> io::Result<Box<[u32]>> {
+        check_len(old)?;
+        let (_, sa) = cdivsufsort::sort(old).into_parts();
+        // SAFETY: i32 to u32 transmute is safe; non-negative values
+        let sa: Vec<u32> = unsafe { core::mem::transmute(sa) };
+        Ok(sa.into_boxed_slice())
+    }
+
+    fn sort64(&self, old: &[u8]) -> io::Result<Box<[u64]>> {
+        let mut sa = vec![0; old.len()].into_boxed_slice();
+        sais(old, &mut sa, u8::MAX.into());
+        Ok(sa)
+    }
+}
+
+/// Sort by direct comparison of suffixes.
+/// Quadratic in the worst case, but free of foreign code.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NaiveSort;
+
+impl SuffixSorter for NaiveSort {
+    fn sort(&self, old: &[u8]) -> io::Result<Box<[u32]>> {
+        check_len(old)?;
+        let mut sa: Vec<u32> = (0..old.len() as u32).collect();
+        sa.sort_unstable_by_key(|&v| {
+            // SAFETY: Values of `sa` are offsets into `old`
+            unsafe { old.get_unchecked(v as usize..) }
+        });
+        Ok(sa.into_boxed_slice())
+    }
+
+    fn sort64(&self, old: &[u8]) -> io::Result<Box<[u64]>> {
+        let mut sa: Vec<u64> = (0..old.len() as u64).collect();
+        sa.sort_unstable_by_key(|&v| {
+            // SAFETY: Values of `sa` are offsets into `old`
+            unsafe { old.get_unchecked(v as usize..) }
+        });
+        Ok(sa.into_boxed_slice())
+    }
+}
+
+/// Sort with libsais, optionally across OpenMP threads.
+#[cfg(feature = "libsais")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Libsais {
+    threads: Option<u16>,
+}
+
+#[cfg(feature = "libsais")]
+impl Libsais {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `threads` OpenMP threads, or the OpenMP default when zero.
+    #[cfg(feature = "openmp")]
+    pub fn threads(mut self, threads: u16) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+}
+
+#[cfg(feature = "libsais")]
+impl SuffixSorter for Libsais {
+    fn sort(&self, old: &[u8]) -> io::Result<Box<[u32]>> {
+        use libsais::SuffixArrayConstruction;
+        check_len(old)?;
+        let construction = SuffixArrayConstruction::for_text(old).in_owned_buffer32();
+        let sa = match self.threads {
+            #[cfg(feature = "openmp")]
+            Some(threads) => {
+                let threads = match threads {
+                    0 => libsais::ThreadCount::openmp_default(),
+                    n => libsais::ThreadCount::fixed(n),
+                };
+                construction.multi_threaded(threads).run()
+            }
+            _ => construction.single_threaded().run(),
+        }
+        .map_err(|e| io::Error::other(format!("{e:?}")))?
+        .into_vec();
+        // SAFETY: i32 to u32 transmute is safe; non-negative values
+        let sa: Vec<u32> = unsafe { core::mem::transmute(sa) };
+        Ok(sa.into_boxed_slice())
+    }
+
+    fn sort64(&self, old: &[u8]) -> io::Result<Box<[u64]>> {
+        use libsais::SuffixArrayConstruction;
+        let construction = SuffixArrayConstruction::for_text(old).in_owned_buffer64();
+        let sa = match self.threads {
+            #[cfg(feature = "openmp")]
+            Some(threads) => {
+                let threads = match threads {
+                    0 => libsais::ThreadCount::openmp_default(),
+                    n => libsais::ThreadCount::fixed(n),
+                };
+                construction.multi_threaded(threads).run()
+            }
+            _ => construction.single_threaded().run(),
+        }
+        .map_err(|e| io::Error::other(format!("{e:?}")))?
+        .into_vec();
+        // SAFETY: i64 to u64 transmute is safe; non-negative values
+        let sa: Vec<u64> = unsafe { core::mem::transmute(sa) };
+        Ok(sa.into_boxed_slice())
+    }
+}
+
+/// The sorter used when none is specified.
+pub(crate) fn default_sorter() -> &'static dyn SuffixSorter {
+    #[cfg(miri)]
+    return &NaiveSort;
+    #[cfg(not(miri))]
+    return &DivSufSort;
+}
+
+/// Suffix array by induced sorting, for symbols of `s` no greater than `upper`.
+///
+/// Follows Nong, Zhang and Chan, "Two Efficient Algorithms for Linear Time
+/// Suffix Array Construction", recursing on the names of LMS substrings.
+/// The names and the suffix array of each level are held in `sa` itself, so
+/// beyond `sa` this needs one bit per symbol and a bucket per name at each
+/// level, at most 8.25 more bytes per byte of input in all.
+fn sais<T: Copy + Ord + Into<u64>>(s: &[T], sa: &mut [u64], upper: usize) {
+    const EMPTY: u64 = u64::MAX;
+    let n = s.len();
+    let sym = |i: usize| s[i].into() as usize;
+    if n < 8 {
+        for (slot, i) in sa.iter_mut().zip(0..) {
+            *slot = i;
+        }
+        sa.sort_unstable_by(|&a, &b| s[a as usize..].cmp(&s[b as usize..]));
+        return;
+    }
+
+    // Whether each suffix is S-type, smaller than its successor, where
+    // the end of the input is smaller than every symbol
+    let mut types = vec![0u64; n.div_ceil(64)];
+    let is_s = |types: &[u64], i: usize| types[i / 64] >> (i % 64) & 1 != 0;
+    for i in (0..n - 1).rev() {
+        if s[i] < s[i + 1] || s[i] == s[i + 1] && is_s(&types, i + 1) {
+            types[i / 64] |= 1 << (i % 64);
+        }
+    }
+    let types = &types[..];
+    let is_lms = |i: usize| i > 0 && is_s(types, i) && !is_s(types, i - 1);
+    let mut bucket = vec![0usize; upper + 1];
+    // Fill `bucket` with the start, or the end, of each symbol's bucket
+    let buckets = |bucket: &mut [usize], end: bool| {
+        bucket.fill(0);
+        for i in 0..n {
+            bucket[sym(i)] += 1;
+        }
+        let mut sum = 0;
+        for count in bucket.iter_mut() {
+            sum += *count;
+            *count = if end { sum } else { sum - *count };
+        }
+    };
+    let induce = |sa: &mut [u64], bucket: &mut [usize]| {
+        buckets(bucket, false);
+        // The last suffix follows the end of the input
+        sa[bucket[sym(n - 1)]] = n as u64 - 1;
+        bucket[sym(n - 1)] += 1;
+        for i in 0..n {
+            let v = sa[i];
+            if v != EMPTY && v > 0 && !is_s(types, v as usize - 1) {
+                let c = sym(v as usize - 1);
+                sa[bucket[c]] = v - 1;
+                bucket[c] += 1;
+            }
+        }
+        buckets(bucket, true);
+        for i in (0..n).rev() {
+            let v = sa[i];
+            if v != EMPTY && v > 0 && is_s(types, v as usize - 1) {
+                let c = sym(v as usize - 1);
+                bucket[c] -= 1;
+                sa[bucket[c]] = v - 1;
+            }
+        }
+    };
+
+    // Sort LMS substrings by inducing from LMS suffixes in any order
+    sa.fill(EMPTY);
+    buckets(&mut bucket, true);
+    for i in (1..n).rev().filter(|&i| is_lms(i)) {
+        bucket[sym(i)] -= 1;
+        sa[bucket[sym(i)]] = i as u64;
+    }
+    induce(sa, &mut bucket);
+
+    // Gather the sorted LMS positions at the front of `sa`
+    let mut m = 0;
+    for i in 0..n {
+        let v = sa[i];
+        if is_lms(v as usize) {
+            sa[m] = v;
+            m += 1;
+        }
+    }
+    // Name each LMS substring by its rank among distinct substrings, at
+    // half its position, as LMS positions are at least two apart
+    sa[m..].fill(EMPTY);
+    let same = |l: usize, r: usize| {
+        for d in 0.. {
+            let (a, b) = (l + d, r + d);
+            if a >= n || b >= n || s[a] != s[b] || is_s(types, a) != is_s(types, b) {
+                return false;
+            }
+            if d > 0 && (is_lms(a) || is_lms(b)) {
+                return is_lms(a) && is_lms(b);
+            }
+        }
+        unreachable!()
+    };
+    let mut names = 0;
+    let mut prev = None;
+    for i in 0..m {
+        let pos = sa[i] as usize;
+        if prev.is_none_or(|prev| !same(prev, pos)) {
+            names += 1;
+            prev = Some(pos);
+        }
+        sa[m + pos / 2] = names as u64 - 1;
+    }
+    // Move the names to the end of `sa`, in the order of their positions
+    let mut j = n;
+    for i in (m..n).rev() {
+        if sa[i] != EMPTY {
+            j -= 1;
+            sa[j] = sa[i];
+        }
+    }
+
+    // Sort the LMS suffixes by the suffix array of their names
+    let (front, names_of) = sa.split_at_mut(n - m);
+    if names < m {
+        sais(names_of, &mut front[..m], names - 1);
+    } else {
+        for (i, &name) in names_of.iter().enumerate() {
+            front[name as usize] = i as u64;
+        }
+    }
+    for (slot, i) in names_of.iter_mut().zip((1..n).filter(|&i| is_lms(i))) {
+        *slot = i as u64;
+    }
+    for i in 0..m {
+        sa[i] = sa[n - m + sa[i] as usize];
+    }
+    sa[m..].fill(EMPTY);
+    // Place them at the ends of their buckets, each at or after its rank
+    buckets(&mut bucket, true);
+    for i in (0..m).rev() {
+        let v = sa[i];
+        sa[i] = EMPTY;
+        bucket[sym(v as usize)] -= 1;
+        sa[bucket[sym(v as usize)]] = v;
+    }
+    induce(sa, &mut bucket);
+}
+
+fn check_len(old: &[u8]) -> io::Result<()> {
+    if old.len() > i32::MAX as usize {
+        return Err(io::Error::other("input too large"));
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::Segment;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+use xxhash_rust::xxh3::xxh3_64;
+
+const BLOCK: usize = 512;
+
+/// A member of a tar archive, with any extended headers that precede it.
+struct Member<'a> {
+    path: Cow<'a, [u8]>,
+    /// Header blocks, including those of GNU long names and pax extended headers.
+    headers: Range<usize>,
+    /// Contents, up to the end of their padding.
+    payload: Range<usize>,
+}
+
+/// Pair the members of two tar archives by content, or failing that by path.
+///
+/// A member of the same path is preferred where its content matches, and
+/// empty members are paired only by path.
+///
+/// The headers, contents and padding of each new member are matched only
+/// against those of its old counterpart, so unchanged header fields are
+/// copied and the checksum is rebuilt as a delta. Members without a
+/// counterpart are matched against the whole of old, and the end of the
+/// archive against the end of old.
+/// Returns `None` unless both inputs parse as tar archives.
+pub(crate) fn plan(old: &[u8], new: &[u8]) -> Option<Vec<Segment>> {
+    let (old_members, old_end) = members(old)?;
+    let (new_members, new_end) = members(new)?;
+    let mut by_path = HashMap::new();
+    let mut by_hash = HashMap::new();
+    for member in &old_members {
+        let hash = xxh3_64(&old[member.payload.clone()]);
+        // Later members replace earlier ones of the same path on extraction
+        by_path.insert(&member.path[..], (member, hash));
+        if !member.payload.is_empty() {
+            by_hash.entry(hash).or_insert(member);
+        }
+    }
+
+    let mut plan = Vec::new();
+    let mut pos = 0;
+    for member in &new_members {
+        let hash = xxh3_64(&new[member.payload.clone()]);
+        let same_path = by_path.get(&member.path[..]).copied();
+        let same = same_path.filter(|&(_, old_hash)| old_hash == hash);
+        let by_content = (!member.payload.is_empty())
+            .then(|| by_hash.get(&hash).copied())
+            .flatten();
+        let Some(old_member) = (same.map(|(m, _)| m))
+            .or(by_content)
+            .or(same_path.map(|(m, _)| m))
+        else {
+            continue;
+        };
+        push(&mut plan, pos..member.headers.start, None);
+        push(
+            &mut plan,
+            member.headers.clone(),
+            Some(old_member.headers.clone()),
+        );
+        push(
+            &mut plan,
+            member.payload.clone(),
+            Some(old_member.payload.clone()),
+        );
+        pos = member.payload.end;
+    }
+    push(&mut plan, pos..new_end, None);
+    push(&mut plan, new_end..new.len(), Some(old_end..old.len()));
+    Some(plan)
+}
+
+/// Append a segment, extending the last where both runs continue it, as
+/// every segment boundary also ends a control.
+fn push(plan: &mut Vec<Segment>, new: Range<usize>, old: Option<Range<usize>>) {
+    let old = old.filter(|old| !old.is_empty());
+    if let (Some((last_new, Some(last_old))), Some(old)) = (plan.last_mut(), &old) {
+        if last_new.end == new.start && last_old.end == old.start {
+            last_new.end = new.end;
+            last_old.end = old.end;
+            return;
+        }
+    }
+    if !new.is_empty() {
+        plan.push((new, old));
+    }
+}
+
+/// Parse the members of a tar archive, if any, and where they end.
+fn members(tar: &[u8]) -> Option<(Vec<Member<'_>>, usize)> {
+    let mut members = Vec::new();
+    let mut pos = 0;
+    let mut group = None;
+    let mut long_path = None;
+    while let Some(header) = tar.get(pos..pos + BLOCK) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !checksum_matches(header) {
+            return None;
+        }
+        let start = pos + BLOCK;
+        let end = start.checked_add(number(&header[124..136])?)?;
+        pos = end.checked_next_multiple_of(BLOCK)?;
+        let payload = tar.get(start..end)?;
+        if pos > tar.len() {
+            return None;
+        }
+        let first = *group.get_or_insert(start - BLOCK);
+        match header[156] {
+            b'L' => long_path = Some(Cow::Borrowed(c_str(payload))),
+            b'x' => long_path = pax_path(payload).map(Cow::Borrowed).or(long_path),
+            b'K' | b'g' => {}
+            _ => {
+                members.push(Member {
+                    path: long_path.take().unwrap_or_else(|| name(header)),
+                    headers: first..start,
+                    payload: start..pos,
+                });
+                group = None;
+            }
+        }
+    }
+    (!members.is_empty()).then_some((members, pos))
+}
+
+/// The path of a header, joined to its prefix in POSIX ustar archives.
+fn name(header: &[u8]) -> Cow<'_, [u8]> {
+    let (name, prefix) = (c_str(&header[..100]), c_str(&header[345..500]));
+    match &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+        true => Cow::Owned([prefix, b"/", name].concat()),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// The `path` record of a pax extended header.
+fn pax_path(mut records: &[u8]) -> Option<&[u8]> {
+    while !records.is_empty() {
+        let space = records.iter().position(|&b| b == b' ')?;
+        let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        // Each length counts itself, so cannot end before its space
+        if len <= space {
+            return None;
+        }
+        let record = records.get(space + 1..len - 1)?;
+        if let Some(path) = record.strip_prefix(b"path=") {
+            return Some(path);
+        }
+        records = records.get(len..)?;
+    }
+    None
+}
+
+fn c_str(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// Parse a numeric field, in octal or in the base-256 extension of GNU tar.
+fn number(field: &[u8]) -> Option<usize> {
+    if field[0] & 0x80 != 0 {
+        let digits = field[1..].iter().copied();
+        return [field[0] & 0x7F]
+            .into_iter()
+            .chain(digits)
+            .try_fold(0usize, |n, b| n.checked_mul(256)?.checked_add(b.into()));
+    }
+    let digits = c_str(field).trim_ascii();
+    digits.iter().try_fold(0usize, |n, &b| match b {
+        b'0'..=b'7' => n.checked_mul(8)?.checked_add((b - b'0').into()),
+        _ => None,
+    })
+}
+
+/// Check the header checksum, summed with its own field as spaces.
+fn checksum_matches(header: &[u8]) -> bool {
+    let sum: usize = (header.iter().enumerate())
+        .map(|(i, &b)| match i {
+            148..156 => usize::from(b' '),
+            _ => usize::from(b),
+        })
+        .sum();
+    number(&header[148..156]) == Some(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_records() {
+        assert_eq!(pax_path(b"12 path=a/b\n"), Some(&b"a/b"[..]));
+        assert_eq!(pax_path(b"9 size=1\n12 path=a/b\n"), Some(&b"a/b"[..]));
+        // Lengths that end before their space or beyond the payload
+        assert_eq!(pax_path(b"4 x"), None);
+        assert_eq!(pax_path(b"1 x\n"), None);
+        assert_eq!(pax_path(b"9 size=1\n"), None);
+    }
+}
@@ -0,0 +1,434 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::diff::DiffOptions;
+use crate::inspect::inspect;
+use crate::limits::Limits;
+use std::collections::HashMap;
+use std::fs::{self, Metadata, OpenOptions};
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof, Unsupported};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_128;
+
+const MAGIC: &[u8; 8] = b"AEHOTREE";
+const VERSION: u32 = 1;
+
+const DIR: u8 = 0;
+const SAME: u8 = 1;
+const DIFF: u8 = 2;
+const FULL: u8 = 3;
+const LINK: u8 = 4;
+
+/// Windows of this length are hashed to fingerprint content.
+const WINDOW: usize = 32;
+/// One window in 2^SAMPLE_BITS is sampled, on average.
+const SAMPLE_BITS: u32 = 6;
+
+/// A directory entry, with its path relative to the root joined by `/`.
+struct Node {
+    path: String,
+    mode: u32,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    Dir,
+    File,
+    Link(String),
+}
+
+/// An old file that may serve as the base of a new file.
+struct Base {
+    path: String,
+    hash: u128,
+    samples: usize,
+}
+
+/// Write a patch that rebuilds the tree at `new` from the tree at `old`.
+///
+/// Each file of new is recorded as unchanged from a file of old with the
+/// same content, wherever it is, as a patch against the old file at the same
+/// path or the most similar old file elsewhere, or in full where no patch is
+/// smaller. Directories, permissions and symlinks are recorded as found.
+pub(crate) fn diff(
+    options: &DiffOptions,
+    old: &Path,
+    new: &Path,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    let mut bases = Vec::new();
+    let mut by_hash = HashMap::new();
+    let mut by_path = HashMap::new();
+    let mut by_sample: HashMap<u64, Vec<usize>> = HashMap::new();
+    for node in walk(old)? {
+        if let NodeKind::File = node.kind {
+            let data = fs::read(join(old, &node.path)?)?;
+            let hash = xxh3_128(&data);
+            let samples = fingerprint(&data);
+            for &sample in &samples {
+                by_sample.entry(sample).or_default().push(bases.len());
+            }
+            by_hash.entry(hash).or_insert(bases.len());
+            by_path.insert(node.path.clone(), bases.len());
+            bases.push(Base {
+                path: node.path,
+                hash,
+                samples: samples.len(),
+            });
+        }
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    for node in walk(new)? {
+        let same_path = by_path.get(&node.path).copied();
+        match &node.kind {
+            NodeKind::Dir => write_entry(writer, DIR, &node)?,
+            NodeKind::Link(target) => {
+                write_entry(writer, LINK, &node)?;
+                write_bytes(writer, target.as_bytes())?;
+            }
+            NodeKind::File => {
+                let data = fs::read(join(new, &node.path)?)?;
+                let hash = xxh3_128(&data);
+                let same = same_path.filter(|&i| bases[i].hash == hash);
+                if let Some(i) = same.or_else(|| by_hash.get(&hash).copied()) {
+                    write_entry(writer, SAME, &node)?;
+                    write_bytes(writer, bases[i].path.as_bytes())?;
+                    writer.write_all(&hash.to_le_bytes())?;
+                    continue;
+                }
+                let samples = fingerprint(&data);
+                let mut hits: HashMap<usize, usize> = HashMap::new();
+                for sample in &samples {
+                    for &i in by_sample.get(sample).into_iter().flatten() {
+                        *hits.entry(i).or_default() += 1;
+                    }
+                }
+                // At least half the samples of each file must be shared
+                let similar = (hits.into_iter())
+                    .filter(|&(i, n)| 2 * n >= samples.len().max(bases[i].samples))
+                    .max_by_key(|&(i, n)| (n, same_path == Some(i), usize::MAX - i));
+                let mut encoded = Vec::new();
+                if let Some(i) = similar.map(|(i, _)| i).or(same_path) {
+                    let base = fs::read(join(old, &bases[i].path)?)?;
+                    options.diff(&base, &data, &mut encoded)?;
+                    if encoded.len() < data.len() {
+                        write_entry(writer, DIFF, &node)?;
+                        write_bytes(writer, bases[i].path.as_bytes())?;
+                        writer.write_all(&hash.to_le_bytes())?;
+                        writer.write_all(&(encoded.len() as u64).to_le_bytes())?;
+                        writer.write_all(&encoded)?;
+                        continue;
+                    }
+                }
+                write_entry(writer, FULL, &node)?;
+                writer.write_all(&(data.len() as u64).to_le_bytes())?;
+                writer.write_all(&data)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rebuild the tree at `new` from the tree at `old` and a patch written by
+/// `diff_tree`.
+///
+/// The patch is checked in full, and every file is rebuilt in memory and
+/// checked against the hash recorded for it, before `new` is written. Every
+/// path must lie within its tree. Files are created afresh, so `new` should
+/// not exist or be empty. Patches are applied within the default `Limits`,
+/// and symlinks are created last so that no path of the patch resolves
+/// through them.
+pub fn apply_tree(old: &Path, patch: &[u8], new: &Path) -> io::Result<()> {
+    let mut reader = Reader(patch);
+    if reader.take(MAGIC.len() as u64)? != MAGIC {
+        return Err(io::Error::new(InvalidData, "not a tree patch"));
+    }
+    if reader.u32()? != VERSION {
+        return Err(io::Error::new(
+            InvalidData,
+            "unsupported tree patch version",
+        ));
+    }
+    let mut entries = Vec::new();
+    while !reader.0.is_empty() {
+        let kind = reader.take(1)?[0];
+        let path = reader.path()?;
+        let mode = reader.u32()?;
+        let body = match kind {
+            DIR => Body::Dir,
+            SAME => Body::Same(reader.path()?, reader.u128()?),
+            DIFF => {
+                let (base, hash) = (reader.path()?, reader.u128()?);
+                let len = reader.u64()?;
+                Body::Diff(base, hash, reader.take(len)?)
+            }
+            FULL => {
+                let len = reader.u64()?;
+                Body::Full(reader.take(len)?)
+            }
+            LINK => Body::Link(reader.bytes()?),
+            _ => return Err(io::Error::new(InvalidData, "unknown tree entry")),
+        };
+        entries.push((path, mode, body));
+    }
+
+    // Every file is rebuilt and checked before any is written
+    let mut files = Vec::new();
+    for (path, _, body) in &entries {
+        let data = match body {
+            Body::Dir | Body::Link(_) => continue,
+            Body::Same(base, hash) => check_hash(fs::read(old.join(base))?, *hash)?,
+            Body::Diff(base, hash, encoded) => {
+                let base = fs::read(old.join(base))?;
+                let limits = Limits::default();
+                let len = inspect(encoded)?.new_len;
+                let mut data = Vec::with_capacity(len.min(limits.output));
+                limits.patch(&base, encoded, &mut data)?;
+                check_hash(data, *hash)?
+            }
+            Body::Full(data) => data.to_vec(),
+        };
+        files.push((path, data));
+    }
+
+    fs::create_dir_all(new)?;
+    for (path, _, body) in &entries {
+        if let Body::Dir = body {
+            fs::create_dir_all(new.join(path))?;
+        }
+    }
+    for (path, data) in files {
+        let path = new.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(&data)?;
+    }
+    for (path, _, body) in &entries {
+        if let Body::Link(target) = body {
+            symlink(target, &new.join(path))?;
+        }
+    }
+    // Directories are restricted last, from the deepest up
+    for (path, mode, body) in entries.iter().rev() {
+        match body {
+            Body::Link(_) => {}
+            _ => set_mode(&new.join(path), *mode)?,
+        }
+    }
+    Ok(())
+}
+
+enum Body<'a> {
+    Dir,
+    Same(PathBuf, u128),
+    Diff(PathBuf, u128, &'a [u8]),
+    Full(&'a [u8]),
+    Link(&'a [u8]),
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: u64) -> io::Result<&'a [u8]> {
+        let len = usize::try_from(len).map_err(|_| io::Error::from(UnexpectedEof))?;
+        let bytes = self.0.get(..len).ok_or(io::Error::from(UnexpectedEof))?;
+        self.0 = &self.0[len..];
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> io::Result<u128> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len.into())
+    }
+
+    /// Read a relative path, refusing any that could leave its tree.
+    fn path(&mut self) -> io::Result<PathBuf> {
+        let path = std::str::from_utf8(self.bytes()?)
+            .map_err(|_| io::Error::new(InvalidData, "tree path is not UTF-8"))?;
+        join(Path::new(""), path)
+    }
+}
+
+/// Resolve a path joined by `/` under `root`, one normal component at a time.
+fn join(root: &Path, path: &str) -> io::Result<PathBuf> {
+    let mut joined = root.to_path_buf();
+    for name in path.split('/') {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if !name.is_empty() => joined.push(name),
+            _ => return Err(io::Error::new(InvalidData, "tree path escapes its root")),
+        }
+    }
+    Ok(joined)
+}
+
+/// List the entries under `root`, parents before children, in sorted order.
+fn walk(root: &Path) -> io::Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(dir) = pending.pop() {
+        let mut children = Vec::new();
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let name = (entry.file_name().into_string())
+                .map_err(|_| io::Error::new(InvalidData, "tree path is not UTF-8"))?;
+            let path = match dir.is_empty() {
+                true => name,
+                false => format!("{dir}/{name}"),
+            };
+            children.push((path, entry.metadata()?));
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        let start = nodes.len();
+        for (path, metadata) in children {
+            let file_type = metadata.file_type();
+            let kind = if file_type.is_dir() {
+                NodeKind::Dir
+            } else if file_type.is_file() {
+                NodeKind::File
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(root.join(&path))?;
+                let target = (target.into_os_string().into_string())
+                    .map_err(|_| io::Error::new(InvalidData, "symlink target is not UTF-8"))?;
+                NodeKind::Link(target)
+            } else {
+                return Err(io::Error::new(Unsupported, "unsupported file type"));
+            };
+            let mode = mode(&metadata);
+            nodes.push(Node { path, mode, kind });
+        }
+        // Visit subdirectories in sorted order
+        for node in nodes[start..].iter().rev() {
+            if let NodeKind::Dir = node.kind {
+                pending.push(node.path.clone());
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+fn write_entry(writer: &mut dyn Write, kind: u8, node: &Node) -> io::Result<()> {
+    writer.write_all(&[kind])?;
+    write_bytes(writer, node.path.as_bytes())?;
+    writer.write_all(&node.mode.to_le_bytes())
+}
+
+fn write_bytes(writer: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| io::Error::from(InvalidData))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn check_hash(data: Vec<u8>, hash: u128) -> io::Result<Vec<u8>> {
+    match xxh3_128(&data) == hash {
+        true => Ok(data),
+        false => Err(io::Error::new(
+            InvalidData,
+            "tree file does not match its hash",
+        )),
+    }
+}
+
+/// Sample hashes of windows of `data`, chosen by content alone so that
+/// files sharing runs of bytes share samples wherever those runs lie.
+fn fingerprint(data: &[u8]) -> Vec<u64> {
+    const BASE: u64 = 0x100000001b3;
+    let out = BASE.wrapping_pow(WINDOW as u32);
+    let mut samples = Vec::new();
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate() {
+        hash = hash.wrapping_mul(BASE).wrapping_add(u64::from(byte) + 1);
+        if i >= WINDOW {
+            hash = hash.wrapping_sub((u64::from(data[i - WINDOW]) + 1).wrapping_mul(out));
+        }
+        if i + 1 >= WINDOW && hash.wrapping_mul(0x9e3779b97f4a7c15) >> (64 - SAMPLE_BITS) == 0 {
+            samples.push(hash);
+        }
+    }
+    samples.sort_unstable();
+    samples.dedup();
+    samples
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, false) => 0o755,
+        (true, true) => 0o555,
+        (false, false) => 0o644,
+        (false, true) => 0o444,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_: &[u8], _: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        Unsupported,
+        "symlinks are unsupported on this platform",
+    ))
+}
@@ -0,0 +1,363 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::inspect::inspect;
+use crate::limits::Limits;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::io::ErrorKind::{AlreadyExists, InvalidData, InvalidInput, NotFound, UnexpectedEof};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Directory under the root that holds staged files, backups and the journal.
+const STAGING: &str = ".aehobak-update";
+const JOURNAL: &str = "journal";
+/// Marks a pending update as being rolled back, once committed.
+const ROLLING_BACK: &str = "rolling-back";
+const MAGIC: &[u8; 8] = b"AEHOJRNL";
+const VERSION: u32 = 1;
+
+/// An atomic update of files under a root directory, each by a patch.
+///
+/// Every new file is staged beside the root and checked against its hash
+/// before any file is replaced, and a journal records the update once staged.
+/// Files are then swapped into place by renames, with backups of the files
+/// they replace, and synced to disk. Should this be interrupted, the next
+/// start may call `Update::complete` or `Update::roll_back` on the same root
+/// to leave either every file updated or none.
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let root = std::env::temp_dir().join(format!("aehobak-doc-{}", std::process::id()));
+/// # std::fs::create_dir_all(&root)?;
+/// # let (old, new) = (b"old content".as_slice(), b"new content".as_slice());
+/// # std::fs::write(root.join("file"), old)?;
+/// # let hash = xxhash_rust::xxh3::xxh3_128(new);
+/// let mut patch = Vec::new();
+/// aehobak::diff(old, new, &mut patch)?;
+/// aehobak::Update::complete(&root)?;
+/// aehobak::Update::new(&root).file("file", &patch, hash).apply()?;
+/// assert_eq!(std::fs::read(root.join("file"))?, new);
+/// # std::fs::remove_dir_all(&root)
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Update<'a> {
+    root: PathBuf,
+    files: Vec<(PathBuf, &'a [u8], u128)>,
+}
+
+/// A file recorded in the journal, by its position in the update.
+pub(crate) struct Entry {
+    path: PathBuf,
+    hash: u128,
+}
+
+impl<'a> Update<'a> {
+    /// Begin an update of files under `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Replace the file at `path`, relative to the root, with the result of
+    /// applying `patch` to it, which must have the xxh3-128 `hash`.
+    /// Each path may be given once.
+    pub fn file<P: Into<PathBuf>>(mut self, path: P, patch: &'a [u8], hash: u128) -> Self {
+        self.files.push((path.into(), patch, hash));
+        self
+    }
+
+    /// Apply every patch, or none.
+    ///
+    /// Fails with `AlreadyExists` while an interrupted update is pending. If
+    /// an error leaves the update neither complete nor rolled back, the
+    /// journal is kept for the next start.
+    pub fn apply(&self) -> io::Result<()> {
+        let entries = self.prepare()?;
+        if let Err(e) = swap(&self.root, &entries) {
+            Self::roll_back(&self.root)?;
+            return Err(e);
+        }
+        finish(&self.root, &entries)
+    }
+
+    /// Stage and verify every file, then commit the journal.
+    /// On failure, nothing under the root is left changed.
+    pub(crate) fn prepare(&self) -> io::Result<Vec<Entry>> {
+        let staging = self.root.join(STAGING);
+        fs::create_dir(&staging).map_err(|e| match e.kind() {
+            AlreadyExists => io::Error::new(AlreadyExists, "an interrupted update is pending"),
+            _ => e,
+        })?;
+        let committed = self.stage(&staging).and_then(|entries| {
+            write_journal(&staging, &entries)?;
+            Ok(entries)
+        });
+        if committed.is_err() {
+            // Cleanup is best effort, as an uncommitted update is discarded on recovery
+            let _ = fs::remove_dir_all(&staging);
+        }
+        committed
+    }
+
+    fn stage(&self, staging: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::with_capacity(self.files.len());
+        let mut paths = HashSet::new();
+        for (i, &(ref path, patch, hash)) in self.files.iter().enumerate() {
+            check_path(path)?;
+            if !paths.insert(path) {
+                return Err(io::Error::new(InvalidInput, "duplicate update path"));
+            }
+            let target = self.root.join(path);
+            let old = fs::read(&target)?;
+            let len = inspect(patch)?.new_len;
+            let mut new = Vec::with_capacity(len.min(Limits::default().output));
+            crate::patch(&old, patch, &mut new)?;
+            if xxh3_128(&new) != hash {
+                return Err(io::Error::new(
+                    InvalidData,
+                    "patched file does not match its hash",
+                ));
+            }
+            let staged = staging.join(format!("{i}.new"));
+            let mut file = File::create_new(&staged)?;
+            file.write_all(&new)?;
+            file.set_permissions(fs::metadata(&target)?.permissions())?;
+            file.sync_all()?;
+            entries.push(Entry {
+                path: path.clone(),
+                hash,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Finish an interrupted update under `root`, returning whether one was pending.
+    ///
+    /// An update interrupted before its journal was committed cannot be
+    /// finished, so it is rolled back instead, leaving every file unchanged.
+    /// So is one interrupted while rolling back, as files already restored
+    /// have lost their staged copies. Staged files are checked against their
+    /// hashes before any is swapped into place.
+    pub fn complete(root: &Path) -> io::Result<bool> {
+        let Some(entries) = pending(root)? else {
+            return Ok(false);
+        };
+        if root.join(STAGING).join(ROLLING_BACK).exists() {
+            restore(root, &entries)?;
+        } else {
+            verify(root, &entries)?;
+            swap(root, &entries)?;
+        }
+        finish(root, &entries)?;
+        Ok(true)
+    }
+
+    /// Undo an interrupted update under `root`, returning whether one was pending.
+    pub fn roll_back(root: &Path) -> io::Result<bool> {
+        let Some(entries) = pending(root)? else {
+            return Ok(false);
+        };
+        mark_roll_back(root)?;
+        restore(root, &entries)?;
+        finish(root, &entries)?;
+        Ok(true)
+    }
+}
+
+/// Commit to rolling back, so that an interrupted roll back is never completed.
+pub(crate) fn mark_roll_back(root: &Path) -> io::Result<()> {
+    let staging = root.join(STAGING);
+    File::create(staging.join(ROLLING_BACK))?.sync_all()?;
+    sync_dir(&staging)
+}
+
+/// Put back each file that was replaced, from its backup.
+/// Each step can be repeated after an interruption.
+fn restore(root: &Path, entries: &[Entry]) -> io::Result<()> {
+    let staging = root.join(STAGING);
+    for (i, entry) in entries.iter().enumerate().rev() {
+        let backup = staging.join(format!("{i}.old"));
+        // A file whose staged copy remains was never replaced
+        if !staging.join(format!("{i}.new")).exists() && backup.exists() {
+            fs::rename(&backup, root.join(&entry.path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check each file that is yet to be swapped into place against its hash.
+fn verify(root: &Path, entries: &[Entry]) -> io::Result<()> {
+    let staging = root.join(STAGING);
+    for (i, entry) in entries.iter().enumerate() {
+        let staged = staging.join(format!("{i}.new"));
+        let data = match fs::read(&staged) {
+            Err(e) if e.kind() == NotFound => continue,
+            data => data?,
+        };
+        if xxh3_128(&data) != entry.hash {
+            return Err(io::Error::new(
+                InvalidData,
+                "staged file does not match its hash",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read the journal of a pending update, discarding any uncommitted one.
+fn pending(root: &Path) -> io::Result<Option<Vec<Entry>>> {
+    let staging = root.join(STAGING);
+    match fs::read(staging.join(JOURNAL)) {
+        Ok(journal) => read_journal(&journal).map(Some),
+        Err(e) if e.kind() == NotFound => {
+            match fs::remove_dir_all(&staging) {
+                Err(e) if e.kind() != NotFound => return Err(e),
+                _ => {}
+            }
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace each file with its staged copy, keeping a backup.
+/// Each step can be repeated after an interruption.
+pub(crate) fn swap(root: &Path, entries: &[Entry]) -> io::Result<()> {
+    let staging = root.join(STAGING);
+    for (i, entry) in entries.iter().enumerate() {
+        let (staged, backup) = (
+            staging.join(format!("{i}.new")),
+            staging.join(format!("{i}.old")),
+        );
+        if !staged.exists() {
+            continue;
+        }
+        let target = root.join(&entry.path);
+        if !backup.exists() {
+            fs::hard_link(&target, &backup).or_else(|_| {
+                let partial = staging.join(format!("{i}.tmp"));
+                fs::copy(&target, &partial)?;
+                File::open(&partial)?.sync_all()?;
+                fs::rename(&partial, &backup)
+            })?;
+            sync_dir(&staging)?;
+        }
+        fs::rename(&staged, &target)?;
+        sync_dir(target.parent().unwrap_or(root))?;
+    }
+    Ok(())
+}
+
+/// Remove the journal, then the staging directory with any backups.
+fn finish(root: &Path, entries: &[Entry]) -> io::Result<()> {
+    for entry in entries {
+        sync_dir(root.join(&entry.path).parent().unwrap_or(root))?;
+    }
+    let staging = root.join(STAGING);
+    fs::remove_file(staging.join(JOURNAL))?;
+    sync_dir(&staging)?;
+    fs::remove_dir_all(&staging)?;
+    sync_dir(root)
+}
+
+/// Write the journal beside the staged files, atomically.
+fn write_journal(staging: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut journal = Vec::new();
+    journal.extend_from_slice(MAGIC);
+    journal.extend_from_slice(&VERSION.to_le_bytes());
+    journal.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for entry in entries {
+        let path = (entry.path.to_str())
+            .ok_or(io::Error::new(InvalidInput, "update path is not UTF-8"))?;
+        journal.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        journal.extend_from_slice(path.as_bytes());
+        journal.extend_from_slice(&entry.hash.to_le_bytes());
+    }
+    journal.extend_from_slice(&xxh3_128(&journal).to_le_bytes());
+    let partial = staging.join("journal.tmp");
+    let mut file = File::create_new(&partial)?;
+    file.write_all(&journal)?;
+    file.sync_all()?;
+    fs::rename(&partial, staging.join(JOURNAL))?;
+    sync_dir(staging)
+}
+
+fn read_journal(journal: &[u8]) -> io::Result<Vec<Entry>> {
+    let invalid = || io::Error::new(InvalidData, "corrupt update journal");
+    let (body, checksum) = journal.split_last_chunk::<16>().ok_or_else(invalid)?;
+    if u128::from_le_bytes(*checksum) != xxh3_128(body) || !body.starts_with(MAGIC) {
+        return Err(invalid());
+    }
+    let mut rest = &body[MAGIC.len()..];
+    let mut take = |len: usize| -> io::Result<&[u8]> {
+        let bytes = rest.get(..len).ok_or(io::Error::from(UnexpectedEof))?;
+        rest = &rest[len..];
+        Ok(bytes)
+    };
+    if u32::from_le_bytes(take(4)?.try_into().unwrap()) != VERSION {
+        return Err(io::Error::new(
+            InvalidData,
+            "unsupported update journal version",
+        ));
+    }
+    let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let path = std::str::from_utf8(take(len as usize)?).map_err(|_| invalid())?;
+        let path = PathBuf::from(path);
+        check_path(&path).map_err(|_| invalid())?;
+        let hash = u128::from_le_bytes(take(16)?.try_into().unwrap());
+        entries.push(Entry { path, hash });
+    }
+    Ok(entries)
+}
+
+/// Refuse paths that could leave the root or reach the staging directory.
+fn check_path(path: &Path) -> io::Result<()> {
+    let mut components = path.components().peekable();
+    let inside = components.peek().is_some()
+        && components.all(|c| matches!(c, Component::Normal(_)))
+        && !path.starts_with(STAGING);
+    match inside {
+        true => Ok(()),
+        false => Err(io::Error::new(InvalidInput, "update path escapes its root")),
+    }
+}
+
+/// Persist the entries of a directory, where the platform supports it.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
@@ -0,0 +1,528 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use crate::convert::{instructions, push_add, push_literal, Inst};
+use crate::diff::Op;
+use crate::limits::{self, Limits};
+use std::io;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof, Unsupported};
+use std::io::Write;
+
+const MAGIC: [u8; 4] = [0xD6, 0xC3, 0xC4, 0x00];
+// Header indicator bits
+const VCD_DECOMPRESS: u8 = 1;
+const VCD_CODETABLE: u8 = 2;
+const VCD_APPHEADER: u8 = 4;
+// Window indicator bits, of which the checksum is an xdelta3 extension
+const VCD_SOURCE: u8 = 1;
+const VCD_TARGET: u8 = 2;
+const VCD_ADLER32: u8 = 4;
+/// Sizes of the near and same address caches of the default code table.
+const NEAR: usize = 4;
+const SAME: usize = 3;
+/// Target windows are kept within what xdelta3 and open-vcdiff decode.
+const WINDOW: usize = 1 << 23;
+/// Shorter matches cost more as a copy than as added bytes.
+const MIN_COPY: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+/// One half of a code table entry, with a size of zero read explicitly.
+#[derive(Clone, Copy, Debug)]
+struct Code {
+    kind: Kind,
+    size: usize,
+    mode: usize,
+}
+
+/// The default code table of RFC 3284, section 5.6.
+fn code_table() -> Vec<(Code, Code)> {
+    let code = |kind, size, mode| Code { kind, size, mode };
+    let noop = code(Kind::Noop, 0, 0);
+    let mut table = vec![(code(Kind::Run, 0, 0), noop)];
+    table.extend((0..=17).map(|size| (code(Kind::Add, size, 0), noop)));
+    for mode in 0..2 + NEAR + SAME {
+        let sizes = [0].into_iter().chain(4..=18);
+        table.extend(sizes.map(|size| (code(Kind::Copy, size, mode), noop)));
+    }
+    for mode in 0..6 {
+        for add in 1..=4 {
+            let copies = (4..=6).map(|copy| code(Kind::Copy, copy, mode));
+            table.extend(copies.map(|copy| (code(Kind::Add, add, 0), copy)));
+        }
+    }
+    for mode in 6..2 + NEAR + SAME {
+        let adds = (1..=4).map(|add| code(Kind::Add, add, 0));
+        table.extend(adds.map(|add| (add, code(Kind::Copy, 4, mode))));
+    }
+    for mode in 0..2 + NEAR + SAME {
+        table.push((code(Kind::Copy, 4, mode), code(Kind::Add, 1, 0)));
+    }
+    debug_assert_eq!(table.len(), 256);
+    table
+}
+
+/// The near and same caches through which copy addresses are coded.
+struct AddressCache {
+    near: [usize; NEAR],
+    next: usize,
+    same: [usize; SAME * 256],
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self {
+            near: [0; NEAR],
+            next: 0,
+            same: [0; SAME * 256],
+        }
+    }
+
+    fn update(&mut self, addr: usize) {
+        self.near[self.next] = addr;
+        self.next = (self.next + 1) % NEAR;
+        self.same[addr % (SAME * 256)] = addr;
+    }
+
+    /// Decode the address of a copy at `here` in the address space of a window.
+    fn decode(&mut self, here: usize, mode: usize, addrs: &mut &[u8]) -> io::Result<usize> {
+        let addr = match mode {
+            0 => read_int(addrs)?,
+            1 => here.checked_sub(read_int(addrs)?).ok_or(InvalidData)?,
+            m if m < 2 + NEAR => self.near[m - 2]
+                .checked_add(read_int(addrs)?)
+                .ok_or(InvalidData)?,
+            m => self.same[(m - 2 - NEAR) * 256 + usize::from(read_byte(addrs)?)],
+        };
+        if addr >= here {
+            return Err(io::Error::new(
+                InvalidData,
+                "copy address beyond current position",
+            ));
+        }
+        self.update(addr);
+        Ok(addr)
+    }
+
+    /// Encode `addr` in the mode that takes fewest bytes, returning the mode.
+    fn encode(&mut self, here: usize, addr: usize, addrs: &mut Vec<u8>) -> usize {
+        let slot = addr % (SAME * 256);
+        let (mode, bytes) = if self.same[slot] == addr {
+            (2 + NEAR + slot / 256, vec![slot as u8])
+        } else {
+            let near = self.near.iter().enumerate();
+            let near = near.filter_map(|(i, &n)| Some((2 + i, addr.checked_sub(n)?)));
+            let (mode, value) = [(0, addr), (1, here - addr)]
+                .into_iter()
+                .chain(near)
+                .min_by_key(|&(_, value)| int_len(value))
+                .unwrap();
+            let mut bytes = Vec::new();
+            write_int(&mut bytes, value);
+            (mode, bytes)
+        };
+        addrs.extend(bytes);
+        self.update(addr);
+        mode
+    }
+}
+
+/// Convert a patch to VCDIFF (RFC 3284), given the old it applies to.
+///
+/// Each add of the patch becomes copies of the bytes it leaves unchanged and
+/// adds of those it changes. Fills become runs, and self-copies become copies
+/// from the target where they fall within the same window.
+pub fn export_vcdiff<T: Write>(old: &[u8], patch: &[u8], writer: &mut T) -> io::Result<()> {
+    let (new, insts) = instructions(old, patch, MIN_COPY)?;
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[0])?;
+    let mut window = Vec::new();
+    let (mut window_start, mut pos) = (0, 0);
+    for mut inst in insts {
+        while inst.len() != 0 {
+            let len = inst.len().min(window_start + WINDOW - pos);
+            window.push(inst.head(len));
+            inst = inst.tail(len);
+            pos += len;
+            if pos == window_start + WINDOW {
+                write_window(&new, window_start, &window, writer)?;
+                window.clear();
+                window_start = pos;
+            }
+        }
+    }
+    if !window.is_empty() {
+        write_window(&new, window_start, &window, writer)?;
+    }
+    Ok(())
+}
+
+/// Write the instructions of a window of new beginning at `start`.
+fn write_window(
+    new: &[u8],
+    start: usize,
+    insts: &[Inst],
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    // The source segment spans the bytes of old copied by this window
+    let copies = insts.iter().filter_map(|inst| match *inst {
+        Inst::Copy(from, len) => Some(from..from + len),
+        _ => None,
+    });
+    let source = copies.reduce(|a, b| a.start.min(b.start)..a.end.max(b.end));
+    let (seg_pos, seg_len) = source.map_or((0, 0), |source| (source.start, source.len()));
+
+    let (mut data, mut codes, mut addrs) = (Vec::new(), Vec::new(), Vec::new());
+    let mut cache = AddressCache::new();
+    let mut pos = start;
+    for inst in insts {
+        let here = seg_len + pos - start;
+        let (kind, mode) = match *inst {
+            Inst::Add(ref range) => {
+                data.extend(&new[range.clone()]);
+                (Kind::Add, 0)
+            }
+            Inst::Run(_, byte) => {
+                data.push(byte);
+                (Kind::Run, 0)
+            }
+            Inst::Copy(from, _) => (Kind::Copy, cache.encode(here, from - seg_pos, &mut addrs)),
+            Inst::Repeat(from, _) if from >= start => {
+                let addr = seg_len + from - start;
+                (Kind::Copy, cache.encode(here, addr, &mut addrs))
+            }
+            // Copies from earlier windows are added instead
+            Inst::Repeat(_, len) => {
+                data.extend(&new[pos..pos + len]);
+                (Kind::Add, 0)
+            }
+        };
+        write_code(&mut codes, kind, inst.len(), mode);
+        pos += inst.len();
+    }
+
+    let mut delta = Vec::new();
+    write_int(&mut delta, pos - start);
+    delta.push(0);
+    for section in [&data, &codes, &addrs] {
+        write_int(&mut delta, section.len());
+    }
+    delta.extend(data.into_iter().chain(codes).chain(addrs));
+    let mut header = Vec::new();
+    match seg_len {
+        0 => header.push(0),
+        _ => {
+            header.push(VCD_SOURCE);
+            write_int(&mut header, seg_len);
+            write_int(&mut header, seg_pos);
+        }
+    }
+    write_int(&mut header, delta.len());
+    writer.write_all(&header)?;
+    writer.write_all(&delta)
+}
+
+/// Write the single instruction code of the default table for an instruction.
+fn write_code(codes: &mut Vec<u8>, kind: Kind, size: usize, mode: usize) {
+    let (index, implicit) = match kind {
+        Kind::Add if (1..=17).contains(&size) => (1 + size, true),
+        Kind::Add => (1, false),
+        Kind::Copy if (4..=18).contains(&size) => (19 + mode * 16 + size - 3, true),
+        Kind::Copy => (19 + mode * 16, false),
+        Kind::Run | Kind::Noop => (0, false),
+    };
+    codes.push(index as u8);
+    if !implicit {
+        write_int(codes, size);
+    }
+}
+
+/// Apply VCDIFF to `old`, returning new and controls that produce it.
+///
+/// Copies from old become adds, and everything else becomes literals, but
+/// for short literals between copies along one diagonal, which become deltas.
+pub(crate) fn read(old: &[u8], mut vcdiff: &[u8]) -> io::Result<(Vec<u8>, Vec<Op>)> {
+    if take(&mut vcdiff, MAGIC.len())? != MAGIC {
+        return Err(io::Error::new(InvalidData, "not a VCDIFF file"));
+    }
+    let indicator = read_byte(&mut vcdiff)?;
+    if indicator & VCD_DECOMPRESS != 0 {
+        return Err(io::Error::new(Unsupported, "VCDIFF secondary compression"));
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        return Err(io::Error::new(Unsupported, "VCDIFF custom code table"));
+    }
+    if indicator & !(VCD_DECOMPRESS | VCD_CODETABLE | VCD_APPHEADER) != 0 {
+        return Err(io::Error::new(
+            InvalidData,
+            "unknown VCDIFF header indicator",
+        ));
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        let len = read_int(&mut vcdiff)?;
+        take(&mut vcdiff, len)?;
+    }
+    let table = code_table();
+    let limits = Limits::default();
+    let (mut new, mut ops) = (Vec::new(), Vec::new());
+    while !vcdiff.is_empty() {
+        read_window(old, &mut vcdiff, &table, &limits, &mut new, &mut ops)?;
+    }
+    Ok((new, ops))
+}
+
+fn read_window(
+    old: &[u8],
+    vcdiff: &mut &[u8],
+    table: &[(Code, Code)],
+    limits: &Limits,
+    new: &mut Vec<u8>,
+    ops: &mut Vec<Op>,
+) -> io::Result<()> {
+    let indicator = read_byte(vcdiff)?;
+    if indicator & !(VCD_SOURCE | VCD_TARGET | VCD_ADLER32) != 0
+        || indicator & (VCD_SOURCE | VCD_TARGET) == VCD_SOURCE | VCD_TARGET
+    {
+        return Err(io::Error::new(
+            InvalidData,
+            "unknown VCDIFF window indicator",
+        ));
+    }
+    let (seg_len, seg_pos) = match indicator & (VCD_SOURCE | VCD_TARGET) {
+        0 => (0, 0),
+        _ => (read_int(vcdiff)?, read_int(vcdiff)?),
+    };
+    let seg_end = seg_pos.checked_add(seg_len).ok_or(InvalidData)?;
+    let from_old = indicator & VCD_SOURCE != 0;
+    if seg_end > if from_old { old.len() } else { new.len() } {
+        return Err(io::Error::new(InvalidData, "VCDIFF segment out of range"));
+    }
+
+    let len = read_int(vcdiff)?;
+    let mut delta = take(vcdiff, len)?;
+    let target_len = read_int(&mut delta)?;
+    let start = new.len();
+    if start
+        .checked_add(target_len)
+        .is_none_or(|end| end > limits.output)
+    {
+        return Err(limits::exceeded("output"));
+    }
+    if read_byte(&mut delta)? != 0 {
+        return Err(io::Error::new(Unsupported, "VCDIFF compressed sections"));
+    }
+    let (data_len, codes_len, addrs_len) = (
+        read_int(&mut delta)?,
+        read_int(&mut delta)?,
+        read_int(&mut delta)?,
+    );
+    let checksum = match indicator & VCD_ADLER32 {
+        0 => None,
+        _ => Some(u32::from_be_bytes(take(&mut delta, 4)?.try_into().unwrap())),
+    };
+    let mut data = take(&mut delta, data_len)?;
+    let mut codes = take(&mut delta, codes_len)?;
+    let mut addrs = take(&mut delta, addrs_len)?;
+    if !delta.is_empty() {
+        return Err(io::Error::new(InvalidData, "trailing VCDIFF window data"));
+    }
+
+    let mut cache = AddressCache::new();
+    while !codes.is_empty() {
+        let (first, second) = table[usize::from(read_byte(&mut codes)?)];
+        for code in [first, second] {
+            let size = match (code.kind, code.size) {
+                (Kind::Noop, _) => continue,
+                (_, 0) => read_int(&mut codes)?,
+                (_, size) => size,
+            };
+            if size > target_len - (new.len() - start) {
+                return Err(io::Error::new(InvalidData, "VCDIFF window overrun"));
+            }
+            match code.kind {
+                Kind::Add => {
+                    new.extend_from_slice(take(&mut data, size)?);
+                    push_literal(ops, size);
+                }
+                Kind::Run => {
+                    let byte = read_byte(&mut data)?;
+                    new.resize(new.len() + size, byte);
+                    push_literal(ops, size);
+                }
+                Kind::Copy => {
+                    let here = seg_len + new.len() - start;
+                    let mut addr = cache.decode(here, code.mode, &mut addrs)?;
+                    let mut size = size;
+                    // The part within the segment
+                    if addr < seg_len {
+                        let len = size.min(seg_len - addr);
+                        let from = seg_pos + addr;
+                        match from_old {
+                            true => {
+                                new.extend_from_slice(&old[from..from + len]);
+                                push_add(ops, from, len);
+                            }
+                            false => {
+                                new.extend_from_within(from..from + len);
+                                push_literal(ops, len);
+                            }
+                        }
+                        (addr, size) = (addr + len, size - len);
+                    }
+                    if size == 0 {
+                        continue;
+                    }
+                    // The part within the window, which may overlap its own output
+                    let mut from = start + addr - seg_len;
+                    push_literal(ops, size);
+                    while size != 0 {
+                        let len = size.min(new.len() - from);
+                        new.extend_from_within(from..from + len);
+                        (from, size) = (from + len, size - len);
+                    }
+                }
+                Kind::Noop => {}
+            }
+        }
+    }
+    if new.len() - start != target_len || !data.is_empty() || !addrs.is_empty() {
+        return Err(io::Error::new(InvalidData, "VCDIFF window inconsistent"));
+    }
+    if checksum.is_some_and(|checksum| checksum != adler32(&new[start..])) {
+        return Err(io::Error::new(InvalidData, "VCDIFF checksum mismatch"));
+    }
+    Ok(())
+}
+
+/// The checksum of xdelta3 over each target window.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // The largest run of bytes before the sums must be reduced
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        (a, b) = (a % 65521, b % 65521);
+    }
+    b << 16 | a
+}
+
+fn read_byte(buf: &mut &[u8]) -> io::Result<u8> {
+    let (&byte, rest) = buf.split_first().ok_or(UnexpectedEof)?;
+    *buf = rest;
+    Ok(byte)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(io::Error::from(UnexpectedEof));
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Read a VCDIFF integer, base 128 with the most significant digit first.
+fn read_int(buf: &mut &[u8]) -> io::Result<usize> {
+    let mut value = 0usize;
+    loop {
+        let byte = read_byte(buf)?;
+        if value > usize::MAX >> 7 {
+            return Err(io::Error::new(InvalidData, "VCDIFF integer overflow"));
+        }
+        value = value << 7 | usize::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, value: usize) {
+    let len = int_len(value);
+    for i in (0..len).rev() {
+        let digit = (value >> (7 * i)) as u8 & 0x7F;
+        out.push(if i == 0 { digit } else { digit | 0x80 });
+    }
+}
+
+fn int_len(value: usize) -> usize {
+    (usize::BITS - value.leading_zeros()).div_ceil(7).max(1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_vector() {
+        let old = b"abcdefghijklmnopqrstuvwxyz";
+        let mut vcdiff = MAGIC.to_vec();
+        vcdiff.push(0);
+        // COPY 10 from old, ADD 3, RUN 5, COPY 8 from new via HERE,
+        // COPY 4 via SAME, then ADD 1 paired with COPY 4 from old
+        vcdiff.extend([VCD_SOURCE, 26, 0, 21, 35, 0, 5, 7, 4]);
+        vcdiff.extend(b"XYZ-!");
+        vcdiff.extend([26, 4, 0, 5, 40, 116, 163]);
+        vcdiff.extend([0, 18, 26, 22]);
+        let (new, ops) = read(old, &vcdiff).unwrap();
+        assert_eq!(new, b"abcdefghijXYZ-----abcdefghabcd!wxyz");
+        assert_eq!(ops[0].add, 10);
+        assert_eq!(ops.last().unwrap().old, 22);
+
+        // The same window with the checksum of xdelta3
+        let checksum = adler32(&new).to_be_bytes();
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        let mut checked = vcdiff.clone();
+        checked[5] |= VCD_ADLER32;
+        checked[8] += 4;
+        checked.splice(14..14, checksum);
+        assert_eq!(read(old, &checked).unwrap().0, new);
+        checked[14] ^= 1;
+        assert!(read(old, &checked).is_err());
+    }
+
+    #[test]
+    fn int_vectors() {
+        for (value, bytes) in [
+            (0, &[0][..]),
+            (127, &[127]),
+            (128, &[0x81, 0]),
+            (16384, &[0x81, 0x80, 0]),
+        ] {
+            let mut out = Vec::new();
+            write_int(&mut out, value);
+            assert_eq!(out, bytes);
+            assert_eq!(read_int(&mut &out[..]).unwrap(), value);
+        }
+    }
+}
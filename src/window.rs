@@ -0,0 +1,90 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+const PRIME: u64 = 0x100000001b3;
+
+/// Pair each segment of `new` with a window of `old` at most `window_len` long.
+///
+/// Old is summarised by a hash of each aligned block. Every block-sized run
+/// of a new segment votes for the diagonal of any old block it matches, and
+/// the window is centred on the most popular diagonal, or on the one nearest
+/// zero in a tie, positive first. Segments without any match keep the
+/// diagonal of their predecessor.
+pub(crate) fn plan(old: &[u8], new: &[u8], window_len: usize) -> Vec<(Range<usize>, Range<usize>)> {
+    let window_len = window_len.min(old.len()).max(1);
+    let segment_len = window_len.div_ceil(2);
+    let block = (window_len / 256).clamp(16, 4096);
+
+    let mut blocks: HashMap<u64, usize> = HashMap::new();
+    for (i, chunk) in old.chunks_exact(block).enumerate() {
+        blocks.entry(hash(chunk)).or_insert(i * block);
+    }
+    let top = PRIME.wrapping_pow(block as u32 - 1);
+
+    let mut plan = Vec::with_capacity(new.len().div_ceil(segment_len));
+    let mut diagonal = 0;
+    let mut votes: HashMap<isize, usize> = HashMap::new();
+    for start in (0..new.len()).step_by(segment_len) {
+        let segment = &new[start..new.len().min(start + segment_len)];
+        votes.clear();
+        if segment.len() >= block {
+            let mut h = hash(&segment[..block]);
+            for i in 0..=segment.len() - block {
+                if i > 0 {
+                    h = h
+                        .wrapping_sub(u64::from(segment[i - 1]).wrapping_mul(top))
+                        .wrapping_mul(PRIME)
+                        .wrapping_add(segment[i + block - 1].into());
+                }
+                if let Some(&pos) = blocks.get(&h) {
+                    *votes
+                        .entry(pos as isize - (start + i) as isize)
+                        .or_default() += 1;
+                }
+            }
+        }
+        if let Some((&best, _)) = votes.iter().max_by_key(|&(&d, &n)| (n, -d.abs(), d)) {
+            diagonal = best;
+        }
+        let centre = (start + segment.len() / 2).saturating_add_signed(diagonal);
+        let old_start = centre
+            .saturating_sub(window_len / 2)
+            .min(old.len() - window_len.min(old.len()));
+        plan.push((
+            start..start + segment.len(),
+            old_start..old_start + window_len.min(old.len()),
+        ));
+    }
+    plan
+}
+
+fn hash(block: &[u8]) -> u64 {
+    block
+        .iter()
+        .fold(0u64, |h, &b| h.wrapping_mul(PRIME).wrapping_add(b.into()))
+}
@@ -0,0 +1,180 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn aehobak(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aehobak"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("aehobak-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn old_new(seed: u64) -> (Vec<u8>, Vec<u8>) {
+    let mut rng = Xoshiro256Plus::seed_from_u64(seed);
+    let mut old = vec![0; 1 << 16];
+    rng.fill_bytes(&mut old);
+    let mut new = old.clone();
+    for i in (0..new.len()).step_by(1000) {
+        new[i] = new[i].wrapping_add(3);
+    }
+    new.splice(20000..20000, [0; 500]);
+    new.extend_from_within(100..1100);
+    (old, new)
+}
+
+#[test]
+fn diff_patch_files() {
+    let dir = temp_dir("files");
+    let (old, new) = old_new(0x510e527fade682d1);
+    std::fs::write(dir.join("old"), &old).unwrap();
+    std::fs::write(dir.join("new"), &new).unwrap();
+    let path = |name: &str| dir.join(name).into_os_string().into_string().unwrap();
+    let (old_path, new_path, patch_path) = (path("old"), path("new"), path("patch"));
+    let diff = ["diff", &old_path, &new_path, &patch_path];
+    let out = aehobak(
+        &[&diff[..], &["--fill", "--self-copy", "--cost", "speed"]].concat(),
+        b"",
+    );
+    assert!(out.status.success());
+    let out = aehobak(&["patch", &old_path, &patch_path, &path("patched")], b"");
+    assert!(out.status.success());
+    assert_eq!(std::fs::read(dir.join("patched")).unwrap(), new);
+
+    let out = aehobak(&["inspect", &patch_path, "--old", &old_path], b"");
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains(&format!("new length:  {}\n", new.len())));
+    assert!(stdout.contains("valid against"));
+
+    let out = aehobak(&["compare", &old_path, &new_path], b"");
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .contains("aehobak+lz4:"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn transcode_stdio() {
+    let (old, new) = old_new(0x9b05688c2b3e6c1f);
+    let mut bspatch = Vec::new();
+    bsdiff::diff(&old, &new, &mut bspatch).unwrap();
+    let out = aehobak(&["encode"], &bspatch);
+    assert!(out.status.success());
+    let mut encoded = Vec::new();
+    aehobak::encode(&bspatch, &mut encoded).unwrap();
+    assert_eq!(out.stdout, encoded);
+    let out = aehobak(&["decode", "-", "-"], &encoded);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, bspatch);
+
+    // Output is bounded before any is written
+    let max_output = bspatch.len().to_string();
+    let out = aehobak(&["decode", "--max-output", &max_output], &encoded);
+    assert_eq!(out.stdout, bspatch);
+    let max_output = (bspatch.len() - 1).to_string();
+    let out = aehobak(&["decode", "--max-output", &max_output], &encoded);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn patch_stdio() {
+    let dir = temp_dir("stdio");
+    let (old, new) = old_new(0x1f83d9abfb41bd6b);
+    std::fs::write(dir.join("old"), &old).unwrap();
+    let old_path = dir.join("old").into_os_string().into_string().unwrap();
+    let mut encoded = Vec::new();
+    aehobak::diff(&old, &new, &mut encoded).unwrap();
+    let out = aehobak(&["patch", &old_path, "-"], &encoded);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, new);
+
+    // Errors are reported with a failing exit code
+    let out = aehobak(&["patch", &old_path, "-"], &encoded[..encoded.len() / 2]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .starts_with("aehobak: "));
+    let out = aehobak(&["inspect", "-", "--old", "/nonexistent"], &encoded);
+    assert_eq!(out.status.code(), Some(1));
+    let out = aehobak(&["patch"], b"");
+    assert_eq!(out.status.code(), Some(2));
+
+    // Standard input is read at most once
+    for args in [
+        &["patch", "-", "-"][..],
+        &["diff", "-", "-"],
+        &["inspect", "-", "--old", "-"],
+    ] {
+        let out = aehobak(args, &encoded);
+        assert_eq!(out.status.code(), Some(1));
+        assert!(String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("standard input can be read only once"));
+    }
+
+    // Output is bounded before new is reserved
+    let max_output = new.len().to_string();
+    let out = aehobak(
+        &["patch", &old_path, "-", "--max-output", &max_output],
+        &encoded,
+    );
+    assert!(out.status.success());
+    let max_output = (new.len() - 1).to_string();
+    let out = aehobak(
+        &["patch", &old_path, "-", "--max-output", &max_output],
+        &encoded,
+    );
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let args = [
+        "inspect",
+        "-",
+        "--old",
+        &old_path,
+        "--max-output",
+        &max_output,
+    ];
+    assert_eq!(aehobak(&args, &encoded).status.code(), Some(1));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,117 @@
+/*-
+ * Copyright 2025 David Michael Barr
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted providing that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR ``AS IS'' AND ANY EXPRESS OR
+ * IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT,
+ * STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING
+ * IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE
+ * POSSIBILITY OF SUCH DAMAGE.
+ */
+
+//! Peak heap use, measured by a counting allocator.
+
+use aehobak::{DivSufSort, SuffixSorter};
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+/// Tests are measured one at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        grow(layout.size());
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        match size.checked_sub(layout.size()) {
+            Some(more) => grow(more),
+            None => _ = CURRENT.fetch_sub(layout.size() - size, Relaxed),
+        }
+        unsafe { System.realloc(ptr, layout, size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn grow(bytes: usize) {
+    let current = CURRENT.fetch_add(bytes, Relaxed) + bytes;
+    PEAK.fetch_max(current, Relaxed);
+}
+
+/// Bytes allocated by `f` at its peak, beyond those live when it began.
+fn peak<R>(f: impl FnOnce() -> R) -> (usize, R) {
+    let base = CURRENT.load(Relaxed);
+    PEAK.store(base, Relaxed);
+    let result = f();
+    (PEAK.load(Relaxed) - base, result)
+}
+
+#[test]
+fn wide_sort_memory() {
+    let _serial = SERIAL.lock().unwrap();
+    let len = 1 << 20;
+    let mut random = vec![0; len];
+    Xoshiro256Plus::seed_from_u64(0x3c6ef372fe94f82b).fill_bytes(&mut random);
+    let ternary = random.iter().map(|b| b % 3).collect();
+    let periodic = (0..len).map(|i| b"abcab"[i % 5]).collect();
+    for old in [random, ternary, periodic, vec![0; len]] {
+        let (bytes, sa) = peak(|| DivSufSort.sort64(&old).unwrap());
+        assert_eq!(sa.len(), len);
+        // The suffix array itself, then bits and buckets for each level
+        assert!(bytes <= len * 8 + len * 33 / 4, "{bytes} bytes");
+    }
+}
+
+#[test]
+fn window_memory() {
+    let _serial = SERIAL.lock().unwrap();
+    let len = 1 << 22;
+    let mut old = vec![0; len];
+    Xoshiro256Plus::seed_from_u64(0xa54ff53a5f1d36f1).fill_bytes(&mut old);
+    let mut new = old.clone();
+    for i in (0..len).step_by(4099) {
+        new[i] ^= 1;
+    }
+    let window_len = 1 << 16;
+    let options = aehobak::DiffOptions::new().window(window_len);
+    let (bytes, encoded) = peak(|| {
+        let mut encoded = Vec::new();
+        options.diff(&old, &new, &mut encoded).unwrap();
+        encoded
+    });
+    let mut patched = Vec::with_capacity(new.len());
+    aehobak::patch(&old, &encoded, &mut patched).unwrap();
+    assert_eq!(patched, new);
+    // Windows together cover old, so were their indexes all live at once,
+    // they would cost 4 bytes per byte of old
+    assert!(bytes < len * 2, "{bytes} bytes");
+}